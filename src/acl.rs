@@ -0,0 +1,458 @@
+use std::collections::HashSet;
+
+use clap::CommandFactory;
+
+/// a `[[role]]` table of bots.toml, what its callers are allowed to run
+#[derive(Debug)]
+pub struct Role {
+    pub name: String,
+    /// the names of the commands allowed, every command if it contains `*`
+    pub commands: HashSet<String>,
+    /// the bots commands about a bot are allowed on, every bot if it's `None`
+    pub bots: Option<HashSet<String>>,
+    /// the callers the role is given to
+    pub callers: HashSet<String>,
+}
+
+impl Role {
+    /// parses a `[[role]]` table of bots.toml, checking every command is a known one
+    pub fn from_toml_table(table: &toml::value::Table) -> Result<Role, String> {
+        let name = match table.get("name") {
+            Some(toml::Value::String(name)) if !name.is_empty() => name.to_string(),
+            Some(_) => return Err("role.name should be a non-empty string!".to_string()),
+            None => return Err("Given role doesn't have a name!".to_string()),
+        };
+
+        let commands = match strings(table, "commands")? {
+            Some(commands) => commands,
+            None => return Err(format!("role {} doesn't have commands!", name)),
+        };
+        let known: Vec<String> = crate::cmd_parser::Cli::command()
+            .get_subcommands()
+            .map(|command| command.get_name().to_string())
+            .collect();
+        if let Some(unknown) = commands
+            .iter()
+            .find(|command| *command != "*" && !known.contains(command))
+        {
+            return Err(format!("{} in role.commands isn't a command!", unknown));
+        }
+
+        let bots = strings(table, "bots")?;
+        let callers = strings(table, "callers")?.unwrap_or_default();
+        if let Some(key) = table
+            .keys()
+            .find(|key| !["name", "commands", "bots", "callers"].contains(&key.as_str()))
+        {
+            return Err(format!("role.{} isn't a key of role!", key));
+        }
+
+        Ok(Role {
+            name,
+            commands,
+            bots,
+            callers,
+        })
+    }
+
+    /// whether the role allows running `command` about `bot`
+    fn allows(&self, command: &str, bot: Option<&str>) -> bool {
+        (self.commands.contains("*") || self.commands.contains(command))
+            && match (&self.bots, bot) {
+                (Some(bots), Some(bot)) => bots.contains(bot),
+                _ => true,
+            }
+    }
+}
+
+/// reads the array of non-empty strings at `key` of a `[[role]]` table
+fn strings(table: &toml::value::Table, key: &str) -> Result<Option<HashSet<String>>, String> {
+    let error = || format!("role.{} should be a array of non-empty strings!", key);
+    match table.get(key) {
+        Some(toml::Value::Array(values)) => values
+            .iter()
+            .map(|value| match value {
+                toml::Value::String(value) if !value.is_empty() => Ok(value.to_string()),
+                _ => Err(error()),
+            })
+            .collect::<Result<HashSet<String>, String>>()
+            .map(Some),
+        Some(_) => Err(error()),
+        None => Ok(None),
+    }
+}
+
+/// the roles in bots.toml, which every command is checked against once any role is configured
+#[derive(Debug, Default)]
+pub struct Acl {
+    pub roles: Vec<Role>,
+    /// the role of callers without any role, and of commands without a caller
+    pub default_role: Option<String>,
+    /// the role of commands without a caller (or with a caller without any role) from local clients,
+    /// which are the terminal, the control socket and the terminal dashboard, `default_role` if it's absent
+    pub local_role: Option<String>,
+}
+
+impl Acl {
+    /// reads `role`, `default_role` and `local_role` from `toml`
+    pub fn parse(toml: &toml::Value) -> Result<Acl, String> {
+        let roles = match toml.get("role") {
+            Some(toml::Value::Array(roles)) => roles
+                .iter()
+                .map(|role| match role.as_table() {
+                    Some(role) => Role::from_toml_table(role)
+                        .map_err(|err| format!("Failed to parse role in bots.toml:\n\t{}", err)),
+                    None => Err("role in bots.toml should be a array of table!".to_string()),
+                })
+                .collect::<Result<Vec<Role>, String>>()?,
+            Some(_) => return Err("role in bots.toml should be a array of table!".to_string()),
+            None => Vec::new(),
+        };
+        let mut names = HashSet::new();
+        if roles.iter().any(|role| !names.insert(&role.name)) {
+            return Err("Multiple roles in bots.toml have identical name!".to_string());
+        }
+
+        let role_name = |key: &str| match toml.get(key) {
+            Some(toml::Value::String(name)) if names.contains(name) => Ok(Some(name.to_string())),
+            Some(_) => Err(format!(
+                "{} should contain a role name presented in a role table!",
+                key
+            )),
+            None => Ok(None),
+        };
+        let default_role = role_name("default_role")?;
+        let local_role = role_name("local_role")?;
+        Ok(Acl {
+            roles,
+            default_role,
+            local_role,
+        })
+    }
+
+    /// whether any role is configured, without which every command is allowed
+    pub fn is_enabled(&self) -> bool {
+        !self.roles.is_empty()
+    }
+
+    /// whether `caller` has admin rights, which is a role allowing every command on every bot,
+    /// or whether no role is configured at all
+    pub fn is_admin(&self, caller: Option<&str>, local: bool) -> bool {
+        !self.is_enabled()
+            || self
+                .roles_of(caller, local)
+                .iter()
+                .any(|role| role.commands.contains("*") && role.bots.is_none())
+    }
+
+    /// the roles of `caller`, which are `local_role` (or `default_role`) for a caller without any role
+    /// and for no caller if the command is from a local client, and `default_role` otherwise
+    fn roles_of(&self, caller: Option<&str>, local: bool) -> Vec<&Role> {
+        let roles: Vec<&Role> = match caller {
+            Some(caller) => self
+                .roles
+                .iter()
+                .filter(|role| role.callers.contains(caller))
+                .collect(),
+            None => Vec::new(),
+        };
+        if !roles.is_empty() {
+            return roles;
+        }
+        let role = match local {
+            true => self.local_role.as_ref().or(self.default_role.as_ref()),
+            false => self.default_role.as_ref(),
+        };
+        self.roles
+            .iter()
+            .filter(|candidate| Some(&candidate.name) == role)
+            .collect()
+    }
+
+    /// checks whether any role of `caller` allows running `command` about `bot`,
+    /// returning the description of why it's denied otherwise
+    ///
+    /// `local` tells whether the command is from a local client, see `local_role`
+    pub fn check(
+        &self,
+        caller: Option<&str>,
+        local: bool,
+        command: &str,
+        bot: Option<&str>,
+    ) -> Result<(), String> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let roles = self.roles_of(caller, local);
+        if roles.iter().any(|role| role.allows(command, bot)) {
+            return Ok(());
+        }
+        let caller = describe(caller, &roles);
+        Err(match bot {
+            Some(bot) => format!("{} isn't allowed to run {} on {}", caller, command, bot),
+            None => format!("{} isn't allowed to run {}", caller, command),
+        })
+    }
+
+    /// narrows `bots` (every bot if it's empty) to the bots any role of `caller` allows running each of `commands` on,
+    /// returning the description of why it's denied if no bot is left
+    ///
+    /// this is for commands about every bot that still only concern some of them, such as `subscribe`
+    pub fn scope(
+        &self,
+        caller: Option<&str>,
+        local: bool,
+        commands: &[&str],
+        bots: &[String],
+    ) -> Result<Vec<String>, String> {
+        if !self.is_enabled() {
+            return Ok(bots.to_vec());
+        }
+        let roles = self.roles_of(caller, local);
+        // `None` while every bot is allowed
+        let mut allowed: Option<HashSet<&String>> = None;
+        for command in commands {
+            let allowing: Vec<&&Role> = roles
+                .iter()
+                .filter(|role| role.allows(command, None))
+                .collect();
+            if allowing.iter().any(|role| role.bots.is_none()) {
+                continue;
+            }
+            let bots = allowing
+                .iter()
+                .flat_map(|role| role.bots.iter().flatten())
+                .collect();
+            allowed = Some(match allowed {
+                Some(allowed) => allowed.intersection(&bots).copied().collect(),
+                None => bots,
+            });
+        }
+        let scoped: Vec<String> = match allowed {
+            None => return Ok(bots.to_vec()),
+            Some(allowed) if bots.is_empty() => {
+                let mut scoped: Vec<String> = allowed.into_iter().cloned().collect();
+                scoped.sort();
+                scoped
+            }
+            Some(allowed) => bots
+                .iter()
+                .filter(|bot| allowed.contains(bot))
+                .cloned()
+                .collect(),
+        };
+        if !scoped.is_empty() {
+            return Ok(scoped);
+        }
+        Err(format!(
+            "{} isn't allowed to run {} on any of the bots asked for",
+            describe(caller, &roles),
+            commands.join(" and ")
+        ))
+    }
+}
+
+/// `caller` along with the names of its roles, for a description of why a command is denied
+fn describe(caller: Option<&str>, roles: &[&Role]) -> String {
+    let caller = match caller {
+        Some(caller) => format!("caller {}", caller),
+        None => "a command without a caller".to_string(),
+    };
+    let roles: Vec<&str> = roles.iter().map(|role| role.name.as_str()).collect();
+    match roles.is_empty() {
+        true => format!("{} with no role", caller),
+        false => format!("{} with role {}", caller, roles.join(",")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROLES: &str = r#"
+        default_role = "viewer"
+
+        [[role]]
+        name = "viewer"
+        commands = ["list-status", "logs"]
+
+        [[role]]
+        name = "operator"
+        commands = ["restart", "logs"]
+        bots = ["bot_b"]
+        callers = ["1", "2"]
+
+        [[role]]
+        name = "deployer"
+        commands = ["build"]
+        callers = ["2"]
+
+        [[role]]
+        name = "admin"
+        commands = ["*"]
+        callers = ["3"]
+    "#;
+
+    fn acl(toml: &str) -> Result<Acl, String> {
+        Acl::parse(&toml::from_str(toml).unwrap())
+    }
+
+    #[test]
+    fn allows_every_command_without_roles() {
+        let acl = acl("").unwrap();
+        assert!(!acl.is_enabled());
+        assert_eq!(acl.check(None, false, "exit", None), Ok(()));
+        assert_eq!(acl.check(Some("1"), false, "kill", Some("bot_a")), Ok(()));
+        assert!(acl.is_admin(None, false));
+    }
+
+    #[test]
+    fn checks_commands_against_the_roles_of_the_caller() {
+        let acl = acl(ROLES).unwrap();
+        assert_eq!(
+            acl.check(Some("1"), false, "restart", Some("bot_b")),
+            Ok(())
+        );
+        assert_eq!(
+            acl.check(Some("1"), false, "restart", Some("bot_a")),
+            Err("caller 1 with role operator isn't allowed to run restart on bot_a".to_string())
+        );
+        // a caller with roles doesn't have `default_role` on top of them
+        assert_eq!(
+            acl.check(Some("1"), false, "list-status", None),
+            Err("caller 1 with role operator isn't allowed to run list-status".to_string())
+        );
+        // any of the roles allowing it is enough
+        assert_eq!(acl.check(Some("2"), false, "build", Some("bot_a")), Ok(()));
+        assert_eq!(acl.check(Some("3"), false, "exit", None), Ok(()));
+    }
+
+    #[test]
+    fn gives_default_role_to_commands_without_a_role() {
+        let acl = acl(ROLES).unwrap();
+        for caller in [None, Some("4")] {
+            assert_eq!(acl.check(caller, false, "list-status", None), Ok(()));
+            assert_eq!(acl.check(caller, true, "logs", Some("bot_a")), Ok(()));
+            assert!(acl.check(caller, true, "exit", None).is_err());
+        }
+        assert_eq!(
+            acl.check(None, false, "kill", Some("bot_a")),
+            Err(
+                "a command without a caller with role viewer isn't allowed to run kill on bot_a"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn gives_local_role_to_local_commands_without_a_role() {
+        let acl = acl(&format!("local_role = \"admin\"\n{}", ROLES)).unwrap();
+        assert_eq!(acl.check(None, true, "exit", None), Ok(()));
+        assert_eq!(acl.check(Some("4"), true, "kill", Some("bot_a")), Ok(()));
+        assert!(acl.check(None, false, "exit", None).is_err());
+        // a caller with roles keeps them, even locally
+        assert!(acl.check(Some("1"), true, "exit", None).is_err());
+        assert!(acl.is_admin(None, true));
+        assert!(!acl.is_admin(None, false));
+    }
+
+    #[test]
+    fn denies_everything_without_a_role_for_it() {
+        let acl = acl(&ROLES.replace("default_role = \"viewer\"", "")).unwrap();
+        assert_eq!(
+            acl.check(None, true, "list-status", None),
+            Err(
+                "a command without a caller with no role isn't allowed to run list-status"
+                    .to_string()
+            )
+        );
+        assert!(!acl.is_admin(None, true));
+    }
+
+    #[test]
+    fn gives_admin_rights_only_for_every_command_on_every_bot() {
+        let scoped = acl(r#"
+            [[role]]
+            name = "admin"
+            commands = ["*"]
+            bots = ["bot_a"]
+            callers = ["3"]
+        "#)
+        .unwrap();
+        assert!(!scoped.is_admin(Some("3"), false));
+
+        let acl = acl(ROLES).unwrap();
+        assert!(acl.is_admin(Some("3"), false));
+        for caller in [None, Some("1"), Some("2"), Some("4")] {
+            assert!(!acl.is_admin(caller, false));
+            assert!(!acl.is_admin(caller, true));
+        }
+    }
+
+    #[test]
+    fn scopes_bots_to_the_roles_of_the_caller() {
+        let acl = acl(&format!("local_role = \"admin\"\n{}", ROLES)).unwrap();
+        let bots = |bots: &[&str]| bots.iter().map(|bot| bot.to_string()).collect::<Vec<_>>();
+        // roles allowing every bot leave the bots asked for as they are
+        assert_eq!(acl.scope(Some("3"), false, &["logs"], &[]), Ok(bots(&[])));
+        assert_eq!(
+            acl.scope(None, false, &["logs"], &bots(&["bot_a"])),
+            Ok(bots(&["bot_a"]))
+        );
+        // otherwise every bot is narrowed to the bots of the roles, and so are the bots asked for
+        assert_eq!(
+            acl.scope(Some("1"), false, &["logs"], &[]),
+            Ok(bots(&["bot_b"]))
+        );
+        assert_eq!(
+            acl.scope(Some("1"), false, &["logs"], &bots(&["bot_a", "bot_b"])),
+            Ok(bots(&["bot_b"]))
+        );
+        assert_eq!(
+            acl.scope(Some("1"), false, &["logs"], &bots(&["bot_a"])),
+            Err("caller 1 with role operator isn't allowed to run logs on any of the bots asked for".to_string())
+        );
+        // every command has to be allowed
+        assert_eq!(
+            acl.scope(None, false, &["logs", "subscribe"], &[]),
+            Err("a command without a caller with role viewer isn't allowed to run logs and subscribe on any of the bots asked for".to_string())
+        );
+        assert_eq!(
+            acl.scope(None, true, &["logs", "subscribe"], &[]),
+            Ok(bots(&[]))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_roles() {
+        let role = "[[role]]\nname = \"viewer\"\ncommands = [\"list\"]\n";
+        for (toml, err) in [
+            (
+                "default_role = \"nobody\"\n".to_string() + role,
+                "default_role should contain a role name presented in a role table!",
+            ),
+            (
+                "local_role = 1\n".to_string() + role,
+                "local_role should contain a role name presented in a role table!",
+            ),
+            (
+                role.repeat(2),
+                "Multiple roles in bots.toml have identical name!",
+            ),
+            (
+                role.replace("list", "frobnicate"),
+                "Failed to parse role in bots.toml:\n\tfrobnicate in role.commands isn't a command!",
+            ),
+            (
+                role.to_string() + "bots = []\ncolor = \"red\"\n",
+                "Failed to parse role in bots.toml:\n\trole.color isn't a key of role!",
+            ),
+            (
+                "[[role]]\ncommands = [\"list\"]\n".to_string(),
+                "Failed to parse role in bots.toml:\n\tGiven role doesn't have a name!",
+            ),
+        ] {
+            assert_eq!(acl(&toml).unwrap_err(), err);
+        }
+    }
+}
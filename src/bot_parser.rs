@@ -1,3 +1,6 @@
+/// path of the configuration file, relative to the working directory
+pub const CONFIG_PATH: &str = "bots.toml";
+
 #[derive(Debug)]
 pub struct Bot {
     name: String,
@@ -6,10 +9,20 @@ pub struct Bot {
     build_args: Option<Vec<String>>,
     run_args: Option<Vec<String>>,
     token: Option<String>,
+    env: std::collections::BTreeMap<String, String>,
+    secret_env: std::collections::BTreeMap<String, String>,
+    shard_count: Option<u32>,
+    shard_range: Option<(u32, u32)>,
+    watchdog: Watchdog,
+    limits: crate::limits::Limits,
+    build_limits: crate::limits::Limits,
+    /// whether the instances of the bot keep running after bothub exits
+    detached: bool,
 }
 
 impl Bot {
-    fn from_toml_table(table: &toml::value::Table) -> Result<Bot, String> {
+    /// parses a `[[bot]]` table of bots.toml, without checking the file system
+    pub fn from_toml_table(table: &toml::value::Table) -> Result<Bot, String> {
         let name = match table.get("name") {
             Some(toml::Value::String(name)) => {
                 if name.contains(char::is_whitespace) {
@@ -104,13 +117,114 @@ impl Bot {
         };
 
         let token = match table.get("token") {
-            Some(toml::Value::String(token)) => Some(token.to_string()),
+            Some(toml::Value::String(token)) => {
+                if let Err(err) = parse_token(token) {
+                    return Err(format!("bot.token isn't a valid discord token, {}", err));
+                }
+                Some(token.to_string())
+            }
             Some(_) => {
                 return Err("bot.token should be a string!".to_string());
             }
             None => None,
         };
 
+        let env = match table.get("env") {
+            Some(toml::Value::Table(vars)) => {
+                let mut env = std::collections::BTreeMap::new();
+                for (key, value) in vars {
+                    if let toml::Value::String(value) = value {
+                        env.insert(key.to_string(), value.to_string());
+                    } else {
+                        return Err("value of bot.env should be a string!".to_string());
+                    }
+                }
+                env
+            }
+            Some(_) => {
+                return Err("bot.env should be a table!".to_string());
+            }
+            None => std::collections::BTreeMap::new(),
+        };
+
+        let secret_env = match table.get("secret_env") {
+            Some(toml::Value::Table(vars)) => {
+                let mut secret_env = std::collections::BTreeMap::new();
+                for (key, value) in vars {
+                    if env.contains_key(key) {
+                        return Err("bot.env and bot.secret_env shouldn't share a key!".to_string());
+                    }
+                    if let toml::Value::String(value) = value {
+                        secret_env.insert(key.to_string(), value.to_string());
+                    } else {
+                        return Err("value of bot.secret_env should be a string!".to_string());
+                    }
+                }
+                secret_env
+            }
+            Some(_) => {
+                return Err("bot.secret_env should be a table!".to_string());
+            }
+            None => std::collections::BTreeMap::new(),
+        };
+
+        let shard_count = match table.get("shards") {
+            Some(toml::Value::Integer(count)) => {
+                if *count < 1 || *count > u32::MAX as i64 {
+                    return Err("bot.shards should be a positive integer!".to_string());
+                }
+                Some(*count as u32)
+            }
+            Some(_) => {
+                return Err("bot.shards should be a integer!".to_string());
+            }
+            None => None,
+        };
+
+        let shard_range = match table.get("shard_range") {
+            Some(toml::Value::Array(arr)) => {
+                let shard_count = match shard_count {
+                    Some(shard_count) => shard_count,
+                    None => {
+                        return Err("bot.shard_range is presented although shards isn't!".into())
+                    }
+                };
+                let (first, last) = match arr.as_slice() {
+                    [toml::Value::Integer(first), toml::Value::Integer(last)] => (*first, *last),
+                    _ => {
+                        return Err("bot.shard_range should be a array of two integers!".into());
+                    }
+                };
+                if first < 0 || first > last || last >= shard_count as i64 {
+                    return Err(
+                        "bot.shard_range should be a range of shard ids below bot.shards!".into(),
+                    );
+                }
+                Some((first as u32, last as u32))
+            }
+            Some(_) => {
+                return Err("bot.shard_range should be a array!".to_string());
+            }
+            None => None,
+        };
+
+        let watchdog = Watchdog::from_toml_table(table)?;
+
+        let limits = crate::limits::Limits::from_toml(table.get("limits"), "limits")?;
+        let build_limits =
+            crate::limits::Limits::from_toml(table.get("build_limits"), "build_limits")?;
+        if repo_path.is_none() && table.contains_key("build_limits") {
+            return Err("bot.build_limits is presented although repo_path isn't!".to_string());
+        }
+
+        let detached = match table.get("detached") {
+            Some(toml::Value::Boolean(detached)) => *detached,
+            Some(_) => {
+                return Err("bot.detached should be a boolean!".to_string());
+            }
+            None => false,
+        };
+
         Ok(Bot {
             name,
             repo_path,
@@ -118,36 +232,146 @@ impl Bot {
             build_args,
             run_args,
             token,
+            env,
+            secret_env,
+            shard_count,
+            shard_range,
+            watchdog,
+            limits,
+            build_limits,
+            detached,
         })
     }
 
     /// checks if repo_path(if specified) and inferred executable_path actaully exists on the file system
     pub fn verify(&self) -> Result<(), String> {
-        match &self.repo_path {
-            Some(path) => match git2::Repository::open(path) {
-                Ok(_) => {}
-                Err(_) => {
-                    return Err("bot.repo_path should lead to a git directiory!".to_string());
-                }
-            },
-            None => {}
-        };
+        if let Some(path) = &self.repo_path {
+            if git2::Repository::open(path).is_err() {
+                return Err("bot.repo_path should lead to a git directiory!".to_string());
+            }
+        }
 
         if !self.executable_path.is_file() {
             return Err("Given executable_path doesn't lead to a executable file!".to_string());
         }
 
-        return Ok(());
+        Ok(())
+    }
+
+    /// checks that none of the other bots shares the token, or the bot user, with this bot,
+    /// `tokens` being pairs of a bot name and a token of it, such as the ones of `configured_tokens`
+    pub fn verify_token<'a, I>(&self, tokens: I) -> Result<(), String>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        match &self.token {
+            Some(token) => verify_unique_token(&self.name, token, tokens),
+            None => Ok(()),
+        }
     }
 
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// the application id (which is also the bot user id) decoded from the token
+    pub fn application_id(&self) -> Option<u64> {
+        self.token
+            .as_ref()
+            .and_then(|token| parse_token(token).ok())
+    }
+
+    /// the token and values of `secret_env`, which should never be revealed in any output
+    pub fn secrets(&self) -> Vec<&str> {
+        self.token
+            .iter()
+            .chain(self.secret_env.values())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// describes every configured value of the bot in lines, except the token and values of `secret_env`
+    pub fn inspect(&self) -> String {
+        let mut output = format!("name {}\n", self.name);
+        if let Some(repo_path) = &self.repo_path {
+            output.push_str(&format!("repo_path {}\n", repo_path.display()));
+        }
+        output.push_str(&format!(
+            "executable_path {}\n",
+            self.executable_path.display()
+        ));
+        if let Some(build_args) = &self.build_args {
+            output.push_str(&format!("build_args {}\n", build_args.join(" ")));
+        }
+        if let Some(run_args) = &self.run_args {
+            output.push_str(&format!("run_args {}\n", run_args.join(" ")));
+        }
+        for (key, value) in &self.env {
+            output.push_str(&format!("env {}={}\n", key, value));
+        }
+        for key in self.secret_env.keys() {
+            output.push_str(&format!("secret_env {}\n", key));
+        }
+        if let Some(shard_count) = self.shard_count {
+            output.push_str(&format!("shards {}\n", shard_count));
+        }
+        if let Some((first, last)) = self.shard_range {
+            output.push_str(&format!("shard_range {} {}\n", first, last));
+        }
+        output.push_str(&self.watchdog.inspect());
+        output.push_str(&self.limits.inspect("limits"));
+        output.push_str(&self.build_limits.inspect("build_limits"));
+        if self.detached {
+            output.push_str("detached true\n");
+        }
+        match self.application_id() {
+            Some(application_id) => {
+                output.push_str(&format!("application_id {}\n", application_id));
+            }
+            None => output.push_str("application_id none\n"),
+        }
+        output
+    }
+
+    /// the abbreviated id of the commit checked out in `repo_path`, if the bot has a repo
+    pub fn head_commit(&self) -> Option<String> {
+        let repo = git2::Repository::open(self.repo_path.as_ref()?).ok()?;
+        let commit = repo.head().ok()?.peel_to_commit().ok()?;
+        let id = commit.id().to_string();
+        Some(id[..7].to_string())
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    pub fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    /// the limits applied to every instance of the bot
+    pub fn limits(&self) -> &crate::limits::Limits {
+        &self.limits
+    }
+
     pub fn has_repo(&self) -> bool {
         self.repo_path.is_some()
     }
 
+    /// the total number of shards of the bot, or `None` if the bot isn't sharded
+    pub fn shard_count(&self) -> Option<u32> {
+        self.shard_count
+    }
+
+    /// the shard ids the hub should run for the bot, which is `[None]` for a bot that isn't sharded
+    pub fn shards(&self) -> Vec<Option<u32>> {
+        match (self.shard_count, self.shard_range) {
+            (None, _) => vec![None],
+            (Some(_), Some((first, last))) => (first..=last).map(Some).collect(),
+            (Some(count), None) => (0..count).map(Some).collect(),
+        }
+    }
+
     pub fn clean(&self) -> Result<std::process::Command, String> {
         let executable_path = match self.executable_path.canonicalize() {
             Ok(path) => path,
@@ -189,9 +413,7 @@ impl Bot {
                 command.current_dir(repo_path).arg("clean");
                 Ok(command)
             }
-            None => {
-                return Err("Target bot doesn't have a repo_path!".to_string());
-            }
+            None => Err("Target bot doesn't have a repo_path!".to_string()),
         }
     }
 
@@ -205,11 +427,10 @@ impl Bot {
                 } else {
                     command.arg("--release");
                 }
+                self.build_limits.apply(&mut command);
                 Ok(command)
             }
-            None => {
-                return Err("Target bot doesn't have a repo_path!".to_string());
-            }
+            None => Err("Target bot doesn't have a repo_path!".to_string()),
         }
     }
 
@@ -220,44 +441,383 @@ impl Bot {
                 command.current_dir(repo_path).arg("pull");
                 Ok(command)
             }
-            None => {
-                return Err("Target bot doesn't have a repo_path!".to_string());
-            }
+            None => Err("Target bot doesn't have a repo_path!".to_string()),
         }
     }
 
-    pub fn run(&self) -> std::process::Command {
+    /// whether `key` is configured in `secret_env` of the bot
+    pub fn is_secret_env(&self, key: &str) -> bool {
+        self.secret_env.contains_key(key)
+    }
+
+    /// creates the command starting the bot, `SHARD_ID` and `SHARD_COUNT` are set if `shard` is presented
+    ///
+    /// values in `overrides` take precedence over the ones loaded from bots.toml
+    pub fn run(&self, shard: Option<u32>, overrides: Option<&Overrides>) -> std::process::Command {
         let mut command = std::process::Command::new(&self.executable_path);
         if let Some(repo_path) = &self.repo_path {
             command.current_dir(repo_path);
         }
+        match overrides.and_then(|overrides| overrides.run_args.as_ref()) {
+            Some(run_args) => {
+                command.args(run_args);
+            }
+            None => {
+                if let Some(run_args) = &self.run_args {
+                    command.args(run_args);
+                }
+            }
+        }
+        command.envs(&self.env).envs(&self.secret_env);
+        if let Some(overrides) = overrides {
+            command.envs(&overrides.env);
+        }
+        match overrides.and_then(|overrides| overrides.token.as_ref()) {
+            Some(token) => {
+                command.env("DISCORD_TOKEN", token);
+            }
+            None => {
+                if let Some(token) = &self.token {
+                    command.env("DISCORD_TOKEN", token);
+                }
+            }
+        }
+        if let (Some(shard), Some(shard_count)) = (shard, self.shard_count) {
+            command.env("SHARD_ID", shard.to_string());
+            command.env("SHARD_COUNT", shard_count.to_string());
+        }
+        self.limits.apply(&mut command);
+        command
+    }
+}
+
+/// what the hub does when a instance of a bot exceeds a threshold of its `Watchdog`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    #[default]
+    Notify,
+    Restart,
+}
+
+impl std::fmt::Display for WatchdogAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Notify => "notify",
+                Self::Restart => "restart",
+            }
+        )
+    }
+}
+
+/// resource usage thresholds of a bot, checked against every sample of its instances
+#[derive(Debug, Default, Clone)]
+pub struct Watchdog {
+    pub max_rss_mb: Option<u64>,
+    pub max_threads: Option<u64>,
+    pub max_open_files: Option<u64>,
+    pub action: WatchdogAction,
+}
+
+impl Watchdog {
+    fn from_toml_table(table: &toml::value::Table) -> Result<Watchdog, String> {
+        let threshold = |key: &str| match table.get(key) {
+            Some(toml::Value::Integer(value)) if *value > 0 => Ok(Some(*value as u64)),
+            Some(_) => Err(format!("bot.{} should be a positive integer!", key)),
+            None => Ok(None),
+        };
+        let max_rss_mb = threshold("max_rss_mb")?;
+        let max_threads = threshold("max_threads")?;
+        let max_open_files = threshold("max_open_files")?;
+
+        let action = match table.get("on_exceed") {
+            Some(toml::Value::String(action)) => match action.as_str() {
+                "notify" => WatchdogAction::Notify,
+                "restart" => WatchdogAction::Restart,
+                _ => return Err("bot.on_exceed should be either notify or restart!".to_string()),
+            },
+            Some(_) => {
+                return Err("bot.on_exceed should be a string!".to_string());
+            }
+            None => WatchdogAction::Notify,
+        };
+        if table.contains_key("on_exceed")
+            && max_rss_mb.is_none()
+            && max_threads.is_none()
+            && max_open_files.is_none()
+        {
+            return Err("bot.on_exceed is presented although none of the thresholds is!".into());
+        }
+
+        Ok(Watchdog {
+            max_rss_mb,
+            max_threads,
+            max_open_files,
+            action,
+        })
+    }
+
+    /// whether any threshold is configured at all
+    pub fn is_enabled(&self) -> bool {
+        self.max_rss_mb.is_some() || self.max_threads.is_some() || self.max_open_files.is_some()
+    }
+
+    /// describes the thresholds exceeded by `usage`, e.g. `rss 600MB>512MB`, or `None` if none is exceeded
+    pub fn check(&self, usage: &crate::resources::Usage) -> Option<String> {
+        let exceeded: Vec<String> = [
+            ("rss", "MB", usage.rss_mb(), self.max_rss_mb),
+            ("threads", "", usage.threads, self.max_threads),
+            ("files", "", usage.open_files, self.max_open_files),
+        ]
+        .into_iter()
+        .filter_map(|(name, unit, value, max)| {
+            max.filter(|max| value > *max)
+                .map(|max| format!("{} {}{}>{}{}", name, value, unit, max, unit))
+        })
+        .collect();
+        (!exceeded.is_empty()).then(|| exceeded.join(" "))
+    }
+
+    /// describes the thresholds in lines like `Bot::inspect`
+    fn inspect(&self) -> String {
+        let mut output = String::new();
+        if let Some(max_rss_mb) = self.max_rss_mb {
+            output.push_str(&format!("max_rss_mb {}\n", max_rss_mb));
+        }
+        if let Some(max_threads) = self.max_threads {
+            output.push_str(&format!("max_threads {}\n", max_threads));
+        }
+        if let Some(max_open_files) = self.max_open_files {
+            output.push_str(&format!("max_open_files {}\n", max_open_files));
+        }
+        if self.is_enabled() {
+            output.push_str(&format!("on_exceed {}\n", self.action));
+        }
+        output
+    }
+}
+
+/// runtime changes to a bot, applied on top of the `Bot` loaded from bots.toml whenever it's started
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
+pub struct Overrides {
+    pub env: std::collections::BTreeMap<String, String>,
+    pub run_args: Option<Vec<String>>,
+    pub token: Option<String>,
+}
+
+impl Overrides {
+    pub fn is_empty(&self) -> bool {
+        self.env.is_empty() && self.run_args.is_none() && self.token.is_none()
+    }
+
+    /// describes every overridden value in lines like `Bot::inspect`, each prefixed with `override`
+    ///
+    /// values of keys in `secret_env` of `bot` and the token are never printed
+    pub fn inspect(&self, bot: &Bot) -> String {
+        let mut output = String::new();
+        for (key, value) in &self.env {
+            if bot.is_secret_env(key) {
+                output.push_str(&format!("override secret_env {}\n", key));
+            } else {
+                output.push_str(&format!("override env {}={}\n", key, value));
+            }
+        }
         if let Some(run_args) = &self.run_args {
-            command.args(run_args);
+            output.push_str(&format!("override run_args {}\n", run_args.join(" ")));
         }
         if let Some(token) = &self.token {
-            command.env("DISCORD_TOKEN", token);
+            match parse_token(token) {
+                Ok(application_id) => {
+                    output.push_str(&format!("override application_id {}\n", application_id));
+                }
+                Err(_) => output.push_str("override application_id none\n"),
+            }
         }
-        command
+        output
+    }
+}
+
+/// the configured token of every bot with one, paired with the name of the bot
+pub fn configured_tokens<'a, I>(bots: I) -> impl Iterator<Item = (&'a str, &'a str)>
+where
+    I: IntoIterator<Item = &'a Bot>,
+{
+    bots.into_iter()
+        .filter_map(|bot| Some((bot.name.as_str(), bot.token.as_deref()?)))
+}
+
+/// checks that `token` is a valid token, and none of `tokens` of a bot other than `bot_name`
+/// is the same token, or a token of the same bot user
+pub fn verify_unique_token<'a, I>(bot_name: &str, token: &str, tokens: I) -> Result<(), String>
+where
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    let user_id = parse_token(token)?;
+    for (other_bot, other_token) in tokens {
+        if other_bot == bot_name {
+            continue;
+        }
+        if other_token == token {
+            return Err(format!(
+                "bot.token is identical to the token of {}!",
+                other_bot
+            ));
+        }
+        if parse_token(other_token) == Ok(user_id) {
+            return Err(format!(
+                "bot.token belongs to the same bot user as the token of {}!",
+                other_bot
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// checks that `token` is made of three non-empty segments of url-safe base64 characters
+/// separated by dots, and returns the bot user id encoded in the first segment
+pub fn parse_token(token: &str) -> Result<u64, String> {
+    use base64::Engine;
+
+    if token.contains(char::is_whitespace) {
+        return Err("it contains whitespace!".to_string());
+    }
+    let segments: Vec<&str> = token.split('.').collect();
+    if segments.len() != 3 {
+        return Err("it should consist of three parts separated by dots!".to_string());
+    }
+    for segment in &segments {
+        if segment.is_empty()
+            || !segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err("its parts should be non-empty and only contain base64 characters!".into());
+        }
+    }
+
+    let user_id = segments[0].replace('-', "+").replace('_', "/");
+    let user_id = match base64::engine::general_purpose::STANDARD_NO_PAD.decode(user_id) {
+        Ok(user_id) => user_id,
+        Err(_) => {
+            return Err("its first part isn't valid base64!".to_string());
+        }
+    };
+    match String::from_utf8(user_id).map(|user_id| user_id.parse::<u64>()) {
+        Ok(Ok(user_id)) => Ok(user_id),
+        _ => Err("its first part doesn't encode a bot user id!".to_string()),
+    }
+}
+
+pub const DEFAULT_HISTORY_LIMIT: usize = 20;
+pub const DEFAULT_HISTORY_STDERR_LINES: usize = 20;
+pub const DEFAULT_SAMPLE_INTERVAL: u64 = 10;
+pub const DEFAULT_TASK_NICE: i32 = 10;
+pub const DEFAULT_TASK_IO_PRIORITY: u8 = 7;
+pub const DEFAULT_CONTROL_SOCKET_MODE: u32 = 0o600;
+pub const DEFAULT_LOCK_FILE: &str = "dcbothub.lock";
+pub const DEFAULT_PID_FILE: &str = "dcbothub.pid";
+pub const DEFAULT_LOG_FILE: &str = "dcbothub.log";
+pub const DEFAULT_STATE_FILE: &str = "dcbothub.state";
+pub const DEFAULT_BOT_LOG_DIR: &str = "logs";
+pub const DEFAULT_CONFIRM_TIMEOUT: u64 = 60;
+pub const DEFAULT_CONFIRM_THRESHOLD: usize = 4;
+pub const DEFAULT_HTTP_ADDRESS: std::net::IpAddr =
+    std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+/// values of bots.toml that apply to the whole hub instead of a single bot
+#[derive(Debug)]
+pub struct Settings {
+    pub control_bot: Option<String>,
+    /// how many records of stopped instances are kept for every bot
+    pub history_limit: usize,
+    /// how many lines at the end of stderr are kept in every record of a stopped instance
+    pub history_stderr_lines: usize,
+    /// how often the resource usage of every running instance is sampled and checked against its thresholds
+    pub sample_interval: std::time::Duration,
+    /// the priority clean, build and pull tasks run at
+    pub task_priority: crate::limits::Priority,
+    /// passed to build tasks as `CARGO_BUILD_JOBS` if presented
+    pub build_jobs: Option<u32>,
+    /// the path of the unix socket commands are accepted from, if presented
+    pub control_socket: Option<std::path::PathBuf>,
+    /// the permissions the socket is created with
+    pub control_socket_mode: u32,
+    /// the file locked by a running bothub
+    pub lock_file: std::path::PathBuf,
+    /// the file the pid of bothub is written to in daemon mode
+    pub pid_file: std::path::PathBuf,
+    /// the file stdout and stderr of bothub are appended to in daemon mode, and while the dashboard of `tui` is shown
+    pub log_file: std::path::PathBuf,
+    /// the file detached instances are recorded in, so the next bothub can adopt them
+    pub state_file: std::path::PathBuf,
+    /// the directory detached instances write their stdout and stderr to
+    pub bot_log_dir: std::path::PathBuf,
+    /// the repo of bothub itself, which `self-update` pulls and builds, if presented
+    pub self_repo_path: Option<std::path::PathBuf>,
+    /// the address the HTTP API listens on, if it's enabled with `http_port`
+    pub http_address: Option<std::net::SocketAddr>,
+    /// the bearer tokens accepted by the HTTP API, along with the caller each of them is for
+    pub http_tokens: Vec<crate::http::HttpToken>,
+    /// the roles commands from callers are checked against
+    pub acl: crate::acl::Acl,
+    /// how long a command waits to be confirmed
+    pub confirm_timeout: std::time::Duration,
+    /// how many instances `restart` can restart at once without confirming it
+    pub confirm_threshold: usize,
+}
+
+/// reads `control_socket` from `toml`
+fn parse_control_socket(toml: &toml::Value) -> Result<Option<std::path::PathBuf>, String> {
+    match toml.get("control_socket") {
+        Some(toml::Value::String(path)) if !path.is_empty() => Ok(Some(path.into())),
+        Some(_) => Err("control_socket should be a non-empty string!".to_string()),
+        None => Ok(None),
     }
 }
 
-use toml;
-pub fn parse_bots() -> Result<(std::collections::HashMap<String, Bot>, Option<String>), ()> {
-    let file = match std::fs::read_to_string("bots.toml") {
+/// reads the path at `key` of `toml`, `default` if it isn't presented
+fn parse_file_path(toml: &toml::Value, key: &str, default: &str) -> Result<std::path::PathBuf, ()> {
+    match toml.get(key) {
+        Some(toml::Value::String(path)) if !path.is_empty() => Ok(path.into()),
+        Some(_) => {
+            println!("{} should be a non-empty string!", key);
+            Err(())
+        }
+        None => Ok(default.into()),
+    }
+}
+
+/// reads only `control_socket` from bots.toml, for connecting to a running bothub
+pub fn control_socket_path() -> Result<Option<std::path::PathBuf>, String> {
+    let file = std::fs::read_to_string(CONFIG_PATH)
+        .map_err(|_| "Failed to open bots.toml, check your working directory...".to_string())?;
+    let toml = file
+        .parse::<toml::Value>()
+        .map_err(|err| format!("Failed to parse bots.toml as a valid toml file:\n\t{}", err))?;
+    parse_control_socket(&toml)
+}
+
+#[allow(clippy::result_unit_err)]
+pub fn parse_bots() -> Result<(std::collections::HashMap<String, Bot>, Settings), ()> {
+    let file = match std::fs::read_to_string(CONFIG_PATH) {
         Ok(file) => file,
         Err(_) => {
             println!("Failed to open bots.toml, check your working directory...");
             return Err(());
         }
     };
+    parse_config(&file)
+}
 
+/// parses and verifies `file` as bots.toml, see `parse_bots`
+#[allow(clippy::result_unit_err)]
+pub fn parse_config(file: &str) -> Result<(std::collections::HashMap<String, Bot>, Settings), ()> {
     let toml = match file.parse::<toml::Value>() {
         Ok(toml) => toml,
         Err(err) => {
-            println!(
-                "Failed to parse bots.toml as a valid toml file:\n\t{}",
-                err.to_string()
-            );
+            println!("Failed to parse bots.toml as a valid toml file:\n\t{}", err);
             return Err(());
         }
     };
@@ -305,11 +865,30 @@ pub fn parse_bots() -> Result<(std::collections::HashMap<String, Bot>, Option<St
         };
     }
 
+    for bot in hashmap.values() {
+        if let Err(err) = bot.verify_token(configured_tokens(hashmap.values())) {
+            println!("Failed on verifying token for {}:\n\t{}", bot.name(), err);
+            return Err(());
+        }
+    }
+
     let control_bot = match toml.get("control_bot") {
         Some(toml::Value::String(control_bot)) => {
-            if !hashmap.contains_key(control_bot) {
-                println!("control_bot should contain a bot name presented in a bot table");
-                return Err(());
+            match hashmap.get(control_bot) {
+                Some(bot) => {
+                    if bot.shard_count().is_some() {
+                        println!("control_bot shouldn't be a sharded bot");
+                        return Err(());
+                    }
+                    if bot.is_detached() {
+                        println!("control_bot shouldn't be a detached bot");
+                        return Err(());
+                    }
+                }
+                None => {
+                    println!("control_bot should contain a bot name presented in a bot table");
+                    return Err(());
+                }
             }
             Some(control_bot.to_string())
         }
@@ -320,5 +899,335 @@ pub fn parse_bots() -> Result<(std::collections::HashMap<String, Bot>, Option<St
         None => None,
     };
 
-    Ok((hashmap, control_bot))
+    let history_limit = match toml.get("history_limit") {
+        Some(toml::Value::Integer(limit)) if *limit >= 0 => *limit as usize,
+        Some(_) => {
+            println!("history_limit should be a non-negative integer!");
+            return Err(());
+        }
+        None => DEFAULT_HISTORY_LIMIT,
+    };
+
+    let history_stderr_lines = match toml.get("history_stderr_lines") {
+        Some(toml::Value::Integer(lines)) if *lines >= 0 => *lines as usize,
+        Some(_) => {
+            println!("history_stderr_lines should be a non-negative integer!");
+            return Err(());
+        }
+        None => DEFAULT_HISTORY_STDERR_LINES,
+    };
+
+    let sample_interval = match toml.get("sample_interval") {
+        Some(toml::Value::Integer(secs)) if *secs > 0 => *secs as u64,
+        Some(_) => {
+            println!("sample_interval should be a positive integer!");
+            return Err(());
+        }
+        None => DEFAULT_SAMPLE_INTERVAL,
+    };
+
+    let task_nice = match toml.get("task_nice") {
+        Some(toml::Value::Integer(nice)) if (-20..=19).contains(nice) => *nice as i32,
+        Some(_) => {
+            println!("task_nice should be a integer from -20 to 19!");
+            return Err(());
+        }
+        None => DEFAULT_TASK_NICE,
+    };
+
+    let task_io_priority = match toml.get("task_io_priority") {
+        Some(toml::Value::Integer(priority)) if (0..=7).contains(priority) => *priority as u8,
+        Some(_) => {
+            println!("task_io_priority should be a integer from 0 to 7!");
+            return Err(());
+        }
+        None => DEFAULT_TASK_IO_PRIORITY,
+    };
+
+    let task_io_class = match toml.get("task_io_class").map(toml::Value::as_str) {
+        Some(Some("idle")) => {
+            if toml.get("task_io_priority").is_some() {
+                println!("task_io_priority is presented although task_io_class is idle!");
+                return Err(());
+            }
+            crate::limits::IoClass::Idle
+        }
+        Some(Some("best-effort")) | None => crate::limits::IoClass::BestEffort(task_io_priority),
+        Some(_) => {
+            println!("task_io_class should be either idle or best-effort!");
+            return Err(());
+        }
+    };
+
+    let build_jobs = match toml.get("build_jobs") {
+        Some(toml::Value::Integer(jobs)) if *jobs > 0 && *jobs <= u32::MAX as i64 => {
+            Some(*jobs as u32)
+        }
+        Some(_) => {
+            println!("build_jobs should be a positive integer!");
+            return Err(());
+        }
+        None => None,
+    };
+
+    let control_socket = match parse_control_socket(&toml) {
+        Ok(control_socket) => control_socket,
+        Err(err) => {
+            println!("{}", err);
+            return Err(());
+        }
+    };
+
+    let control_socket_mode = match toml.get("control_socket_mode") {
+        Some(toml::Value::Integer(mode)) if (0..=0o777).contains(mode) => {
+            if control_socket.is_none() {
+                println!("control_socket_mode is presented although control_socket isn't!");
+                return Err(());
+            }
+            *mode as u32
+        }
+        Some(_) => {
+            println!("control_socket_mode should be a integer from 0o000 to 0o777!");
+            return Err(());
+        }
+        None => DEFAULT_CONTROL_SOCKET_MODE,
+    };
+
+    let lock_file = parse_file_path(&toml, "lock_file", DEFAULT_LOCK_FILE)?;
+    let pid_file = parse_file_path(&toml, "pid_file", DEFAULT_PID_FILE)?;
+    let log_file = parse_file_path(&toml, "log_file", DEFAULT_LOG_FILE)?;
+    let state_file = parse_file_path(&toml, "state_file", DEFAULT_STATE_FILE)?;
+    let bot_log_dir = parse_file_path(&toml, "bot_log_dir", DEFAULT_BOT_LOG_DIR)?;
+
+    let self_repo_path = match toml.get("self_repo_path") {
+        Some(toml::Value::String(path)) if std::path::Path::new(path).is_dir() => Some(path.into()),
+        Some(_) => {
+            println!("self_repo_path should be the path of a existing directory!");
+            return Err(());
+        }
+        None => None,
+    };
+
+    let http_port = match toml.get("http_port") {
+        Some(toml::Value::Integer(port)) if (1..=u16::MAX as i64).contains(port) => {
+            Some(*port as u16)
+        }
+        Some(_) => {
+            println!("http_port should be a integer from 1 to 65535!");
+            return Err(());
+        }
+        None => None,
+    };
+
+    let http_ip = match toml.get("http_address").map(toml::Value::as_str) {
+        Some(Some(address)) => match address.parse::<std::net::IpAddr>() {
+            Ok(_) if http_port.is_none() => {
+                println!("http_address is presented although http_port isn't!");
+                return Err(());
+            }
+            Ok(address) => address,
+            Err(_) => {
+                println!("http_address should be a ip address!");
+                return Err(());
+            }
+        },
+        Some(None) => {
+            println!("http_address should be a ip address!");
+            return Err(());
+        }
+        None => DEFAULT_HTTP_ADDRESS,
+    };
+
+    let http_tokens = match toml.get("http_tokens") {
+        Some(toml::Value::Array(tokens)) => {
+            let tokens: Result<Vec<crate::http::HttpToken>, String> = tokens
+                .iter()
+                .map(crate::http::HttpToken::from_toml)
+                .collect();
+            match tokens {
+                Ok(_) if http_port.is_none() => {
+                    println!("http_tokens is presented although http_port isn't!");
+                    return Err(());
+                }
+                Ok(tokens) if !tokens.is_empty() => tokens,
+                Ok(_) => {
+                    println!("http_tokens should be a non-empty array!");
+                    return Err(());
+                }
+                Err(err) => {
+                    println!("{}", err);
+                    return Err(());
+                }
+            }
+        }
+        Some(_) => {
+            println!("http_tokens should be a non-empty array!");
+            return Err(());
+        }
+        None if http_port.is_some() => {
+            println!("http_tokens is required for the HTTP API enabled with http_port!");
+            return Err(());
+        }
+        None => Vec::new(),
+    };
+
+    let confirm_timeout = match toml.get("confirm_timeout") {
+        Some(toml::Value::Integer(secs)) if *secs > 0 => *secs as u64,
+        Some(_) => {
+            println!("confirm_timeout should be a positive integer!");
+            return Err(());
+        }
+        None => DEFAULT_CONFIRM_TIMEOUT,
+    };
+
+    let confirm_threshold = match toml.get("confirm_threshold") {
+        Some(toml::Value::Integer(instances)) if *instances >= 0 => *instances as usize,
+        Some(_) => {
+            println!("confirm_threshold should be a non-negative integer!");
+            return Err(());
+        }
+        None => DEFAULT_CONFIRM_THRESHOLD,
+    };
+
+    let acl = match crate::acl::Acl::parse(&toml) {
+        Ok(acl) => acl,
+        Err(err) => {
+            println!("{}", err);
+            return Err(());
+        }
+    };
+
+    Ok((
+        hashmap,
+        Settings {
+            control_bot,
+            history_limit,
+            history_stderr_lines,
+            sample_interval: std::time::Duration::from_secs(sample_interval),
+            task_priority: crate::limits::Priority {
+                nice: task_nice,
+                io_class: task_io_class,
+            },
+            build_jobs,
+            control_socket,
+            control_socket_mode,
+            lock_file,
+            pid_file,
+            log_file,
+            state_file,
+            bot_log_dir,
+            self_repo_path,
+            http_address: http_port.map(|port| std::net::SocketAddr::new(http_ip, port)),
+            http_tokens,
+            acl,
+            confirm_timeout: std::time::Duration::from_secs(confirm_timeout),
+            confirm_threshold,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN_A: &str = "MTExMTExMTExMTExMTExMTEx.BotTok.EN3Sh4rD3dB0tsRuN0n3Pr0c3sSP3rSh4rd";
+    const TOKEN_B: &str = "MjIyMjIyMjIyMjIyMjIyMjIy.BotTok.EN3Sh4rD3dB0tsRuN0n3Pr0c3sSP3rSh4rd";
+    // another token of the bot user of `TOKEN_A`
+    const TOKEN_A2: &str = "MTExMTExMTExMTExMTExMTEx.R3s3tT.N3wT0k3nAft3rR3s3tt1ngTh3B0tUs3r";
+
+    fn bot(name: &str, token: &str) -> Bot {
+        let table = [
+            ("name", name),
+            ("executable_path", "/bin/true"),
+            ("token", token),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), toml::Value::from(value)))
+        .collect();
+        Bot::from_toml_table(&table).unwrap()
+    }
+
+    #[test]
+    fn parses_the_bot_user_id_of_a_token() {
+        assert_eq!(parse_token(TOKEN_A), Ok(111111111111111111));
+        assert_eq!(parse_token(TOKEN_A2), Ok(111111111111111111));
+        // url-safe characters are fine anywhere
+        assert_eq!(
+            parse_token("MjIyMjIyMjIyMjIyMjIyMjIy.Bot-_k.EN3Sh4rD3dB0tsRuN0n3Pr0c3s-_3rSh4rd"),
+            Ok(222222222222222222)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        for (token, err) in [
+            (
+                "MTEx.BotTok",
+                "it should consist of three parts separated by dots!",
+            ),
+            (
+                "MTEx.BotTok.Secret.Extra",
+                "it should consist of three parts separated by dots!",
+            ),
+            (
+                "MTEx..Secret",
+                "its parts should be non-empty and only contain base64 characters!",
+            ),
+            (
+                "MTEx.Bot=ok.Secret",
+                "its parts should be non-empty and only contain base64 characters!",
+            ),
+            ("MTEx.BotTok.Sec ret", "it contains whitespace!"),
+            // a single base64 character can't encode a whole byte
+            ("MTExM.BotTok.Secret", "its first part isn't valid base64!"),
+            // "bot", which isn't a user id
+            (
+                "Ym90.BotTok.Secret",
+                "its first part doesn't encode a bot user id!",
+            ),
+        ] {
+            assert_eq!(parse_token(token), Err(err.to_string()), "{}", token);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_tokens_in_bot_tables() {
+        let table = [
+            ("name", "bot_a"),
+            ("executable_path", "/bin/true"),
+            ("token", "MTEx"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), toml::Value::from(value)))
+        .collect();
+        assert_eq!(
+            Bot::from_toml_table(&table).unwrap_err(),
+            "bot.token isn't a valid discord token, it should consist of three parts separated by dots!"
+        );
+    }
+
+    #[test]
+    fn rejects_tokens_shared_across_bots() {
+        let bot_a = bot("bot_a", TOKEN_A);
+        let bot_b = bot("bot_b", TOKEN_B);
+        let bot_c = bot("bot_c", TOKEN_A2);
+        assert_eq!(
+            bot_a.verify_token(configured_tokens([&bot_a, &bot_b])),
+            Ok(())
+        );
+        assert_eq!(
+            bot_a.verify_token(configured_tokens([&bot_b, &bot("bot_c", TOKEN_A)])),
+            Err("bot.token is identical to the token of bot_c!".to_string())
+        );
+        assert_eq!(
+            bot_a.verify_token(configured_tokens([&bot_b, &bot_c])),
+            Err("bot.token belongs to the same bot user as the token of bot_c!".to_string())
+        );
+        // the tokens of the bot itself, such as the overridden one, never count
+        assert_eq!(
+            verify_unique_token("bot_a", TOKEN_A, [("bot_a", TOKEN_A2), ("bot_b", TOKEN_B)]),
+            Ok(())
+        );
+        assert!(verify_unique_token("bot_a", "MTEx.BotTok", [("bot_b", TOKEN_B)]).is_err());
+    }
 }
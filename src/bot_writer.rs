@@ -0,0 +1,253 @@
+use std::path::Path;
+
+use crate::bot_parser::CONFIG_PATH;
+
+fn read_config(path: &Path) -> Result<toml_edit::DocumentMut, String> {
+    let file = std::fs::read_to_string(path)
+        .map_err(|err| format!("Failed to open bots.toml, {}", err))?;
+    file.parse::<toml_edit::DocumentMut>()
+        .map_err(|err| format!("Failed to parse bots.toml, {}", err))
+}
+
+/// replaces the config at `path` with `config` by renaming a temporary file over it,
+/// so bots.toml is never left half-written
+fn write_config(path: &Path, config: &toml_edit::DocumentMut) -> Result<(), String> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, config.to_string())
+        .map_err(|err| format!("Failed to write bots.toml, {}", err))?;
+    std::fs::rename(&tmp_path, path).map_err(|err| format!("Failed to write bots.toml, {}", err))
+}
+
+/// applies `edit` to the config at `path`, which is only written back if `edit` succeeds
+fn edit_config<F>(path: &Path, edit: F) -> Result<(), String>
+where
+    F: FnOnce(&mut toml_edit::DocumentMut) -> Result<(), String>,
+{
+    let mut config = read_config(path)?;
+    edit(&mut config)?;
+    write_config(path, &config)
+}
+
+/// the `[[bot]]` table named `bot_name` in `config`
+fn bot_table<'a>(
+    config: &'a mut toml_edit::DocumentMut,
+    bot_name: &str,
+) -> Result<&'a mut toml_edit::Table, String> {
+    config
+        .get_mut("bot")
+        .and_then(toml_edit::Item::as_array_of_tables_mut)
+        .and_then(|bots| {
+            bots.iter_mut()
+                .find(|bot| bot.get("name").and_then(toml_edit::Item::as_str) == Some(bot_name))
+        })
+        .ok_or_else(|| format!("{} isn't presented in bots.toml", bot_name))
+}
+
+/// applies `update` to the `[[bot]]` table named `bot_name` in bots.toml
+pub fn update_bot<F>(bot_name: &str, update: F) -> Result<(), String>
+where
+    F: FnOnce(&mut toml_edit::Table),
+{
+    edit_config(Path::new(CONFIG_PATH), |config| {
+        update(bot_table(config, bot_name)?);
+        Ok(())
+    })
+}
+
+/// sets `key` in the table named `table_name` (e.g. `env`) of a bot table,
+/// creating it as a inline table if it doesn't exist
+pub fn set_table_value(bot: &mut toml_edit::Table, table_name: &str, key: &str, value: &str) {
+    let table = bot
+        .entry(table_name)
+        .or_insert_with(|| toml_edit::value(toml_edit::InlineTable::new()));
+    table[key] = toml_edit::value(value);
+}
+
+/// sets `key` of a bot table to a array of strings
+pub fn set_array(bot: &mut toml_edit::Table, key: &str, values: &[String]) {
+    bot[key] = toml_edit::value(values.iter().collect::<toml_edit::Array>());
+}
+
+/// appends `bot` as a new `[[bot]]` table at the end of bots.toml
+pub fn add_bot(bot: &toml::value::Table) -> Result<(), String> {
+    edit_config(Path::new(CONFIG_PATH), |config| push_bot(config, bot))
+}
+
+/// appends `bot` as a new `[[bot]]` table at the end of `config`
+fn push_bot(config: &mut toml_edit::DocumentMut, bot: &toml::value::Table) -> Result<(), String> {
+    let mut table = toml_edit::Table::new();
+    // keep the name at the top of the table, like the rest of bots.toml
+    let name = bot.get("name").map(|name| ("name", name));
+    let rest = bot
+        .iter()
+        .filter(|(key, _)| *key != "name")
+        .map(|(key, value)| (key.as_str(), value));
+    for (key, value) in name.into_iter().chain(rest) {
+        let value = value
+            .to_string()
+            .parse::<toml_edit::Value>()
+            .map_err(|err| format!("Failed to convert bot.{}, {}", key, err))?;
+        table[key] = toml_edit::value(value);
+    }
+    match config
+        .entry("bot")
+        .or_insert_with(|| toml_edit::ArrayOfTables::new().into())
+        .as_array_of_tables_mut()
+    {
+        Some(bots) => bots.push(table),
+        None => return Err("bot in bots.toml should be a array of table!".to_string()),
+    }
+    Ok(())
+}
+
+/// removes the `[[bot]]` table named `bot_name` from bots.toml
+pub fn remove_bot(bot_name: &str) -> Result<(), String> {
+    edit_config(Path::new(CONFIG_PATH), |config| pop_bot(config, bot_name))
+}
+
+/// removes the `[[bot]]` table named `bot_name` from `config`
+fn pop_bot(config: &mut toml_edit::DocumentMut, bot_name: &str) -> Result<(), String> {
+    let bots = config
+        .get_mut("bot")
+        .and_then(toml_edit::Item::as_array_of_tables_mut)
+        .ok_or_else(|| "bot in bots.toml should be a array of table!".to_string())?;
+    let len = bots.len();
+    bots.retain(|bot| bot.get("name").and_then(toml_edit::Item::as_str) != Some(bot_name));
+    if bots.len() == len {
+        return Err(format!("{} isn't presented in bots.toml", bot_name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    const CONFIG: &str = r#"# bots run by bothub
+control_bot = "bot_a"   # relays commands
+
+[[bot]]
+name = "bot_a"
+executable_path = "/bin/true"
+# kept in sync with the dashboard
+env = { MODE = "prod" }
+
+[[bot]]
+name    = "bot_b"
+executable_path = "/bin/false"
+"#;
+
+    /// a config file of its own for a test, removed once it's dropped
+    struct TempConfig(std::path::PathBuf);
+
+    impl TempConfig {
+        fn new(name: &str) -> TempConfig {
+            let path =
+                std::env::temp_dir().join(format!("dcbothub-{}-{}.toml", name, std::process::id()));
+            std::fs::write(&path, CONFIG).unwrap();
+            TempConfig(path)
+        }
+
+        fn read(&self) -> String {
+            std::fs::read_to_string(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn edit<F>(config: &str, edit: F) -> Result<String, String>
+    where
+        F: FnOnce(&mut toml_edit::DocumentMut) -> Result<(), String>,
+    {
+        let mut config = config.parse::<toml_edit::DocumentMut>().unwrap();
+        edit(&mut config)?;
+        Ok(config.to_string())
+    }
+
+    #[test]
+    fn keeps_comments_and_formatting_of_other_tables() {
+        let updated = edit(CONFIG, |config| {
+            let bot = bot_table(config, "bot_a")?;
+            set_table_value(bot, "env", "LEVEL", "debug");
+            set_array(bot, "run_args", &["--shard".to_string(), "0".to_string()]);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            updated,
+            CONFIG.replace(
+                "env = { MODE = \"prod\" }\n",
+                "env = { MODE = \"prod\" , LEVEL = \"debug\" }\nrun_args = [\"--shard\", \"0\"]\n"
+            )
+        );
+        assert_eq!(
+            edit(CONFIG, |config| bot_table(config, "bot_c").map(|_| ())),
+            Err("bot_c isn't presented in bots.toml".to_string())
+        );
+    }
+
+    #[test]
+    fn adds_a_bot_at_the_end_with_its_name_first() {
+        let bot: toml::Value = toml::from_str(
+            "executable_path = \"/bin/true\"\nname = \"bot_c\"\nrun_args = [\"-v\"]",
+        )
+        .unwrap();
+        let added = edit(CONFIG, |config| push_bot(config, bot.as_table().unwrap())).unwrap();
+        assert_eq!(
+            added,
+            format!(
+                "{}\n[[bot]]\nname = \"bot_c\"\nexecutable_path = \"/bin/true\"\nrun_args = [\"-v\"]\n",
+                CONFIG
+            )
+        );
+        assert!(edit("bot = 1", |config| push_bot(
+            config,
+            bot.as_table().unwrap()
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn removes_only_the_bot() {
+        let removed = edit(CONFIG, |config| pop_bot(config, "bot_b")).unwrap();
+        assert_eq!(
+            removed,
+            CONFIG.replace(
+                "\n[[bot]]\nname    = \"bot_b\"\nexecutable_path = \"/bin/false\"\n",
+                ""
+            )
+        );
+        assert_eq!(
+            edit(CONFIG, |config| pop_bot(config, "bot_c")),
+            Err("bot_c isn't presented in bots.toml".to_string())
+        );
+    }
+
+    #[test]
+    fn replaces_the_file_by_renaming() {
+        let config = TempConfig::new("rename");
+        let inode = std::fs::metadata(&config.0).unwrap().ino();
+        edit_config(&config.0, |config| pop_bot(config, "bot_b")).unwrap();
+        // a file written in place would keep its inode
+        assert_ne!(std::fs::metadata(&config.0).unwrap().ino(), inode);
+        assert!(!config.read().contains("bot_b"));
+        let mut tmp_path = config.0.clone().into_os_string();
+        tmp_path.push(".tmp");
+        assert!(!Path::new(&tmp_path).exists());
+    }
+
+    #[test]
+    fn leaves_the_file_alone_if_the_edit_fails() {
+        let config = TempConfig::new("failed");
+        let inode = std::fs::metadata(&config.0).unwrap().ino();
+        assert!(edit_config(&config.0, |config| pop_bot(config, "bot_c")).is_err());
+        assert_eq!(std::fs::metadata(&config.0).unwrap().ino(), inode);
+        assert_eq!(config.read(), CONFIG);
+    }
+}
@@ -6,6 +6,15 @@ use clap::{Parser, Subcommand};
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+    /// output the result of the command in json, in a line
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// whom the command is run for, such as a Discord user id, which is checked against `role` in bots.toml
+    #[arg(long, global = true)]
+    pub caller: Option<String>,
+    /// run a command which needs confirming right away, which requires admin rights
+    #[arg(long, global = true)]
+    pub yes: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -20,8 +29,22 @@ pub enum Commands {
     ListStatus,
     /// list running/finished tasks such as build processes or pull processes
     ListTasks,
-    /// get the status of a specific bot
-    Status { bot_name: String },
+    /// get the status of a specific bot, or of a single shard of it
+    Status {
+        bot_name: String,
+        #[arg(long)]
+        shard: Option<u32>,
+    },
+    /// print out the exit status and output of a bot, or of a single shard of it, without removing it
+    Logs {
+        bot_name: String,
+        #[arg(long)]
+        shard: Option<u32>,
+    },
+    /// show the resource usage of every running instance, or of every running instance of a bot
+    Top { bot_name: Option<String> },
+    /// list the records of stopped instances of a bot, oldest first
+    History { bot_name: String },
     /// get the status of a specific task
     TaskStatus { task_id: String },
     /// perform a "cargo clean" at the repo of a bot without removing the executable
@@ -32,29 +55,267 @@ pub enum Commands {
     Build { bot_name: String },
     /// perform a "git pull" at the repo of a bot
     Pull { bot_name: String },
-    /// start the bot if it isn't already runninng
-    Start { bot_name: String },
-    /// print a message to the stdin of the a bot
+    /// start the bot, or a single shard of it, if it isn't already runninng
+    Start {
+        bot_name: String,
+        #[arg(long)]
+        shard: Option<u32>,
+    },
+    /// stop the bot, or a single shard of it, and start it again
+    Restart {
+        bot_name: String,
+        #[arg(long)]
+        shard: Option<u32>,
+    },
+    /// stop every bot except control_bot, and every shard of them, and start them again
+    RestartAll,
+    /// print a message to the stdin of the a bot, or of a single shard of it
     Msg {
         bot_name: String,
+        #[arg(long)]
+        shard: Option<u32>,
         #[arg(action = clap::ArgAction::Append)]
         message: Vec<String>,
     },
-    /// verify all paths loaded from `bots.toml`,
+    /// describe the configuration of a bot loaded from bots.toml, without revealing its token
+    Inspect { bot_name: String },
+    /// override environment variables of a bot, given as `KEY=VALUE`, from the next time it starts
+    SetEnv {
+        bot_name: String,
+        #[arg(required = true)]
+        vars: Vec<String>,
+        /// also write the values to bots.toml
+        #[arg(long)]
+        save: bool,
+    },
+    /// override `run_args` of a bot from the next time it starts, with the arguments given after `--`
+    SetArgs {
+        bot_name: String,
+        /// also write the arguments to bots.toml
+        #[arg(long)]
+        save: bool,
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// override the token of a bot from the next time it starts,
+    /// read from a file so the token itself is never a part of a command
+    SetToken {
+        bot_name: String,
+        /// a file containing the token of the bot
+        #[arg(long)]
+        token_file: String,
+        /// also write the token to bots.toml
+        #[arg(long)]
+        save: bool,
+    },
+    /// remove every runtime override of a bot
+    ClearOverrides { bot_name: String },
+    /// add a new bot, checked the same way as a bot in bots.toml, and write it to bots.toml
+    AddBot {
+        bot_name: String,
+        /// `repo_path` of the bot
+        #[arg(long)]
+        repo: Option<String>,
+        /// `executable_path` of the bot
+        #[arg(long)]
+        executable: Option<String>,
+        /// a file containing the token of the bot
+        #[arg(long)]
+        token_file: Option<String>,
+        /// an element of `build_args` of the bot, can be given multiple times
+        #[arg(long = "build-arg", allow_hyphen_values = true)]
+        build_args: Vec<String>,
+        /// an element of `run_args` of the bot, can be given multiple times
+        #[arg(long = "run-arg", allow_hyphen_values = true)]
+        run_args: Vec<String>,
+        /// `shards` of the bot
+        #[arg(long)]
+        shards: Option<u32>,
+    },
+    /// remove a bot without any instance, and remove it from bots.toml
+    RemoveBot { bot_name: String },
+    /// verify all paths and tokens loaded from `bots.toml`,
     /// or only paths of the bot `BOT_NAME` if presented
     Verify { bot_name: Option<String> },
-    /// stop a bot with the given name
-    Kill { bot_name: String },
+    /// stop a bot with the given name, or a single shard of it
+    Kill {
+        bot_name: String,
+        #[arg(long)]
+        shard: Option<u32>,
+    },
     /// kill the control bot, then attempt to restart it
     ControlRestart,
     /// stop a task with the given id
     Terminate { task_id: String },
-    /// print out the exit status and output of a stopped bot and remove it from `bot_instances`
-    Conclude { bot_name: String },
-    /// blockingly wait a task to finish, or to fail, and return the exit status of the task
-    Wait { task_id: String },
+    /// print out the exit status and output of a stopped bot, or a single shard of it, and remove it from `bot_instances`
+    Conclude {
+        bot_name: String,
+        #[arg(long)]
+        shard: Option<u32>,
+    },
+    /// wait a task to finish, or to fail, and return the exit status of the task,
+    /// blocking only the client that asked until then, or until `timeout` seconds passes
+    Wait {
+        task_id: String,
+        #[arg(long)]
+        timeout: Option<u64>,
+    },
+    /// cancel the `wait` the client is blocked on
+    Cancel,
+    /// push the given kinds of events (or every kind) to the client, optionally only the ones about the given bots
+    Subscribe {
+        events: Vec<crate::events::EventKind>,
+        #[arg(long = "bot")]
+        bots: Vec<String>,
+    },
+    /// stop pushing the given kinds of events (or any event) to the client
+    Unsubscribe {
+        events: Vec<crate::events::EventKind>,
+    },
+    /// run the command waiting to be confirmed with `token`
+    Confirm { token: String },
+    /// switch the protocol the client speaks
+    Hello {
+        #[arg(value_enum, default_value_t)]
+        protocol: crate::protocol::Protocol,
+    },
     /// print out the exit status and output of a finished/failed task and remove it from `tasks`
     Finish { task_id: String },
+    /// pull and build `self_repo_path`, then switch to the new executable without restarting any bot
+    SelfUpdate,
     /// kill all running tasks and bots and exit dcbothub
     Exit,
 }
+
+impl Commands {
+    /// the name the command is invoked with, such as `list-status`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::List => "list",
+            Self::ListExisting => "list-existing",
+            Self::ListExecuting => "list-executing",
+            Self::ListStatus => "list-status",
+            Self::ListTasks => "list-tasks",
+            Self::Status { .. } => "status",
+            Self::Logs { .. } => "logs",
+            Self::Top { .. } => "top",
+            Self::History { .. } => "history",
+            Self::TaskStatus { .. } => "task-status",
+            Self::Clean { .. } => "clean",
+            Self::CleanAll { .. } => "clean-all",
+            Self::Build { .. } => "build",
+            Self::Pull { .. } => "pull",
+            Self::Start { .. } => "start",
+            Self::Restart { .. } => "restart",
+            Self::RestartAll => "restart-all",
+            Self::Msg { .. } => "msg",
+            Self::Inspect { .. } => "inspect",
+            Self::SetEnv { .. } => "set-env",
+            Self::SetArgs { .. } => "set-args",
+            Self::SetToken { .. } => "set-token",
+            Self::ClearOverrides { .. } => "clear-overrides",
+            Self::AddBot { .. } => "add-bot",
+            Self::RemoveBot { .. } => "remove-bot",
+            Self::Verify { .. } => "verify",
+            Self::Kill { .. } => "kill",
+            Self::ControlRestart => "control-restart",
+            Self::Terminate { .. } => "terminate",
+            Self::Conclude { .. } => "conclude",
+            Self::Wait { .. } => "wait",
+            Self::Cancel => "cancel",
+            Self::Subscribe { .. } => "subscribe",
+            Self::Unsubscribe { .. } => "unsubscribe",
+            Self::Confirm { .. } => "confirm",
+            Self::Hello { .. } => "hello",
+            Self::Finish { .. } => "finish",
+            Self::SelfUpdate => "self-update",
+            Self::Exit => "exit",
+        }
+    }
+
+    /// the bot the command is about, `None` for commands about every bot or about none
+    ///
+    /// commands on a task are about the bot of the task, which only the hub knows
+    pub fn bot_name(&self) -> Option<&str> {
+        match self {
+            Self::Status { bot_name, .. }
+            | Self::Logs { bot_name, .. }
+            | Self::History { bot_name }
+            | Self::Clean { bot_name }
+            | Self::CleanAll { bot_name }
+            | Self::Build { bot_name }
+            | Self::Pull { bot_name }
+            | Self::Start { bot_name, .. }
+            | Self::Restart { bot_name, .. }
+            | Self::Msg { bot_name, .. }
+            | Self::Inspect { bot_name }
+            | Self::SetEnv { bot_name, .. }
+            | Self::SetArgs { bot_name, .. }
+            | Self::SetToken { bot_name, .. }
+            | Self::ClearOverrides { bot_name }
+            | Self::AddBot { bot_name, .. }
+            | Self::RemoveBot { bot_name }
+            | Self::Kill { bot_name, .. }
+            | Self::Conclude { bot_name, .. } => Some(bot_name),
+            Self::Top { bot_name } | Self::Verify { bot_name } => bot_name.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// the task the command is about
+    pub fn task_id(&self) -> Option<&str> {
+        match self {
+            Self::TaskStatus { task_id }
+            | Self::Terminate { task_id }
+            | Self::Wait { task_id, .. }
+            | Self::Finish { task_id } => Some(task_id),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Commands, String> {
+        Cli::try_parse_from(std::iter::once("dcbothub").chain(line.split_whitespace()))
+            .map(|cli| cli.command)
+            .map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn takes_set_args_arguments_after_the_separator() {
+        let Ok(Commands::SetArgs {
+            bot_name,
+            save,
+            args,
+        }) = parse("set-args bot_a --save -- --save --shard 1")
+        else {
+            panic!("set-args isn't parsed");
+        };
+        assert_eq!(bot_name, "bot_a");
+        assert!(save);
+        assert_eq!(args, ["--save", "--shard", "1"]);
+
+        let Ok(Commands::SetArgs { save, args, .. }) = parse("set-args bot_a --") else {
+            panic!("set-args isn't parsed");
+        };
+        assert!(!save);
+        assert!(args.is_empty());
+        assert!(parse("set-args bot_a --shard 1").is_err());
+    }
+
+    #[test]
+    fn reads_set_token_from_a_file() {
+        let Ok(Commands::SetToken {
+            token_file, save, ..
+        }) = parse("set-token bot_a --token-file token.txt")
+        else {
+            panic!("set-token isn't parsed");
+        };
+        assert_eq!(token_file, "token.txt");
+        assert!(!save);
+        assert!(parse("set-token bot_a MTEx.BotTok.Secret").is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use serde_json::json;
+
+use crate::cmd_parser::Commands;
+use crate::hub::{Hub, Output};
+
+/// how many random bytes a token is made of, so it can't be guessed before it expires
+const TOKEN_BYTES: usize = 16;
+
+/// a command waiting for `confirm` with its token
+struct Confirmation {
+    command: Commands,
+    /// the caller of the command, the only caller allowed to confirm it
+    caller: Option<String>,
+    /// whether the command is from a local client, which only a local client may confirm
+    local: bool,
+    deadline: Instant,
+}
+
+/// the commands waiting to be confirmed, by their token
+#[derive(Default)]
+pub struct Confirmations(HashMap<String, Confirmation>);
+
+impl Confirmations {
+    /// checks `command` of `caller` against roles and asks for confirming it if it's hard to undo,
+    /// returning the command to run, which is the one confirmed for `confirm`, or the reply to it otherwise
+    ///
+    /// `local` tells whether the command is from a local client, and only confirms from a client as local as the one asking
+    pub fn admit(
+        &mut self,
+        hub: &Hub,
+        caller: Option<String>,
+        local: bool,
+        yes: bool,
+        command: Commands,
+    ) -> Result<Commands, Output> {
+        let authorized = hub
+            .authorize(caller.as_deref(), local, &command)
+            .and_then(|()| match yes && hub.needs_confirmation(&command) {
+                true => hub.authorize_yes(caller.as_deref(), local, &command),
+                false => Ok(()),
+            });
+        if let Err(denied) = authorized {
+            eprint!("{}", hub.redact(&denied.text));
+            return Err(denied);
+        }
+        let now = Instant::now();
+        self.0.retain(|_, confirmation| confirmation.deadline > now);
+        match command {
+            Commands::Confirm { token } => match self.0.get(&token) {
+                Some(confirmation)
+                    if confirmation.caller == caller && confirmation.local == local =>
+                {
+                    Ok(self.0.remove(&token).unwrap().command)
+                }
+                _ => Err(Output::new("none\n", json!({ "result": "none" }))),
+            },
+            command if !yes && hub.needs_confirmation(&command) => {
+                let token = token().map_err(|err| Output::error("err", &err))?;
+                let timeout = hub.confirm_timeout();
+                let name = command.name();
+                let bot = command.bot_name().map(str::to_string);
+                let output = Output::new(
+                    match &bot {
+                        Some(bot) => {
+                            format!("confirm {} {} {} {}\n", token, timeout.as_secs(), name, bot)
+                        }
+                        None => format!("confirm {} {} {}\n", token, timeout.as_secs(), name),
+                    },
+                    json!({
+                        "result": "confirm",
+                        "token": token,
+                        "command": name,
+                        "bot": bot,
+                        "timeout_seconds": timeout.as_secs(),
+                    }),
+                );
+                self.0.insert(
+                    token,
+                    Confirmation {
+                        command,
+                        caller,
+                        local,
+                        deadline: now + timeout,
+                    },
+                );
+                Err(output)
+            }
+            command => Ok(command),
+        }
+    }
+}
+
+/// a new token for confirming a command, which is random so a caller can't confirm the command of another
+fn token() -> Result<String, String> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    let read = unsafe { libc::getrandom(bytes.as_mut_ptr().cast(), bytes.len(), 0) };
+    if read != bytes.len() as isize {
+        return Err(format!(
+            "Failed to get random bytes, {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROLES: &str = r#"
+        default_role = "operator"
+        local_role = "admin"
+
+        [[bot]]
+        name = "bot_a"
+        executable_path = "/bin/true"
+
+        [[role]]
+        name = "operator"
+        commands = ["kill", "exit"]
+        callers = ["1", "2"]
+
+        [[role]]
+        name = "admin"
+        commands = ["*"]
+        callers = ["3"]
+    "#;
+
+    fn hub() -> Hub {
+        let (bots, settings) = crate::bot_parser::parse_config(ROLES).unwrap();
+        Hub::new(bots, settings, std::sync::Arc::new(|_| {}))
+    }
+
+    fn kill() -> Commands {
+        Commands::Kill {
+            bot_name: "bot_a".to_string(),
+            shard: None,
+        }
+    }
+
+    fn confirm(token: &str) -> Commands {
+        Commands::Confirm {
+            token: token.to_string(),
+        }
+    }
+
+    /// asks for confirming `kill` of `caller`, returning the token
+    fn ask(confirmations: &mut Confirmations, hub: &Hub, caller: Option<&str>) -> String {
+        let asked = confirmations.admit(hub, caller.map(str::to_string), false, false, kill());
+        let json = asked.unwrap_err().json;
+        assert_eq!(json["result"], "confirm");
+        json["token"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn confirms_once_only_for_the_caller_asking() {
+        let hub = hub();
+        let mut confirmations = Confirmations::default();
+        let token = ask(&mut confirmations, &hub, Some("1"));
+        assert_eq!(token.len(), TOKEN_BYTES * 2);
+        assert_ne!(token, ask(&mut confirmations, &hub, Some("1")));
+
+        for (caller, local) in [(Some("2"), false), (None, false), (None, true)] {
+            let confirmed = confirmations.admit(
+                &hub,
+                caller.map(str::to_string),
+                local,
+                false,
+                confirm(&token),
+            );
+            assert_eq!(confirmed.unwrap_err().text, "none\n");
+        }
+        let confirm_as_1 = |confirmations: &mut Confirmations| {
+            confirmations.admit(&hub, Some("1".to_string()), false, false, confirm(&token))
+        };
+        assert!(matches!(
+            confirm_as_1(&mut confirmations),
+            Ok(Commands::Kill { .. })
+        ));
+        assert_eq!(confirm_as_1(&mut confirmations).unwrap_err().text, "none\n");
+    }
+
+    #[test]
+    fn forgets_expired_confirmations() {
+        let hub = hub();
+        let mut confirmations = Confirmations::default();
+        let token = ask(&mut confirmations, &hub, None);
+        confirmations.0.get_mut(&token).unwrap().deadline = Instant::now();
+        let confirmed = confirmations.admit(&hub, None, false, false, confirm(&token));
+        assert_eq!(confirmed.unwrap_err().text, "none\n");
+        assert!(confirmations.0.is_empty());
+    }
+
+    #[test]
+    fn only_skips_confirming_with_admin_rights() {
+        let hub = hub();
+        let mut confirmations = Confirmations::default();
+        for (caller, local) in [(Some("1"), false), (None, false)] {
+            let admitted =
+                confirmations.admit(&hub, caller.map(str::to_string), local, true, kill());
+            assert_eq!(admitted.unwrap_err().json["result"], "denied");
+        }
+        for (caller, local) in [(Some("3"), false), (None, true)] {
+            let admitted =
+                confirmations.admit(&hub, caller.map(str::to_string), local, true, kill());
+            assert!(matches!(admitted, Ok(Commands::Kill { .. })));
+        }
+        assert!(confirmations.0.is_empty());
+    }
+}
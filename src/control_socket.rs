@@ -0,0 +1,160 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use crate::bot_parser;
+
+/// binds the unix socket commands are accepted from, and sets its permissions to `mode`
+///
+/// a socket left behind by a bothub which didn't exit cleanly is replaced,
+/// while a socket some running bothub still listens on is not
+///
+/// the socket is bound in a directory only the owner can enter, and only moved to `path` once its mode is set,
+/// so nobody else can connect to it in between
+pub fn bind(path: &Path, mode: u32) -> Result<UnixListener, String> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!(
+                "{} is being listened on by another bothub",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(path)
+            .map_err(|err| format!("Failed removing stale {}, {}", path.display(), err))?;
+    }
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(path.file_name().unwrap_or_default());
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(dir_name);
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|err| format!("Failed creating {}, {}", dir.display(), err))?;
+    let bound = dir.join("socket");
+    let listener = UnixListener::bind(&bound)
+        .map_err(|err| format!("Failed binding {}, {}", path.display(), err))
+        .and_then(|listener| {
+            std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode)).map_err(
+                |err| format!("Failed setting permissions of {}, {}", path.display(), err),
+            )?;
+            std::fs::rename(&bound, path)
+                .map_err(|err| format!("Failed moving {}, {}", path.display(), err))?;
+            Ok(listener)
+        });
+    // the socket is only left in it if binding failed
+    let _ = std::fs::remove_file(&bound);
+    let _ = std::fs::remove_dir(&dir);
+    listener
+}
+
+/// the arguments of `dcbothub ctl`, which sends commands to a running bothub through its control socket
+#[derive(clap::Args, Debug)]
+pub struct Ctl {
+    /// the socket to connect to, `control_socket` in bots.toml by default
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// the command to send, commands are read from stdin line by line if it's absent
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+/// connects to the control socket at `socket`, or at `control_socket` in bots.toml if it's absent
+pub fn connect(socket: Option<PathBuf>) -> Result<UnixStream, String> {
+    let path = match socket {
+        Some(path) => path,
+        None => bot_parser::control_socket_path()?
+            .ok_or_else(|| "control_socket isn't presented in bots.toml".to_string())?,
+    };
+    UnixStream::connect(&path)
+        .map_err(|err| format!("Failed connecting to {}, {}", path.display(), err))
+}
+
+/// runs `dcbothub ctl`, printing the reply to every command sent
+pub fn ctl(args: Ctl) -> Result<(), String> {
+    let stream = connect(args.socket)?;
+    let mut reader = BufReader::new(
+        stream
+            .try_clone()
+            .map_err(|err| format!("Failed cloning the connection, {}", err))?,
+    );
+    let mut writer = stream;
+
+    let mut send = |command: &str| -> Result<(), String> {
+        writeln!(writer, "{}", command)
+            .and_then(|_| writer.flush())
+            .map_err(|err| format!("Failed sending the command, {}", err))?;
+        print_reply(&mut reader)
+    };
+    if !args.command.is_empty() {
+        return send(&args.command.join(" "));
+    }
+    for line in std::io::stdin().lock().lines() {
+        let line = line.map_err(|err| format!("Failed reading stdin, {}", err))?;
+        if !line.trim().is_empty() {
+            send(&line)?;
+        }
+    }
+    Ok(())
+}
+
+/// prints lines from bothub until the reply to the last command is printed
+///
+/// replies in the text protocol start with their line count, which isn't printed, while events
+/// and anything in the json protocol are single lines printed as is
+///
+/// `exit` isn't replied, bothub just closes the connection
+fn print_reply(reader: &mut impl BufRead) -> Result<(), String> {
+    let mut stdout = std::io::stdout().lock();
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        if let Ok(count) = line.trim_end().parse::<usize>() {
+            for _ in 0..count {
+                let line = read_line(reader)?
+                    .ok_or_else(|| "bothub closed the connection amid a reply".to_string())?;
+                write!(stdout, "{}", line).unwrap();
+            }
+            return Ok(());
+        }
+        write!(stdout, "{}", line).unwrap();
+        let event = line.starts_with("event ")
+            || serde_json::from_str::<serde_json::Value>(&line)
+                .is_ok_and(|line| line["type"] == "event");
+        if !event {
+            return Ok(());
+        }
+    }
+}
+
+/// reads a line from bothub, `None` if the connection is closed
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(line)),
+        Err(err) => Err(format!("Failed reading the reply, {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binds_with_the_mode_set_and_nothing_left_behind() {
+        let dir = std::env::temp_dir().join(format!("dcbothub-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hub.sock");
+        let listener = bind(&path, 0o600).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert!(UnixStream::connect(&path).is_ok());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(bind(&path, 0o600).is_err());
+        drop(listener);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
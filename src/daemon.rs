@@ -0,0 +1,82 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// takes the exclusive lock on `path`, so a second bothub in the same directory refuses to start,
+/// and writes the pid of bothub to it
+///
+/// the lock is released when the returned file is closed, which happens however bothub exits,
+/// so the file itself is left in place
+pub fn lock(path: &Path) -> Result<File, String> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|err| format!("Failed opening {}, {}", path.display(), err))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::WouldBlock {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(format!(
+                "{} is held by another bothub (pid {})",
+                path.display(),
+                pid.trim()
+            ));
+        }
+        return Err(format!("Failed locking {}, {}", path.display(), err));
+    }
+    write_pid(&mut file, std::process::id())
+        .map_err(|err| format!("Failed writing {}, {}", path.display(), err))?;
+    Ok(file)
+}
+
+/// replaces the content of `file` with `pid` in a line
+pub fn write_pid(file: &mut File, pid: u32) -> std::io::Result<()> {
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", pid)
+}
+
+/// forks bothub into a new session detached from the terminal,
+/// with stdin read from `/dev/null` and stdout and stderr appended to `log_path`
+///
+/// returns the pid of the detached bothub in the original process, and `None` in the detached one;
+/// it must be called before any thread is spawned, since only the calling thread is forked
+pub fn detach(log_path: &Path) -> Result<Option<u32>, String> {
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .map_err(|err| format!("Failed opening {}, {}", log_path.display(), err))?;
+    let null =
+        File::open("/dev/null").map_err(|err| format!("Failed opening /dev/null, {}", err))?;
+    // anything buffered would otherwise be written by both processes
+    let _ = std::io::stdout().flush();
+
+    match unsafe { libc::fork() } {
+        -1 => Err(format!(
+            "Failed forking, {}",
+            std::io::Error::last_os_error()
+        )),
+        0 => {
+            let redirected = unsafe {
+                libc::setsid() != -1
+                    && libc::dup2(null.as_raw_fd(), 0) != -1
+                    && libc::dup2(log.as_raw_fd(), 1) != -1
+                    && libc::dup2(log.as_raw_fd(), 2) != -1
+            };
+            if !redirected {
+                return Err(format!(
+                    "Failed detaching from the terminal, {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            Ok(None)
+        }
+        pid => Ok(Some(pid as u32)),
+    }
+}
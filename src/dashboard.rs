@@ -0,0 +1,173 @@
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_json::Value;
+
+use crate::status::{describe, text};
+
+/// the cookie the dashboard keeps the token logged in with, which is accepted wherever a bearer token is
+pub const TOKEN_COOKIE: &str = "dcbothub_token";
+
+/// the actions the dashboard has buttons for on every bot
+pub const ACTIONS: [&str; 3] = ["restart", "build", "pull"];
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+table{border-collapse:collapse;margin-bottom:2em}\
+th,td{border:1px solid #ccc;padding:.3em .6em;text-align:left;vertical-align:top}\
+.running{color:#080}.exited,.failed{color:#b00}\
+form{display:inline}pre{max-height:20em;overflow:auto;background:#f4f4f4;margin:0}";
+
+/// the dashboard of every instance in `instances` and every task in `tasks`, the json outputs of `list-status` and `list-tasks`
+///
+/// the output of tasks is streamed from the first item still kept, and the page reloads on any event
+/// numbered after `rendered`, the last item of the stream before the page is rendered
+///
+/// `done` is what the last button did, and `confirm` the token of the action it asks for confirming, if any
+pub fn page(
+    instances: &Value,
+    tasks: &Value,
+    rendered: u64,
+    done: Option<&str>,
+    confirm: Option<&str>,
+) -> String {
+    let mut instances: Vec<&Value> = instances.as_array().into_iter().flatten().collect();
+    instances.sort_by_key(|instance| (text(&instance["bot"]), instance["shard"].as_u64()));
+    let mut tasks: Vec<&Value> = tasks.as_array().into_iter().flatten().collect();
+    tasks.sort_by_key(|task| text(&task["task_id"]));
+
+    let mut html = head("dcbothub");
+    html.push_str(
+        "<h1>dcbothub</h1>\n\
+         <form method=\"post\" action=\"/dashboard/logout\"><button>log out</button></form>\n\
+         <a href=\"/dashboard\">refresh</a>\n",
+    );
+    if let Some(done) = done {
+        html.push_str(&format!("<p><b>{}</b></p>\n", escape(done)));
+    }
+    if let Some(token) = confirm {
+        html.push_str(&format!(
+            "<form method=\"post\" action=\"/dashboard/confirm/{}\"><button>confirm</button></form>\n",
+            utf8_percent_encode(token, NON_ALPHANUMERIC)
+        ));
+    }
+
+    html.push_str(
+        "<h2>Bots</h2>\n<table>\n<tr><th>bot</th><th>state</th><th>pid</th><th>since</th>\
+         <th>uptime</th><th>restarts</th><th>commit</th><th></th></tr>\n",
+    );
+    for instance in instances {
+        let bot = text(&instance["bot"]);
+        let name = match instance["shard"].as_u64() {
+            Some(shard) => format!("{}#{}", bot, shard),
+            None => bot.clone(),
+        };
+        let uptime = instance["uptime_seconds"]
+            .as_u64()
+            .map(|uptime| crate::instance::format_uptime(std::time::Duration::from_secs(uptime)))
+            .unwrap_or_default();
+        let mut actions = String::new();
+        for action in ACTIONS {
+            let shard = match (action, instance["shard"].as_u64()) {
+                ("restart", Some(shard)) => format!("?shard={}", shard),
+                _ => String::new(),
+            };
+            actions.push_str(&format!(
+                "<form method=\"post\" action=\"/dashboard/bots/{}/{}{}\"><button>{}</button></form> ",
+                utf8_percent_encode(&bot, NON_ALPHANUMERIC),
+                action,
+                shard,
+                action
+            ));
+        }
+        html.push_str(&format!(
+            "<tr><td>{}</td><td class=\"{}\">{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape(&name),
+            escape(&text(&instance["state"])),
+            escape(&describe(instance)),
+            text(&instance["pid"]),
+            escape(&text(&instance["since"])),
+            uptime,
+            text(&instance["restarts"]),
+            escape(&text(&instance["commit"])),
+            actions
+        ));
+    }
+    html.push_str("</table>\n");
+
+    html.push_str(
+        "<h2>Tasks</h2>\n<table>\n<tr><th>task</th><th>bot</th><th>type</th><th>state</th><th>output</th></tr>\n",
+    );
+    for task in tasks {
+        let task_id = text(&task["task_id"]);
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td class=\"{}\">{}</td><td><pre id=\"task-{}\"></pre></td></tr>\n",
+            escape(&task_id),
+            escape(&text(&task["bot"])),
+            escape(&text(&task["task_type"])),
+            escape(&text(&task["state"])),
+            escape(&describe(task)),
+            escape(&task_id)
+        ));
+    }
+    html.push_str("</table>\n");
+
+    html.push_str(&format!(
+        "<script>\n\
+         const rendered = {};\n\
+         const source = new EventSource(\"/stream?after=0&types=task-stdout,task-stderr,bot-started,bot-exited,task-finished\");\n\
+         const line = event => {{\n\
+           const item = JSON.parse(event.data);\n\
+           const output = document.getElementById(\"task-\" + item.task_id);\n\
+           if (output) {{\n\
+             output.textContent += item.data + \"\\n\";\n\
+             output.scrollTop = output.scrollHeight;\n\
+           }}\n\
+         }};\n\
+         source.addEventListener(\"task-stdout\", line);\n\
+         source.addEventListener(\"task-stderr\", line);\n\
+         for (const kind of [\"bot-started\", \"bot-exited\", \"task-finished\"]) {{\n\
+           source.addEventListener(kind, event => {{\n\
+             if (JSON.parse(event.data).seq > rendered) location.reload();\n\
+           }});\n\
+         }}\n\
+         </script>\n</body>\n</html>\n",
+        rendered
+    ));
+    html
+}
+
+/// the page asking for a token, which is posted to `/dashboard/login`
+pub fn login(error: Option<&str>) -> String {
+    let mut html = head("dcbothub login");
+    html.push_str("<h1>dcbothub</h1>\n");
+    if let Some(error) = error {
+        html.push_str(&format!("<p class=\"failed\">{}</p>\n", escape(error)));
+    }
+    html.push_str(
+        "<form method=\"post\" action=\"/dashboard/login\">\n\
+         <input type=\"password\" name=\"token\" placeholder=\"token\" autofocus>\n\
+         <button>log in</button>\n</form>\n</body>\n</html>\n",
+    );
+    html
+}
+
+fn head(title: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n",
+        escape(title),
+        STYLE
+    )
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,160 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+/// the kinds of events pushed to subscribed clients
+#[derive(
+    clap::ValueEnum,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    /// a instance of a bot started, including restarts
+    BotStarted,
+    /// a instance of a bot exited, for whatever reason
+    BotExited,
+    /// a task such as a build exited
+    TaskFinished,
+    /// `verify` found a bot with a invalid configuration
+    VerifyFailed,
+    /// a instance exceeded a watchdog threshold
+    Watchdog,
+    /// `self-update` made progress, failed, or switched to the new executable
+    SelfUpdate,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 6] = [
+        Self::BotStarted,
+        Self::BotExited,
+        Self::TaskFinished,
+        Self::VerifyFailed,
+        Self::Watchdog,
+        Self::SelfUpdate,
+    ];
+}
+
+impl std::fmt::Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::BotStarted => "bot-started",
+                Self::BotExited => "bot-exited",
+                Self::TaskFinished => "task-finished",
+                Self::VerifyFailed => "verify-failed",
+                Self::Watchdog => "watchdog",
+                Self::SelfUpdate => "self-update",
+            }
+        )
+    }
+}
+
+/// something that happened in bothub without being asked for
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    /// the bot the event is about
+    pub bot_name: String,
+    /// the rest of the event, after the kind
+    pub details: String,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, bot_name: &str, details: String) -> Event {
+        Event {
+            kind,
+            bot_name: bot_name.to_string(),
+            details,
+        }
+    }
+
+    /// the event in a single line, which starts with `event` so it can't be mistaken for the line count of a reply
+    ///
+    /// `event` *EventKind* *Details*
+    pub fn line(&self) -> String {
+        format!(
+            "event {} {}\n",
+            self.kind,
+            self.details.trim_end().replace('\n', " ")
+        )
+    }
+}
+
+/// the events a client subscribed to
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Subscription {
+    kinds: HashSet<EventKind>,
+    /// only events about these bots are pushed, unless it's empty
+    bots: HashSet<String>,
+}
+
+impl Subscription {
+    /// subscribes to `kinds`, or to every kind if it's empty,
+    /// and only to events about `bots` from now on if it isn't empty
+    pub fn subscribe(&mut self, kinds: &[EventKind], bots: &[String]) {
+        match kinds {
+            [] => self.kinds.extend(EventKind::ALL),
+            kinds => self.kinds.extend(kinds),
+        }
+        if !bots.is_empty() {
+            self.bots = bots.iter().cloned().collect();
+        }
+    }
+
+    /// unsubscribes from `kinds`, or from every kind if it's empty
+    pub fn unsubscribe(&mut self, kinds: &[EventKind]) {
+        match kinds {
+            [] => *self = Subscription::default(),
+            kinds => self.kinds.retain(|kind| !kinds.contains(kind)),
+        }
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        self.kinds.contains(&event.kind)
+            && (self.bots.is_empty() || self.bots.contains(&event.bot_name))
+    }
+
+    /// `{"events": [`*EventKind*...`], "bots": [`*BotName*...`]}`
+    pub fn json(&self) -> serde_json::Value {
+        let mut kinds: Vec<_> = self.kinds.iter().collect();
+        kinds.sort();
+        let kinds: Vec<String> = kinds.into_iter().map(EventKind::to_string).collect();
+        let mut bots: Vec<&String> = self.bots.iter().collect();
+        bots.sort();
+        serde_json::json!({ "events": kinds, "bots": bots })
+    }
+
+    /// (`none`|`some` *EventKind*... [`bots` *BotName*...])
+    pub fn describe(&self) -> String {
+        if self.kinds.is_empty() {
+            return "none\n".to_string();
+        }
+        let mut kinds: Vec<_> = self.kinds.iter().collect();
+        kinds.sort();
+        let mut output = "some".to_string();
+        for kind in kinds {
+            output.push_str(&format!(" {}", kind));
+        }
+        if !self.bots.is_empty() {
+            let mut bots: Vec<_> = self.bots.iter().collect();
+            bots.sort();
+            output.push_str(" bots");
+            for bot in bots {
+                output.push_str(&format!(" {}", bot));
+            }
+        }
+        output.push('\n');
+        output
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::instance::{Finishing, Instance};
+use crate::InstanceKey;
+
+/// why a instance is removed from `bot_instances`, or `Exit` if it's recorded as soon as it exited
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    /// exited on its own (or by `kill`), which is recorded before it's removed
+    Exit,
+    Conclude,
+    Restart,
+    ControlRestart,
+    /// restarted for exceeding a resource threshold
+    Watchdog,
+}
+
+impl EndReason {
+    /// whether another instance is started to replace the removed one
+    pub fn is_restart(&self) -> bool {
+        matches!(self, Self::Restart | Self::ControlRestart | Self::Watchdog)
+    }
+}
+
+impl std::fmt::Display for EndReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Exit => "exit",
+                Self::Conclude => "conclude",
+                Self::Restart => "restart",
+                Self::ControlRestart => "control-restart",
+                Self::Watchdog => "watchdog",
+            }
+        )
+    }
+}
+
+/// a stopped (or failed) bot instance
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    pub shard: Option<u32>,
+    pub pid: Option<u32>,
+    /// `None` if the instance failed to start
+    pub started_at: Option<SystemTime>,
+    pub stopped_at: SystemTime,
+    /// the exit status of the instance, or the description of how it failed to start
+    pub status: Result<crate::instance::Exit, String>,
+    /// why the instance was started, e.g. `startup`, `start` or `restart`
+    pub start_reason: String,
+    pub end_reason: EndReason,
+    /// the last lines of stderr of the instance
+    pub stderr_tail: Vec<String>,
+}
+
+impl Record {
+    /// records a instance removed from `bot_instances`, the instance should already have exited
+    ///
+    /// along with the end of its stderr, which the stderr tail is taken from once it's done
+    pub fn new(
+        shard: Option<u32>,
+        instance: Result<Instance, String>,
+        end_reason: EndReason,
+    ) -> (Record, Option<Finishing>) {
+        match instance {
+            Ok(mut instance) => Record::of_instance(shard, &mut instance, end_reason),
+            Err(err) => (
+                Record {
+                    shard,
+                    pid: None,
+                    started_at: None,
+                    stopped_at: SystemTime::now(),
+                    status: Err(err),
+                    start_reason: "unknown".to_string(),
+                    end_reason,
+                    stderr_tail: Vec::new(),
+                },
+                None,
+            ),
+        }
+    }
+
+    /// records a instance which already exited, whether it's still in `bot_instances` or not,
+    /// see `Record::new`
+    fn of_instance(
+        shard: Option<u32>,
+        instance: &mut Instance,
+        end_reason: EndReason,
+    ) -> (Record, Option<Finishing>) {
+        let status = instance
+            .try_wait()
+            .map_err(|err| err.to_string())
+            .and_then(|status| status.ok_or_else(|| "still running".to_string()));
+        let record = Record {
+            shard,
+            pid: Some(instance.pid()),
+            started_at: Some(instance.started_at()),
+            stopped_at: instance.exited_at().unwrap_or_else(SystemTime::now),
+            status,
+            start_reason: instance.start_reason().to_string(),
+            end_reason,
+            stderr_tail: Vec::new(),
+        };
+        (record, instance.finishing_stderr())
+    }
+
+    /// sets the stderr tail to the last `stderr_lines` lines of `stderr`
+    fn set_stderr_tail(&mut self, stderr: &[String], stderr_lines: usize) {
+        self.stderr_tail = stderr[stderr.len().saturating_sub(stderr_lines)..].to_vec();
+    }
+
+    /// whether the record is of `instance`
+    fn is_of(&self, shard: Option<u32>, instance: &Instance) -> bool {
+        self.shard == shard
+            && self.pid == Some(instance.pid())
+            && self.started_at == Some(instance.started_at())
+    }
+
+    /// the record in json, with the status of the instance when it's stopped
+    pub fn json(&self, bot_name: &str) -> serde_json::Value {
+        let mut status = crate::status::Status {
+            bot: Some(bot_name.to_string()),
+            shard: self.shard,
+            pid: self.pid,
+            ..crate::status::Status::none()
+        };
+        match &self.status {
+            Ok(exit) => status.set_exit(exit),
+            Err(err) => status.set_failed(err),
+        }
+        status.json_with(serde_json::json!({
+            "started_at": self
+                .started_at
+                .map(|started_at| humantime::format_rfc3339_seconds(started_at).to_string()),
+            "stopped_at": humantime::format_rfc3339_seconds(self.stopped_at).to_string(),
+            "start_reason": self.start_reason,
+            "end_reason": self.end_reason.to_string(),
+            "stderr_tail": self.stderr_tail,
+        }))
+    }
+
+    /// a line describing the record, then the line count of the stderr tail, then the stderr tail
+    pub fn describe(&self, bot_name: &str) -> String {
+        let mut output = crate::instance_name(&(bot_name.to_string(), self.shard));
+        if let Some(pid) = self.pid {
+            output.push_str(&format!(" pid {}", pid));
+        }
+        if let Some(started_at) = self.started_at {
+            output.push_str(&format!(
+                " started {}",
+                humantime::format_rfc3339_seconds(started_at)
+            ));
+        }
+        output.push_str(&format!(
+            " stopped {}",
+            humantime::format_rfc3339_seconds(self.stopped_at)
+        ));
+        match &self.status {
+            Ok(exit) => output.push_str(&format!(" {}", exit.description())),
+            Err(err) => output.push_str(&format!(" failed {}", err)),
+        }
+        output.push_str(&format!(
+            " start {} end {}\n{}\n",
+            self.start_reason,
+            self.end_reason,
+            self.stderr_tail.len()
+        ));
+        for line in &self.stderr_tail {
+            output.push_str(line);
+            output.push('\n');
+        }
+        output
+    }
+}
+
+/// records of stopped instances of every bot, oldest first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct History {
+    limit: usize,
+    stderr_lines: usize,
+    records: HashMap<String, VecDeque<Record>>,
+    /// a list of pairs in json, which only has string keys
+    #[serde(serialize_with = "serialize_restarts")]
+    #[serde(deserialize_with = "deserialize_restarts")]
+    restarts: HashMap<InstanceKey, u32>,
+    /// records waiting for stderr of their instance to be read to the end, which is never handed over,
+    /// see `History::take_finishing`
+    #[serde(skip)]
+    pending: Vec<Pending>,
+}
+
+/// a record of a bot kept out of the history until the stderr of the instance is read to the end
+#[derive(Debug, Clone)]
+struct Pending {
+    bot_name: String,
+    record: Record,
+    stderr: Finishing,
+    /// whether the end of stderr is taken by `take_finishing` to be waited on
+    taken: bool,
+}
+
+fn serialize_restarts<S: Serializer>(
+    restarts: &HashMap<InstanceKey, u32>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(restarts)
+}
+
+fn deserialize_restarts<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<InstanceKey, u32>, D::Error> {
+    Ok(Vec::<(InstanceKey, u32)>::deserialize(deserializer)?
+        .into_iter()
+        .collect())
+}
+
+impl History {
+    /// keeps at most `limit` records for every bot, each with at most `stderr_lines` lines of stderr
+    pub fn new(limit: usize, stderr_lines: usize) -> History {
+        History {
+            limit,
+            stderr_lines,
+            records: HashMap::new(),
+            restarts: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// applies `limit` and `stderr_lines` from the current settings to a history handed over by `self-update`,
+    /// dropping the oldest records of every bot with too many
+    pub fn set_limits(&mut self, limit: usize, stderr_lines: usize) {
+        self.limit = limit;
+        self.stderr_lines = stderr_lines;
+        for records in self.records.values_mut() {
+            while records.len() > limit {
+                records.pop_front();
+            }
+        }
+        self.records.retain(|_, records| !records.is_empty());
+    }
+
+    /// records a instance removed from `bot_instances`, unless it was recorded by `record_exit` already
+    pub fn record(
+        &mut self,
+        (bot_name, shard): InstanceKey,
+        instance: Result<Instance, String>,
+        end_reason: EndReason,
+    ) {
+        if end_reason.is_restart() {
+            *self.restarts.entry((bot_name.clone(), shard)).or_default() += 1;
+        }
+        if let Ok(instance) = &instance {
+            if self.is_recorded(&bot_name, shard, instance) {
+                return;
+            }
+        }
+        if self.limit == 0 {
+            return;
+        }
+        let (record, stderr) = Record::new(shard, instance, end_reason);
+        self.add(bot_name, record, stderr);
+    }
+
+    /// records a instance as soon as its exit is noticed, so a instance which is never removed is recorded as well
+    pub fn record_exit(&mut self, (bot_name, shard): &InstanceKey, instance: &mut Instance) {
+        if self.limit == 0 || self.is_recorded(bot_name, *shard, instance) {
+            return;
+        }
+        let (record, stderr) = Record::of_instance(*shard, instance, EndReason::Exit);
+        self.add(bot_name.clone(), record, stderr);
+    }
+
+    fn is_recorded(&self, bot_name: &str, shard: Option<u32>, instance: &Instance) -> bool {
+        self.records
+            .get(bot_name)
+            .is_some_and(|records| records.iter().any(|record| record.is_of(shard, instance)))
+            || self.pending.iter().any(|pending| {
+                pending.bot_name == bot_name && pending.record.is_of(shard, instance)
+            })
+    }
+
+    /// adds a record right away if the end of stderr is done, or once it's done otherwise,
+    /// so reading stderr to the end never holds up the caller
+    fn add(&mut self, bot_name: String, mut record: Record, stderr: Option<Finishing>) {
+        match stderr {
+            Some(stderr) if !stderr.is_done() => self.pending.push(Pending {
+                bot_name,
+                record,
+                stderr,
+                taken: false,
+            }),
+            stderr => {
+                if let Some(stderr) = stderr {
+                    record.set_stderr_tail(&stderr.lines(), self.stderr_lines);
+                }
+                self.push(bot_name, record);
+            }
+        }
+    }
+
+    /// takes the end of stderr of every record added since the last call which isn't done yet,
+    /// along with the pid of the instance, which is passed to `finish` once it's done
+    pub fn take_finishing(&mut self) -> Vec<(u32, Finishing)> {
+        self.pending
+            .iter_mut()
+            .filter(|pending| !pending.taken)
+            .filter_map(|pending| {
+                pending.taken = true;
+                Some((pending.record.pid?, pending.stderr.clone()))
+            })
+            .collect()
+    }
+
+    /// adds every record of the instance `pid` whose stderr is done
+    pub fn finish(&mut self, pid: u32) {
+        let (done, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|pending| pending.record.pid == Some(pid) && pending.stderr.is_done());
+        self.pending = pending;
+        for Pending {
+            bot_name,
+            mut record,
+            stderr,
+            ..
+        } in done
+        {
+            record.set_stderr_tail(&stderr.lines(), self.stderr_lines);
+            self.push(bot_name, record);
+        }
+    }
+
+    /// a copy of the history with every pending record added with the stderr captured so far,
+    /// to be handed to the executable `self-update` switches to
+    pub fn settled(&self) -> History {
+        let mut history = self.clone();
+        for Pending {
+            bot_name,
+            mut record,
+            stderr,
+            ..
+        } in std::mem::take(&mut history.pending)
+        {
+            record.set_stderr_tail(&stderr.lines(), history.stderr_lines);
+            history.push(bot_name, record);
+        }
+        history
+    }
+
+    /// adds a record of a bot, dropping the oldest record of the bot if it has too many
+    fn push(&mut self, bot_name: String, record: Record) {
+        let records = self.records.entry(bot_name).or_default();
+        while records.len() >= self.limit {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    pub fn get(&self, bot_name: &str) -> Option<&VecDeque<Record>> {
+        self.records.get(bot_name)
+    }
+
+    /// how many times a bot (or a shard of it) has been restarted, regardless of the retention limit
+    pub fn restarts(&self, key: &InstanceKey) -> u32 {
+        self.restarts.get(key).copied().unwrap_or(0)
+    }
+}
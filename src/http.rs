@@ -0,0 +1,723 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use crate::cmd_parser::{Cli, Commands};
+use crate::dashboard::{self, TOKEN_COOKIE};
+use crate::hub::Output;
+use crate::stream::{Filter, Stream};
+
+/// how often a comment is sent to a idle stream, so a closed connection is noticed
+pub const KEEP_ALIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// executes a command the same way as one from any other client, or returns `None` if bothub is exiting
+pub type Execute = Arc<dyn Fn(Cli) -> Option<Output> + Send + Sync>;
+
+/// a bearer token the HTTP API accepts, one of `http_tokens` in bots.toml
+///
+/// requests with it are made for `caller`, which is checked against `role` like `--caller`,
+/// or for no caller if it's `None`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpToken {
+    pub token: String,
+    pub caller: Option<String>,
+}
+
+impl HttpToken {
+    /// parses a item of `http_tokens`, either the token or a table of `token` and `caller`
+    pub fn from_toml(value: &toml::Value) -> Result<HttpToken, String> {
+        let error = "http_tokens should only contain non-empty strings, or tables of a non-empty token and caller!";
+        let non_empty = |value: Option<&toml::Value>| match value {
+            Some(toml::Value::String(value)) if !value.is_empty() => Ok(value.to_string()),
+            _ => Err(error.to_string()),
+        };
+        match value {
+            toml::Value::Table(table) => {
+                if let Some(key) = table.keys().find(|key| *key != "token" && *key != "caller") {
+                    return Err(format!("http_tokens.{} isn't a key of a token!", key));
+                }
+                Ok(HttpToken {
+                    token: non_empty(table.get("token"))?,
+                    caller: Some(non_empty(table.get("caller"))?),
+                })
+            }
+            value => Ok(HttpToken {
+                token: non_empty(Some(value))?,
+                caller: None,
+            }),
+        }
+    }
+}
+
+/// binds the address the HTTP API listens on
+pub fn bind(address: SocketAddr) -> Result<TcpListener, String> {
+    TcpListener::bind(address).map_err(|err| format!("Failed binding {}, {}", address, err))
+}
+
+/// serves the HTTP API on a separate thread, handling every request on a thread of its own
+///
+/// every request must carry `Authorization: Bearer` (or the cookie of the dashboard) with one of `tokens`,
+/// and is made for the caller of the token, while `GET /stream` streams the items of `stream`
+pub fn serve(
+    listener: TcpListener,
+    tokens: Vec<HttpToken>,
+    execute: Execute,
+    stream: Stream,
+) -> Result<(), String> {
+    let server = tiny_http::Server::from_listener(listener, None)
+        .map_err(|err| format!("Failed serving the HTTP API, {}", err))?;
+    let tokens = Arc::new(tokens);
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let tokens = tokens.clone();
+            let execute = execute.clone();
+            let stream = stream.clone();
+            std::thread::spawn(move || handle(request, &tokens, &execute, &stream));
+        }
+    });
+    Ok(())
+}
+
+/// a response before it's written
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl Response {
+    fn json(status: u16, body: &Value) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+            headers: Vec::new(),
+        }
+    }
+
+    fn error(status: u16, error: &str) -> Response {
+        Response::json(status, &json!({ "error": error }))
+    }
+
+    fn html(status: u16, body: String) -> Response {
+        Response {
+            status,
+            content_type: "text/html; charset=utf-8",
+            body,
+            headers: Vec::new(),
+        }
+    }
+
+    /// a `303 See Other` to `location`, which is got after a form is posted
+    fn redirect(location: &str) -> Response {
+        Response {
+            headers: vec![("Location", location.to_string())],
+            ..Response::html(303, String::new())
+        }
+    }
+
+    fn with_header(mut self, field: &'static str, value: String) -> Response {
+        self.headers.push((field, value));
+        self
+    }
+}
+
+fn handle(
+    mut request: tiny_http::Request,
+    tokens: &[HttpToken],
+    execute: &Execute,
+    stream: &Stream,
+) {
+    let (segments, query) = match parse_url(request.url()) {
+        Ok(url) => url,
+        Err(response) => return respond(request, response),
+    };
+    // `None` if it's unauthorized, otherwise the caller of the token it carries
+    let caller = authorized(&request, tokens);
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let response = match (segments.as_slice(), caller) {
+        (["dashboard", segments @ ..], caller) => serve_dashboard(
+            &mut request,
+            segments,
+            &query,
+            caller,
+            tokens,
+            execute,
+            stream,
+        ),
+        (_, None) => Response::error(401, "unauthorized"),
+        (["stream"], Some(caller)) => match request.method() {
+            tiny_http::Method::Get => match scope(&query.filter, caller.as_deref(), execute) {
+                Ok(filter) => return stream_items(request, &query, &filter, stream),
+                Err(response) => response,
+            },
+            _ => Response::error(405, "method not allowed"),
+        },
+        (segments, Some(caller)) => route(
+            request.method(),
+            segments,
+            &query,
+            caller.as_deref(),
+            execute,
+        ),
+    };
+    respond(request, response);
+}
+
+fn respond(request: tiny_http::Request, response: Response) {
+    let mut written = tiny_http::Response::from_string(response.body)
+        .with_status_code(response.status)
+        .with_header(header("Content-Type", response.content_type));
+    if response.status == 401 {
+        written.add_header(header("WWW-Authenticate", "Bearer"));
+    }
+    for (field, value) in response.headers {
+        written.add_header(header(field, &value));
+    }
+    if let Err(err) = request.respond(written) {
+        eprintln!("Failed responding to a HTTP request, {}", err);
+    }
+}
+
+fn header(field: &str, value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(field, value).unwrap()
+}
+
+/// the command line a request runs `command` with, for `caller`, with `--yes` if `yes`
+fn cli(command: Commands, caller: Option<&str>, yes: bool) -> Cli {
+    Cli {
+        command,
+        json: true,
+        caller: caller.map(str::to_string),
+        yes,
+    }
+}
+
+/// the caller of the token in `tokens` which `request` carries as a bearer token, or in the cookie of the dashboard,
+/// or `None` if it carries none of them
+fn authorized(request: &tiny_http::Request, tokens: &[HttpToken]) -> Option<Option<String>> {
+    let bearer = request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("Authorization"))
+        .filter_map(|header| header.value.as_str().strip_prefix("Bearer "))
+        .map(str::to_string);
+    let cookies = request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("Cookie"))
+        .flat_map(|header| header.value.as_str().split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .filter(|(name, _)| *name == TOKEN_COOKIE)
+        .map(|(_, token)| {
+            percent_encoding::percent_decode_str(token)
+                .decode_utf8_lossy()
+                .into_owned()
+        });
+    bearer
+        .chain(cookies)
+        .fold(None, |found, token| found.or(known(&token, tokens)))
+}
+
+/// the caller of `token` if it's one of `tokens`, compared with every one of them so the time doesn't tell which
+fn known(token: &str, tokens: &[HttpToken]) -> Option<Option<String>> {
+    tokens.iter().fold(None, |found, expected| {
+        let matches = equal(token, &expected.token);
+        found.or(matches.then(|| expected.caller.clone()))
+    })
+}
+
+/// compares two tokens in a time which doesn't tell how much of them matches
+fn equal(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// the query parameters of a request, `shard`, `tail` and `yes` for commands, `after`, `bots` and `types` for the stream,
+/// and `done` and `confirm` for the dashboard
+#[derive(Default)]
+struct Query {
+    shard: Option<u32>,
+    tail: Option<usize>,
+    /// whether the command runs right away, rather than waiting to be confirmed
+    yes: bool,
+    after: Option<u64>,
+    filter: Filter,
+    /// what the dashboard did before redirecting to itself
+    done: Option<String>,
+    /// the token of the action of the dashboard waiting to be confirmed
+    confirm: Option<String>,
+}
+
+/// the decoded path segments and the query parameters of the url of a request
+fn parse_url(url: &str) -> Result<(Vec<String>, Query), Response> {
+    // the url of a request is only the path and the query, which are parsed relative to any base
+    let url = url::Url::parse("http://localhost")
+        .and_then(|base| base.join(url))
+        .map_err(|_| Response::error(400, "invalid url"))?;
+    let mut query = Query::default();
+    for (key, value) in url.query_pairs() {
+        // `bots` and `types` are comma-separated lists
+        let mut list = value.split(',').filter(|item| !item.is_empty());
+        let parsed = match key.as_ref() {
+            "shard" => value.parse().map(|shard| query.shard = Some(shard)).is_ok(),
+            "tail" => value.parse().map(|tail| query.tail = Some(tail)).is_ok(),
+            "after" => value.parse().map(|after| query.after = Some(after)).is_ok(),
+            "yes" => value.parse().map(|yes| query.yes = yes).is_ok(),
+            "done" => {
+                query.done = Some(value.to_string());
+                true
+            }
+            "confirm" => {
+                query.confirm = Some(value.to_string());
+                true
+            }
+            "bots" => {
+                query.filter.bots.extend(list.map(str::to_string));
+                true
+            }
+            "types" => list
+                .try_for_each(|kind| {
+                    query.filter.kinds.insert(kind.parse()?);
+                    Ok::<_, String>(())
+                })
+                .is_ok(),
+            _ => true,
+        };
+        if !parsed {
+            return Err(Response::error(400, &format!("invalid {}", key)));
+        }
+    }
+    let segments = url
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            percent_encoding::percent_decode_str(segment)
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .collect();
+    Ok((segments, query))
+}
+
+/// maps a request onto a command and executes it
+fn route(
+    method: &tiny_http::Method,
+    segments: &[&str],
+    query: &Query,
+    caller: Option<&str>,
+    execute: &Execute,
+) -> Response {
+    let shard = query.shard;
+    // whether the route is to be posted to, rather than got
+    let (post, command) = match segments {
+        [] | ["bots"] => (false, Commands::ListStatus),
+        ["bots", bot_name] => (
+            false,
+            Commands::Status {
+                bot_name: bot_name.to_string(),
+                shard,
+            },
+        ),
+        ["bots", bot_name, "logs"] => (
+            false,
+            Commands::Logs {
+                bot_name: bot_name.to_string(),
+                shard,
+            },
+        ),
+        ["bots", bot_name, "history"] => (
+            false,
+            Commands::History {
+                bot_name: bot_name.to_string(),
+            },
+        ),
+        ["bots", bot_name, action] => match bot_command(action, bot_name, shard) {
+            Some(command) => (true, command),
+            None => return Response::error(404, "not found"),
+        },
+        ["tasks"] => (false, Commands::ListTasks),
+        ["tasks", task_id] => (
+            false,
+            Commands::TaskStatus {
+                task_id: task_id.to_string(),
+            },
+        ),
+        ["tasks", task_id, "terminate"] => (
+            true,
+            Commands::Terminate {
+                task_id: task_id.to_string(),
+            },
+        ),
+        ["tasks", task_id, "finish"] => (
+            true,
+            Commands::Finish {
+                task_id: task_id.to_string(),
+            },
+        ),
+        ["top"] => (false, Commands::Top { bot_name: None }),
+        ["confirm", token] => (
+            true,
+            Commands::Confirm {
+                token: token.to_string(),
+            },
+        ),
+        _ => return Response::error(404, "not found"),
+    };
+    let allowed = match post {
+        true => tiny_http::Method::Post,
+        false => tiny_http::Method::Get,
+    };
+    if *method != allowed {
+        return Response::error(405, "method not allowed");
+    }
+
+    let output = match execute(cli(command, caller, query.yes)) {
+        Some(output) => output,
+        None => return Response::error(503, "bothub is exiting"),
+    };
+    let mut body = output.json;
+    match body.get("result").and_then(Value::as_str) {
+        Some("denied") => return Response::json(403, &body),
+        // the command is waiting to be confirmed with `POST /confirm/{token}`
+        Some("confirm") => return Response::json(202, &body),
+        // `confirm` with a token nothing is waiting with
+        Some("none") => return Response::json(404, &body),
+        _ => {}
+    }
+    if body.get("state") == Some(&json!("none")) {
+        return Response::json(404, &body);
+    }
+    if let Some(tail) = query.tail {
+        tail_logs(&mut body, tail);
+    }
+    Response::json(200, &body)
+}
+
+/// serves the dashboard under `/dashboard`, which asks for a token to log in with rather than answering `401` with json,
+/// and redirects back to itself after a button is pressed
+fn serve_dashboard(
+    request: &mut tiny_http::Request,
+    segments: &[&str],
+    query: &Query,
+    caller: Option<Option<String>>,
+    tokens: &[HttpToken],
+    execute: &Execute,
+    stream: &Stream,
+) -> Response {
+    let post = *request.method() == tiny_http::Method::Post;
+    match segments {
+        ["login"] if post => match form_field(request, "token") {
+            Some(token) if known(&token, tokens).is_some() => {
+                let token = percent_encoding::utf8_percent_encode(
+                    &token,
+                    percent_encoding::NON_ALPHANUMERIC,
+                );
+                Response::redirect("/dashboard").with_header(
+                    "Set-Cookie",
+                    format!(
+                        "{}={}; Path=/; HttpOnly; SameSite=Strict",
+                        TOKEN_COOKIE, token
+                    ),
+                )
+            }
+            _ => Response::html(401, dashboard::login(Some("invalid token"))),
+        },
+        ["logout"] if post => Response::redirect("/dashboard").with_header(
+            "Set-Cookie",
+            format!(
+                "{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict",
+                TOKEN_COOKIE
+            ),
+        ),
+        ["login"] | ["logout"] => Response::error(405, "method not allowed"),
+        _ if caller.is_none() => Response::html(401, dashboard::login(None)),
+        _ => serve_authorized(
+            request,
+            segments,
+            query,
+            caller.flatten().as_deref(),
+            execute,
+            stream,
+        ),
+    }
+}
+
+/// serves the dashboard for `caller`, the caller of the token it's logged in with
+fn serve_authorized(
+    request: &tiny_http::Request,
+    segments: &[&str],
+    query: &Query,
+    caller: Option<&str>,
+    execute: &Execute,
+    stream: &Stream,
+) -> Response {
+    let post = *request.method() == tiny_http::Method::Post;
+    match segments {
+        [] if !post => {
+            // taken first, so anything happening while the page is rendered reloads it
+            let rendered = stream.next_seq() - 1;
+            match (
+                execute(cli(Commands::ListStatus, caller, false)),
+                execute(cli(Commands::ListTasks, caller, false)),
+            ) {
+                (Some(denied), _) | (_, Some(denied))
+                    if denied.json.get("result") == Some(&json!("denied")) =>
+                {
+                    Response::error(403, denied.json["error"].as_str().unwrap_or_default())
+                }
+                (Some(instances), Some(tasks)) => Response::html(
+                    200,
+                    dashboard::page(
+                        &instances.json,
+                        &tasks.json,
+                        rendered,
+                        query.done.as_deref(),
+                        query.confirm.as_deref(),
+                    ),
+                ),
+                _ => Response::error(503, "bothub is exiting"),
+            }
+        }
+        ["bots", bot_name, action] if post && dashboard::ACTIONS.contains(action) => {
+            let command = bot_command(action, bot_name, query.shard).unwrap();
+            act(
+                execute,
+                command,
+                caller,
+                &format!("{} {}", action, bot_name),
+            )
+        }
+        ["confirm", token] if post => act(
+            execute,
+            Commands::Confirm {
+                token: token.to_string(),
+            },
+            caller,
+            "confirm",
+        ),
+        [] => Response::error(405, "method not allowed"),
+        ["bots", _, action] if dashboard::ACTIONS.contains(action) => {
+            Response::error(405, "method not allowed")
+        }
+        ["confirm", _] => Response::error(405, "method not allowed"),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+/// runs `command` of `caller` for a button of the dashboard described by `action`,
+/// and redirects back to the dashboard with the first line of its output, and its token if it has to be confirmed
+fn act(execute: &Execute, command: Commands, caller: Option<&str>, action: &str) -> Response {
+    let output = match execute(cli(command, caller, false)) {
+        Some(output) => output,
+        None => return Response::error(503, "bothub is exiting"),
+    };
+    let done = format!(
+        "{}: {}",
+        action,
+        output.text.lines().next().unwrap_or_default()
+    );
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    query.append_pair("done", &done);
+    if output.json.get("result") == Some(&json!("confirm")) {
+        query.append_pair("confirm", output.json["token"].as_str().unwrap_or_default());
+    }
+    Response::redirect(&format!("/dashboard?{}", query.finish()))
+}
+
+/// the value of `field` in the url-encoded form posted with `request`
+fn form_field(request: &mut tiny_http::Request, field: &str) -> Option<String> {
+    let mut form = String::new();
+    // a form of a few fields, anything longer is cut off rather than read into memory
+    request
+        .as_reader()
+        .take(4096)
+        .read_to_string(&mut form)
+        .ok()?;
+    url::form_urlencoded::parse(form.as_bytes())
+        .find(|(key, _)| key == field)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// the command a POST to `/bots/{name}/{action}` runs
+fn bot_command(action: &str, bot_name: &str, shard: Option<u32>) -> Option<Commands> {
+    let bot_name = bot_name.to_string();
+    Some(match action {
+        "start" => Commands::Start { bot_name, shard },
+        "restart" => Commands::Restart { bot_name, shard },
+        "kill" => Commands::Kill { bot_name, shard },
+        "conclude" => Commands::Conclude { bot_name, shard },
+        "build" => Commands::Build { bot_name },
+        "pull" => Commands::Pull { bot_name },
+        "clean" => Commands::Clean { bot_name },
+        _ => return None,
+    })
+}
+
+/// keeps only the last `tail` lines of `stdout` and `stderr` of every instance in `body`
+fn tail_logs(body: &mut Value, tail: usize) {
+    let instances = match body {
+        Value::Array(instances) => instances,
+        _ => return,
+    };
+    for instance in instances {
+        for stream in ["stdout", "stderr"] {
+            if let Some(Value::Array(lines)) = instance.get_mut(stream) {
+                let skipped = lines.len().saturating_sub(tail);
+                lines.drain(..skipped);
+            }
+        }
+    }
+}
+
+/// checks the stream `caller` asks for with `filter` is allowed, which is `subscribe` and `logs` of its bots,
+/// returning `filter` with only the bots the roles of `caller` allow
+fn scope(filter: &Filter, caller: Option<&str>, execute: &Execute) -> Result<Filter, Response> {
+    let mut bots: Vec<String> = filter.bots.iter().cloned().collect();
+    bots.sort();
+    let command = Commands::Subscribe {
+        events: Vec::new(),
+        bots,
+    };
+    let output = match execute(cli(command, caller, false)) {
+        Some(output) => output,
+        None => return Err(Response::error(503, "bothub is exiting")),
+    };
+    match output.json.get("result").and_then(Value::as_str) {
+        Some("denied") => Err(Response::json(403, &output.json)),
+        _ => Ok(Filter {
+            kinds: filter.kinds.clone(),
+            bots: output.json["bots"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect(),
+        }),
+    }
+}
+
+/// streams every item of `stream` matching `filter` as server-sent events, until the connection is closed
+///
+/// the stream resumes after the sequence number in `Last-Event-ID` or `after` of `query` if either is presented,
+/// otherwise only items from now on are streamed
+fn stream_items(request: tiny_http::Request, query: &Query, filter: &Filter, stream: &Stream) {
+    let last_event_id = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Last-Event-ID"))
+        .and_then(|header| header.value.as_str().trim().parse().ok());
+    let mut after = last_event_id
+        .or(query.after)
+        .unwrap_or(u64::MAX)
+        .min(stream.next_seq() - 1);
+
+    let mut writer = request.into_writer();
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    if writer
+        .write_all(head.as_bytes())
+        .and_then(|_| writer.flush())
+        .is_err()
+    {
+        return;
+    }
+    let mut written_at = std::time::Instant::now();
+    loop {
+        let read = stream.read(after, KEEP_ALIVE_INTERVAL);
+        let mut events = String::new();
+        if read.lost > 0 {
+            after += read.lost;
+            events.push_str(&format!(
+                "event: lost\ndata: {}\n\n",
+                json!({ "lost": read.lost })
+            ));
+        }
+        for item in read.items {
+            after = item.seq;
+            if filter.matches(&item) {
+                events.push_str(&format!(
+                    "id: {}\nevent: {}\ndata: {}\n\n",
+                    item.seq,
+                    item.kind,
+                    serde_json::to_string(&item).unwrap()
+                ));
+            }
+        }
+        if events.is_empty() {
+            if written_at.elapsed() < KEEP_ALIVE_INTERVAL {
+                continue;
+            }
+            events.push_str(": keep-alive\n\n");
+        }
+        written_at = std::time::Instant::now();
+        if writer
+            .write_all(events.as_bytes())
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(value: &str) -> Result<HttpToken, String> {
+        let value: toml::Value = toml::from_str(&format!("token = {}", value)).unwrap();
+        HttpToken::from_toml(&value["token"])
+    }
+
+    #[test]
+    fn parses_tokens_with_and_without_a_caller() {
+        assert_eq!(
+            token("\"secret\""),
+            Ok(HttpToken {
+                token: "secret".to_string(),
+                caller: None,
+            })
+        );
+        assert_eq!(
+            token("{ token = \"secret\", caller = \"3\" }"),
+            Ok(HttpToken {
+                token: "secret".to_string(),
+                caller: Some("3".to_string()),
+            })
+        );
+        for invalid in ["\"\"", "1", "{ token = \"secret\" }", "{ caller = \"3\" }"] {
+            assert!(token(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            token("{ token = \"secret\", caller = \"3\", role = \"admin\" }"),
+            Err("http_tokens.role isn't a key of a token!".to_string())
+        );
+    }
+
+    #[test]
+    fn takes_the_caller_from_the_token() {
+        let tokens = [
+            HttpToken {
+                token: "viewer-token".to_string(),
+                caller: None,
+            },
+            HttpToken {
+                token: "admin-token".to_string(),
+                caller: Some("3".to_string()),
+            },
+        ];
+        assert_eq!(known("viewer-token", &tokens), Some(None));
+        assert_eq!(known("admin-token", &tokens), Some(Some("3".to_string())));
+        assert_eq!(known("admin-toke", &tokens), None);
+        assert_eq!(known("", &tokens), None);
+    }
+}
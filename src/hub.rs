@@ -0,0 +1,2207 @@
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdin, ChildStdout};
+
+use serde_json::{json, Value};
+
+use crate::bot_parser::{Settings, WatchdogAction};
+use crate::events::{Event, EventKind};
+use crate::history::{EndReason, History};
+use crate::instance::{
+    self, exit_description, format_uptime, watch_pid, Exit, ExitNotifier, Finishing, Instance,
+    InstanceHandoff,
+};
+use crate::limits::{Limits, Priority};
+use crate::redact::{Redactor, SECRETS_VAR};
+use crate::resources::{self, Monitor};
+use crate::state::{DetachedInstance, State as SavedState};
+use crate::status::{State, Status};
+use crate::stream::{Stream, Tap};
+use crate::{
+    bot_parser, bot_writer, cmd_parser, instance_name, BotInstances, BotOverrides, Bots,
+    InstanceKey, Task, TaskType, Tasks,
+};
+
+/// the output of a command, both in text and in json
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub text: String,
+    pub json: Value,
+}
+
+impl Output {
+    pub fn new(text: impl Into<String>, json: Value) -> Output {
+        Output {
+            text: text.into(),
+            json,
+        }
+    }
+
+    /// `none`, for anything which doesn't exist
+    pub fn none() -> Output {
+        Output::of_status(&Status::none())
+    }
+
+    /// `some` *Status*, or `none` if the status is `none`
+    pub fn of_status(status: &Status) -> Output {
+        match status.state {
+            State::None => Output::new("none\n", status.json()),
+            _ => Output::new(format!("some {}\n", status.text()), status.json()),
+        }
+    }
+
+    /// a output with a single word result such as `some saved`, which is `{"result": "saved"}` in json
+    pub fn result(text: &str) -> Output {
+        let result = text.trim_end().trim_start_matches("some ");
+        Output::new(text, json!({ "result": result }))
+    }
+
+    /// `err` *Description* prefixed with `prefix`, which is `{"result": "err", "error": Description}` in json
+    pub fn error(prefix: &str, error: &str) -> Output {
+        Output::new(
+            format!("{} {}\n", prefix, error),
+            json!({ "result": "err", "error": error }),
+        )
+    }
+
+    /// a empty list, which is a json array
+    pub fn list() -> Output {
+        Output::new(String::new(), json!([]))
+    }
+
+    /// adds a item to a list
+    pub fn push(&mut self, text: String, json: Value) {
+        self.text.push_str(&text);
+        if let Value::Array(items) = &mut self.json {
+            items.push(json);
+        }
+    }
+}
+
+/// how the hub answers a command
+#[derive(Debug)]
+pub enum Reply {
+    /// the output of the command
+    Output(Output),
+    /// `wait` on a running task, which should be answered with `Hub::finish_wait` once the task exits,
+    /// or with `some started waiting timeout` once `timeout` passes
+    Wait {
+        task_id: String,
+        timeout: Option<std::time::Duration>,
+    },
+    /// `control-restart`, which should be done with `Hub::restart_control_bot`, and is never answered
+    ControlRestart,
+    Exit,
+}
+
+/// the name tasks of `self-update` are listed under, in place of a bot name
+pub const SELF_TASK_NAME: &str = "dcbothub";
+
+/// how often the exits of instances and tasks which can't be watched with a pidfd are checked
+pub const EXIT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// how far `self-update` has got
+#[derive(Debug)]
+enum SelfUpdate {
+    Idle,
+    /// `git pull` of `self_repo_path` is running as the task
+    Pulling(String),
+    /// `cargo build --release` of `self_repo_path` is running as the task
+    Building(String),
+    /// the new executable is built, and switched to once no task is running
+    Ready,
+}
+
+/// everything in the hub handed to the executable `self-update` switches to, except finished tasks
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Handoff {
+    instances: Vec<(InstanceKey, Result<InstanceHandoff, String>)>,
+    overrides: BotOverrides,
+    history: History,
+    task_serial_counter: u32,
+    /// the sequence number of the next item of the stream, missing from handoffs of executables before it
+    #[serde(default)]
+    stream_seq: u64,
+}
+
+impl Handoff {
+    /// every file descriptor the instances are handed with, which must be kept open across `exec`
+    pub fn fds(&self) -> Vec<std::os::fd::RawFd> {
+        self.instances
+            .iter()
+            .filter_map(|(_, instance)| instance.as_ref().ok())
+            .flat_map(InstanceHandoff::fds)
+            .collect()
+    }
+}
+
+/// every bot, instance and task handled by bothub
+pub struct Hub {
+    bots: Bots,
+    control_bot: Option<String>,
+    bot_instances: BotInstances,
+    tasks: Tasks,
+    overrides: BotOverrides,
+    history: History,
+    redactor: Redactor,
+    settings: Settings,
+    monitor: Monitor,
+    notify_exit: ExitNotifier,
+    task_serial_counter: u32,
+    /// events not taken by `take_events` yet
+    events: Vec<Event>,
+    /// every event and line of output, for clients of the HTTP API
+    stream: Stream,
+    /// the detached instances last written to the state file
+    saved_state: SavedState,
+    self_update: SelfUpdate,
+}
+
+impl Hub {
+    /// `notify_exit` is called with the pid of every instance or task as soon as it exits
+    pub fn new(bots: Bots, settings: Settings, notify_exit: ExitNotifier) -> Hub {
+        Hub {
+            redactor: Redactor::from_bots(&bots),
+            control_bot: settings.control_bot.clone(),
+            history: History::new(settings.history_limit, settings.history_stderr_lines),
+            bots,
+            bot_instances: HashMap::new(),
+            tasks: HashMap::new(),
+            overrides: HashMap::new(),
+            settings,
+            monitor: Monitor::new(),
+            notify_exit,
+            task_serial_counter: 0,
+            events: Vec::new(),
+            stream: Stream::new(1),
+            saved_state: SavedState::default(),
+            self_update: SelfUpdate::Idle,
+        }
+    }
+
+    /// resumes the hub handed over by the bothub before `self-update`, returning a notification
+    /// for every instance of a bot no longer in bots.toml, which is killed unless it's detached
+    ///
+    /// `resume` *BotName* (`killed`|`left`) *Description*
+    ///
+    /// # Safety
+    ///
+    /// the file descriptors in `handoff` must be open and owned by nothing else
+    pub unsafe fn resume(
+        bots: Bots,
+        settings: Settings,
+        notify_exit: ExitNotifier,
+        handoff: Handoff,
+    ) -> (Hub, Vec<String>) {
+        let mut hub = Hub::new(bots, settings, notify_exit);
+        hub.saved_state = SavedState::load(&hub.settings.state_file).unwrap_or_default();
+        hub.overrides = handoff.overrides;
+        hub.history = handoff.history;
+        // the retention of the bothub before `self-update` might differ from the one in bots.toml now
+        hub.history.set_limits(
+            hub.settings.history_limit,
+            hub.settings.history_stderr_lines,
+        );
+        hub.task_serial_counter = handoff.task_serial_counter;
+        hub.stream = Stream::new(handoff.stream_seq);
+
+        let mut notifications = Vec::new();
+        for (key, instance) in handoff.instances {
+            let known = hub
+                .bots
+                .get(&key.0)
+                .is_some_and(|bot| bot.shards().contains(&key.1));
+            let instance = instance.map(|instance| {
+                let (stdout, stderr) = log_paths(&hub.settings.bot_log_dir, &key);
+                let tap = Tap::instance(&hub.stream, &key);
+                Instance::resume(instance, (&stdout, &stderr), &hub.redactor, &tap)
+            });
+            match instance {
+                _ if known => {
+                    hub.bot_instances.insert(key, instance);
+                }
+                Ok(instance) if instance.is_detached() => notifications.push(format!(
+                    "resume {} left pid {} isn't a instance of any bot in bots.toml anymore\n",
+                    instance_name(&key),
+                    instance.pid()
+                )),
+                Ok(mut instance) => {
+                    if instance.try_wait().unwrap().is_none() {
+                        instance.kill().expect("Failed to kill running child");
+                    }
+                    instance.wait().unwrap();
+                    notifications.push(format!(
+                        "resume {} killed pid {} isn't a instance of any bot in bots.toml anymore\n",
+                        instance_name(&key),
+                        instance.pid()
+                    ));
+                }
+                Err(_) => {}
+            }
+        }
+        hub.watch_instances();
+        hub.save_state();
+        (hub, notifications)
+    }
+
+    /// adopts the detached instances the previous bothub recorded in the state file, which `start` then skips,
+    /// returning a notification for each of them
+    ///
+    /// `adopt` *BotName* (`pid` *PID*)|(`failed` *FailureDescription*)
+    pub fn adopt(&mut self) -> Vec<String> {
+        let state = match SavedState::load(&self.settings.state_file) {
+            Ok(state) => state,
+            Err(err) => return vec![format!("adopt failed {}\n", err)],
+        };
+        let mut notifications = Vec::new();
+        for detached in &state.instances {
+            let key = (detached.bot.clone(), detached.shard);
+            let instance = match self.bots.get(&detached.bot) {
+                Some(bot) if bot.shards().contains(&detached.shard) => {
+                    let (stdout, stderr) = log_paths(&self.settings.bot_log_dir, &key);
+                    Instance::adopt(
+                        detached.pid,
+                        detached.start_time,
+                        detached.started_at(),
+                        (&stdout, &stderr),
+                        &self.redactor,
+                        &Tap::instance(&self.stream, &key),
+                        &detached.start_reason,
+                        detached.commit.clone(),
+                    )
+                }
+                _ => Err(format!(
+                    "pid {} isn't a instance of any bot in bots.toml, so it's left alone",
+                    detached.pid
+                )),
+            };
+            match instance {
+                Ok(instance) => {
+                    notifications.push(format!(
+                        "adopt {} pid {}\n",
+                        instance_name(&key),
+                        detached.pid
+                    ));
+                    self.bot_instances.insert(key, Ok(instance));
+                }
+                Err(err) => {
+                    notifications.push(format!("adopt {} failed {}\n", instance_name(&key), err))
+                }
+            }
+        }
+        self.saved_state = state;
+        notifications
+    }
+
+    /// starts every bot (and every shard of them), returning stdout and stdin of `control_bot` if it's configured
+    ///
+    /// fails with the description of how `control_bot` failed to start
+    pub fn start(&mut self) -> Result<Option<(ChildStdout, ChildStdin)>, String> {
+        let mut control_io = None;
+        for (name, bot) in &self.bots {
+            for shard in bot.shards() {
+                let key = (name.clone(), shard);
+                // adopted from the previous bothub
+                if self.bot_instances.contains_key(&key) {
+                    continue;
+                }
+                let instance = if Some(name) == self.control_bot.as_ref() {
+                    spawn_bot(bot, shard, None, &self.settings.bot_log_dir, &self.redactor).map(
+                        |mut child| {
+                            control_io =
+                                Some((child.stdout.take().unwrap(), child.stdin.take().unwrap()));
+                            let tap = Tap::instance(&self.stream, &key);
+                            Instance::new(child, &self.redactor, &tap, "startup", bot.head_commit())
+                        },
+                    )
+                } else {
+                    spawn_instance(
+                        bot,
+                        shard,
+                        None,
+                        &self.settings.bot_log_dir,
+                        &self.redactor,
+                        &self.stream,
+                        "startup",
+                    )
+                };
+                self.bot_instances.insert(key, instance);
+            }
+        }
+        self.watch_instances();
+        self.save_state();
+
+        match (&self.control_bot, control_io) {
+            (None, _) => Ok(None),
+            (Some(_), Some(control_io)) => Ok(Some(control_io)),
+            (Some(control_bot), None) => Err(self.bot_instances[&(control_bot.clone(), None)]
+                .as_ref()
+                .unwrap_err()
+                .clone()),
+        }
+    }
+
+    pub fn sample_interval(&self) -> std::time::Duration {
+        self.settings.sample_interval
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        self.redactor.redact(text)
+    }
+
+    pub fn redact_output(&self, output: &Output) -> Output {
+        Output::new(
+            self.redactor.redact(&output.text),
+            self.redactor.redact_json(&output.json),
+        )
+    }
+
+    /// checks `command` against the roles in bots.toml for `caller`, returning the reply to it if it's denied
+    ///
+    /// `local` tells whether the command is from a local client, see `Acl::local_role`
+    ///
+    /// commands on a task are checked as commands about the bot of the task, while `hello` and `cancel`
+    /// only concern the client, and `confirm` only runs a command already checked, so they're always allowed
+    ///
+    /// a caller is only trusted from `control_bot`, which relays commands for the callers talking to it,
+    /// and from the HTTP API, which takes it from the token, so a local command naming one is denied,
+    /// since anything able to write to the terminal or the control socket could name any caller
+    pub fn authorize(
+        &self,
+        caller: Option<&str>,
+        local: bool,
+        command: &cmd_parser::Commands,
+    ) -> Result<(), Output> {
+        if local && caller.is_some() {
+            let err = "a caller is only accepted from control_bot and the HTTP API";
+            return Err(Output::new(
+                format!("denied {}\n", err),
+                json!({ "result": "denied", "error": err }),
+            ));
+        }
+        if matches!(
+            command,
+            cmd_parser::Commands::Hello { .. }
+                | cmd_parser::Commands::Cancel
+                | cmd_parser::Commands::Confirm { .. }
+        ) {
+            return Ok(());
+        }
+        let task_bot = command
+            .task_id()
+            .and_then(|task_id| self.tasks.get(task_id))
+            .map(|((bot_name, _, _), _)| bot_name.as_str());
+        // `restart-all` is about every bot it restarts, so every one of them has to be allowed
+        let keys = match command {
+            cmd_parser::Commands::RestartAll => restarted_by_all(&self.bots, &self.control_bot),
+            _ => Vec::new(),
+        };
+        let mut bots: Vec<Option<&str>> =
+            keys.iter().map(|(name, _)| Some(name.as_str())).collect();
+        bots.dedup();
+        if bots.is_empty() {
+            bots.push(command.bot_name().or(task_bot));
+        }
+        bots.into_iter()
+            .try_for_each(|bot| self.settings.acl.check(caller, local, command.name(), bot))
+            .map_err(|err| {
+                Output::new(
+                    format!("denied {}\n", err),
+                    json!({ "result": "denied", "error": err }),
+                )
+            })
+    }
+
+    /// narrows the bots a subscription of `caller` asks for (every bot if it's empty) to the bots its roles
+    /// allow running each of `commands` on, returning the reply to it if no bot is left, see `Acl::scope`
+    pub fn scope(
+        &self,
+        caller: Option<&str>,
+        local: bool,
+        commands: &[&str],
+        bots: &[String],
+    ) -> Result<Vec<String>, Output> {
+        self.settings
+            .acl
+            .scope(caller, local, commands, bots)
+            .map_err(|err| {
+                Output::new(
+                    format!("denied {}\n", err),
+                    json!({ "result": "denied", "error": err }),
+                )
+            })
+    }
+
+    /// checks whether `caller` may skip confirming `command` with `--yes`, which requires admin rights
+    pub fn authorize_yes(
+        &self,
+        caller: Option<&str>,
+        local: bool,
+        command: &cmd_parser::Commands,
+    ) -> Result<(), Output> {
+        if self.settings.acl.is_admin(caller, local) {
+            return Ok(());
+        }
+        let caller = match caller {
+            Some(caller) => format!("caller {}", caller),
+            None => "a command without a caller".to_string(),
+        };
+        let err = format!(
+            "{} isn't allowed to run {} with --yes",
+            caller,
+            command.name()
+        );
+        Err(Output::new(
+            format!("denied {}\n", err),
+            json!({ "result": "denied", "error": err }),
+        ))
+    }
+
+    /// whether `command` only runs once it's confirmed, which is every command hard to undo:
+    /// `exit`, `clean-all`, `kill`, `remove-bot`, and `restart` or `restart-all` of more instances than `confirm_threshold`
+    pub fn needs_confirmation(&self, command: &cmd_parser::Commands) -> bool {
+        match command {
+            cmd_parser::Commands::Exit
+            | cmd_parser::Commands::CleanAll { .. }
+            | cmd_parser::Commands::Kill { .. }
+            | cmd_parser::Commands::RemoveBot { .. } => true,
+            cmd_parser::Commands::Restart { bot_name, shard } => {
+                select_instances(&self.bots, bot_name, *shard)
+                    .is_some_and(|keys| keys.len() > self.settings.confirm_threshold)
+            }
+            cmd_parser::Commands::RestartAll => {
+                restarted_by_all(&self.bots, &self.control_bot).len()
+                    > self.settings.confirm_threshold
+            }
+            _ => false,
+        }
+    }
+
+    /// how long a command waits to be confirmed
+    pub fn confirm_timeout(&self) -> std::time::Duration {
+        self.settings.confirm_timeout
+    }
+
+    /// executes a command, the output of which isn't redacted yet
+    pub fn execute(&mut self, command: &cmd_parser::Commands) -> Reply {
+        let reply = self.execute_command(command);
+        self.watch_instances();
+        self.save_state();
+        reply
+    }
+
+    fn execute_command(&mut self, command: &cmd_parser::Commands) -> Reply {
+        let Hub {
+            bots,
+            control_bot,
+            bot_instances,
+            tasks,
+            overrides,
+            history,
+            redactor,
+            settings,
+            monitor,
+            notify_exit,
+            task_serial_counter,
+            events,
+            stream,
+            saved_state: _,
+            self_update,
+        } = self;
+
+        Reply::Output(match command {
+            cmd_parser::Commands::List => {
+                let names: Vec<&String> = bots.keys().collect();
+                Output::new(join_line(&names), json!(names))
+            }
+            cmd_parser::Commands::ListExisting => {
+                let names: Vec<String> = bot_instances.keys().map(instance_name).collect();
+                let instances: Vec<Value> = bot_instances
+                    .keys()
+                    .map(|(bot_name, shard)| json!({ "bot": bot_name, "shard": shard }))
+                    .collect();
+                Output::new(join_line(&names), json!(instances))
+            }
+            cmd_parser::Commands::ListExecuting => {
+                let task_ids: Vec<&String> = tasks.keys().collect();
+                Output::new(join_line(&task_ids), json!(task_ids))
+            }
+            cmd_parser::Commands::ListStatus => {
+                let mut output = Output::list();
+                for (key, instance) in bot_instances.iter_mut() {
+                    let status = instance_status(key, instance, history, bots[&key.0].limits());
+                    output.push(
+                        format!("{} {}\n", instance_name(key), status.text),
+                        status.json,
+                    );
+                }
+                output
+            }
+            cmd_parser::Commands::ListTasks => {
+                let mut output = Output::list();
+                for (task_id, task) in tasks.iter_mut() {
+                    let status = task_status(task_id, task);
+                    output.push(format!("{}\n", status.text), status.json);
+                }
+                output
+            }
+            cmd_parser::Commands::Status { bot_name, shard } => {
+                match select_instances(bots, bot_name, *shard) {
+                    Some(keys) => {
+                        for_each_instance(&keys, *shard, |key| match bot_instances.get_mut(key) {
+                            Some(instance) => {
+                                let status =
+                                    instance_status(key, instance, history, bots[&key.0].limits());
+                                Output::new(format!("some {}\n", status.text), status.json)
+                            }
+                            None => Output::none(),
+                        })
+                    }
+                    None => Output::none(),
+                }
+            }
+            cmd_parser::Commands::Logs { bot_name, shard } => {
+                match select_instances(bots, bot_name, *shard) {
+                    Some(keys) => {
+                        for_each_instance(&keys, *shard, |key| match bot_instances.get_mut(key) {
+                            Some(instance) => {
+                                let status = Status::of_instance(key, instance, None);
+                                match instance {
+                                    Ok(instance) => {
+                                        let (stdout, stderr) = instance.output();
+                                        output_with_logs(&status, &stdout, &stderr)
+                                    }
+                                    Err(_) => Output::of_status(&status),
+                                }
+                            }
+                            None => Output::none(),
+                        })
+                    }
+                    None => Output::none(),
+                }
+            }
+            cmd_parser::Commands::Top { bot_name } => match bot_name {
+                Some(bot_name) if !bots.contains_key(bot_name) => Output::none(),
+                _ => {
+                    let mut instances: Vec<_> = bot_instances
+                        .iter_mut()
+                        .filter(|((name, _), _)| {
+                            bot_name.as_ref().is_none_or(|bot_name| bot_name == name)
+                        })
+                        .collect();
+                    instances.sort_by_key(|(key, _)| *key);
+                    let mut output = Output::list();
+                    output.text.push_str("some\n");
+                    for (key, instance) in instances {
+                        if let Ok(instance) = instance {
+                            if instance.try_wait().unwrap().is_some() {
+                                continue;
+                            }
+                            if let Some((usage, cpu_percent)) = monitor.sample(key, instance.pid())
+                            {
+                                output.push(
+                                    format!(
+                                        "{} {}\n",
+                                        instance_name(key),
+                                        resources::describe(&usage, cpu_percent)
+                                    ),
+                                    json!({
+                                        "bot": key.0,
+                                        "shard": key.1,
+                                        "pid": instance.pid(),
+                                        "rss_mb": usage.rss_mb(),
+                                        "cpu_seconds": usage.cpu_time.as_secs_f64(),
+                                        "cpu_percent": cpu_percent,
+                                        "threads": usage.threads,
+                                        "open_files": usage.open_files,
+                                        "processes": usage.processes,
+                                    }),
+                                );
+                            }
+                        }
+                    }
+                    output
+                }
+            },
+            cmd_parser::Commands::History { bot_name } => match history.get(bot_name) {
+                Some(records) => {
+                    let mut output = Output::list();
+                    output.text.push_str(&format!("some {}\n", records.len()));
+                    for record in records {
+                        output.push(record.describe(bot_name), record.json(bot_name));
+                    }
+                    output
+                }
+                None if bots.contains_key(bot_name) => Output::new("some 0\n", json!([])),
+                None => Output::none(),
+            },
+            cmd_parser::Commands::TaskStatus { task_id } => match tasks.get_mut(task_id) {
+                Some(task) => {
+                    let status = task_status(task_id, task);
+                    Output::new(format!("some {}\n", status.text), status.json)
+                }
+                None => Output::none(),
+            },
+            cmd_parser::Commands::Clean { bot_name }
+            | cmd_parser::Commands::CleanAll { bot_name }
+            | cmd_parser::Commands::Build { bot_name }
+            | cmd_parser::Commands::Pull { bot_name } => match bots.get(bot_name) {
+                Some(bot) if bot.has_repo() => {
+                    let (task_type, task) = match command {
+                        cmd_parser::Commands::Clean { .. } => (TaskType::Clean, bot.clean()),
+                        cmd_parser::Commands::CleanAll { .. } => (TaskType::Clean, bot.clean_all()),
+                        cmd_parser::Commands::Build { .. } => (
+                            TaskType::Build,
+                            bot.build().map(|mut task| {
+                                if let Some(build_jobs) = settings.build_jobs {
+                                    task.env("CARGO_BUILD_JOBS", build_jobs.to_string());
+                                }
+                                task
+                            }),
+                        ),
+                        _ => (TaskType::Pull, bot.pull()),
+                    };
+                    let task_id = add_task(
+                        tasks,
+                        task_serial_counter,
+                        bot_name,
+                        task_type,
+                        task,
+                        &settings.task_priority,
+                        notify_exit,
+                        redactor,
+                        stream,
+                    );
+                    Output::new(format!("some {}\n", task_id), json!({ "task_id": task_id }))
+                }
+                Some(_) => Output::new(
+                    "some no_repo\n",
+                    json!({ "task_id": null, "error": "no_repo" }),
+                ),
+                None => Output::none(),
+            },
+            cmd_parser::Commands::Start { bot_name, shard } => {
+                match select_instances(bots, bot_name, *shard) {
+                    Some(keys) => for_each_instance(&keys, *shard, |key| {
+                        let result = if bot_instances.contains_key(key) {
+                            "exists"
+                        } else {
+                            bot_instances.insert(
+                                key.clone(),
+                                spawn_instance(
+                                    &bots[bot_name],
+                                    key.1,
+                                    overrides.get(bot_name),
+                                    &settings.bot_log_dir,
+                                    redactor,
+                                    stream,
+                                    "start",
+                                ),
+                            );
+                            "spawned"
+                        };
+                        let status =
+                            Status::of_instance(key, bot_instances.get_mut(key).unwrap(), None);
+                        let text = match result {
+                            "exists" => "exists\n",
+                            _ => "none some spawned\n",
+                        };
+                        Output::new(text, status.json_with(json!({ "result": result })))
+                    }),
+                    None => Output::new("none none\n", Status::none().json()),
+                }
+            }
+            cmd_parser::Commands::Restart { bot_name, shard } => {
+                match select_instances(bots, bot_name, *shard) {
+                    Some(keys) => for_each_instance(&keys, *shard, |key| {
+                        restart_instance(
+                            bots,
+                            bot_instances,
+                            overrides,
+                            history,
+                            redactor,
+                            stream,
+                            &settings.bot_log_dir,
+                            key,
+                            EndReason::Restart,
+                        )
+                    }),
+                    None => Output::none(),
+                }
+            }
+            cmd_parser::Commands::RestartAll => {
+                let mut output = Output::list();
+                for key in restarted_by_all(bots, control_bot) {
+                    let Output { text, mut json } = restart_instance(
+                        bots,
+                        bot_instances,
+                        overrides,
+                        history,
+                        redactor,
+                        stream,
+                        &settings.bot_log_dir,
+                        &key,
+                        EndReason::Restart,
+                    );
+                    if let Value::Object(json) = &mut json {
+                        json.insert("bot".to_string(), json!(key.0));
+                        json.insert("shard".to_string(), json!(key.1));
+                    }
+                    output.push(format!("{} {}", instance_name(&key), text), json);
+                }
+                output
+            }
+            cmd_parser::Commands::Msg {
+                bot_name,
+                shard,
+                message,
+            } => match select_instances(bots, bot_name, *shard) {
+                Some(keys) => {
+                    for_each_instance(&keys, *shard, |key| match bot_instances.get_mut(key) {
+                        Some(instance) => {
+                            let status = Status::of_instance(key, instance, None);
+                            let written = match instance {
+                                Ok(instance) if status.state == State::Running => {
+                                    Some(match instance.stdin() {
+                                        // e.g. the bot closed its stdin
+                                        Some(stdin) => {
+                                            let mut bot_out = BufWriter::new(stdin);
+                                            writeln!(bot_out, "{}", message.join(" "))
+                                                .and_then(|_| bot_out.flush())
+                                                .map_err(|err| err.to_string())
+                                        }
+                                        None => Err("stdin isn't piped".to_string()),
+                                    })
+                                }
+                                _ => None,
+                            };
+                            let text = match (status.state, &written) {
+                                (State::Exited, _) => "started exited\n",
+                                (_, Some(Ok(()))) => "started running written\n",
+                                (_, Some(Err(_))) => "started running failed\n",
+                                _ => "failed\n",
+                            };
+                            let mut extra = json!({ "written": matches!(written, Some(Ok(()))) });
+                            if let Some(Err(err)) = written {
+                                extra["error"] = json!(err);
+                            }
+                            Output::new(text, status.json_with(extra))
+                        }
+                        None => Output::none(),
+                    })
+                }
+                None => Output::none(),
+            },
+            cmd_parser::Commands::Inspect { bot_name } => match bots.get(bot_name) {
+                Some(bot) => {
+                    let inspected = format!(
+                        "{}{}",
+                        bot.inspect(),
+                        overrides
+                            .get(bot_name)
+                            .map_or_else(String::new, |overrides| overrides.inspect(bot))
+                    );
+                    let lines: Vec<&str> = inspected.lines().collect();
+                    Output::new(
+                        format!("some\n{}", inspected),
+                        json!({ "bot": bot_name, "inspect": lines }),
+                    )
+                }
+                None => Output::none(),
+            },
+            cmd_parser::Commands::SetEnv {
+                bot_name,
+                vars,
+                save,
+            } => match bots.get(bot_name) {
+                Some(bot) => match parse_env_vars(vars) {
+                    Ok(vars) => {
+                        for (key, value) in &vars {
+                            if bot.is_secret_env(key) {
+                                redactor.add_secret(value);
+                            }
+                        }
+                        overrides
+                            .entry(bot_name.clone())
+                            .or_default()
+                            .env
+                            .extend(vars.clone());
+                        save_output(save.then(|| {
+                            bot_writer::update_bot(bot_name, |table| {
+                                for (key, value) in &vars {
+                                    let table_name = if bot.is_secret_env(key) {
+                                        "secret_env"
+                                    } else {
+                                        "env"
+                                    };
+                                    bot_writer::set_table_value(table, table_name, key, value);
+                                }
+                            })
+                        }))
+                    }
+                    Err(err) => save_output(Some(Err(err))),
+                },
+                None => Output::none(),
+            },
+            cmd_parser::Commands::SetArgs {
+                save,
+                bot_name,
+                args,
+            } => match bots.get(bot_name) {
+                Some(_) => {
+                    overrides.entry(bot_name.clone()).or_default().run_args = Some(args.clone());
+                    save_output(save.then(|| {
+                        bot_writer::update_bot(bot_name, |table| {
+                            bot_writer::set_array(table, "run_args", args)
+                        })
+                    }))
+                }
+                None => Output::none(),
+            },
+            cmd_parser::Commands::SetToken {
+                bot_name,
+                token_file,
+                save,
+            } => match bots.get(bot_name) {
+                Some(_) => match read_token_file(token_file).and_then(|token| {
+                    bot_parser::verify_unique_token(
+                        bot_name,
+                        &token,
+                        tokens_in_use(bots, overrides),
+                    )
+                    .map(|_| token)
+                }) {
+                    Ok(token) => {
+                        redactor.add_secret(&token);
+                        overrides.entry(bot_name.clone()).or_default().token = Some(token.clone());
+                        save_output(save.then(|| {
+                            bot_writer::update_bot(bot_name, |table| {
+                                table["token"] = toml_edit::value(token.as_str());
+                            })
+                        }))
+                    }
+                    Err(err) => save_output(Some(Err(err))),
+                },
+                None => Output::none(),
+            },
+            cmd_parser::Commands::ClearOverrides { bot_name } => match bots.get(bot_name) {
+                Some(_) => match overrides.remove(bot_name) {
+                    Some(removed) if !removed.is_empty() => Output::result("some cleared\n"),
+                    _ => Output::result("some empty\n"),
+                },
+                None => Output::none(),
+            },
+            cmd_parser::Commands::AddBot {
+                bot_name,
+                repo,
+                executable,
+                token_file,
+                build_args,
+                run_args,
+                shards,
+            } => {
+                if bots.contains_key(bot_name) {
+                    Output::result("exists\n")
+                } else {
+                    match new_bot_table(
+                        bot_name, repo, executable, token_file, build_args, run_args, shards,
+                    )
+                    .and_then(|table| {
+                        let bot = bot_parser::Bot::from_toml_table(&table)?;
+                        bot.verify()?;
+                        bot.verify_token(tokens_in_use(bots, overrides))?;
+                        bot_writer::add_bot(&table)?;
+                        Ok(bot)
+                    }) {
+                        Ok(bot) => {
+                            for secret in bot.secrets() {
+                                redactor.add_secret(secret);
+                            }
+                            bots.insert(bot_name.clone(), bot);
+                            Output::result("added\n")
+                        }
+                        Err(err) => Output::error("err", &err),
+                    }
+                }
+            }
+            cmd_parser::Commands::RemoveBot { bot_name } => {
+                if !bots.contains_key(bot_name) {
+                    Output::none()
+                } else if control_bot.as_ref() == Some(bot_name) {
+                    Output::result("control\n")
+                } else if bot_instances.keys().any(|(name, _)| name == bot_name) {
+                    Output::result("exists\n")
+                } else {
+                    match bot_writer::remove_bot(bot_name) {
+                        Ok(_) => {
+                            bots.remove(bot_name);
+                            overrides.remove(bot_name);
+                            Output::result("removed\n")
+                        }
+                        Err(err) => Output::error("err", &err),
+                    }
+                }
+            }
+            cmd_parser::Commands::Verify { bot_name } => {
+                let mut verify = |bot_name: &String| {
+                    let bot = &bots[bot_name];
+                    let verified = bot.verify().and_then(|_| {
+                        bot.verify_token(bot_parser::configured_tokens(bots.values()))
+                    });
+                    if let Err(err) = &verified {
+                        events.push(Event::new(
+                            EventKind::VerifyFailed,
+                            bot_name,
+                            format!("{} {}", bot_name, err),
+                        ));
+                    }
+                    let json = json!({
+                        "bot": bot_name,
+                        "ok": verified.is_ok(),
+                        "error": verified.as_ref().err(),
+                    });
+                    let text = match verified {
+                        Ok(_) => "ok\n".to_string(),
+                        Err(err) => format!("err {}\n", err),
+                    };
+                    Output::new(text, json)
+                };
+                match bot_name {
+                    Some(bot_name) if bots.contains_key(bot_name) => {
+                        let verified = verify(bot_name);
+                        Output::new(format!("some {}", verified.text), verified.json)
+                    }
+                    Some(_) => Output::none(),
+                    None => {
+                        let mut output = Output::list();
+                        for bot_name in bots.keys() {
+                            let verified = verify(bot_name);
+                            output.push(format!("{} {}", bot_name, verified.text), verified.json);
+                        }
+                        output
+                    }
+                }
+            }
+            cmd_parser::Commands::Kill { bot_name, shard } => {
+                match select_instances(bots, bot_name, *shard) {
+                    Some(keys) => {
+                        for_each_instance(&keys, *shard, |key| match bot_instances.get_mut(key) {
+                            Some(instance) => {
+                                let status = Status::of_instance(key, instance, None);
+                                let result = match (status.state, instance) {
+                                    (State::Running, Ok(instance)) => {
+                                        instance.kill().unwrap();
+                                        "killed"
+                                    }
+                                    (State::Exited, _) => "exited",
+                                    _ => "failed",
+                                };
+                                stop_output(&status, result)
+                            }
+                            None => Output::none(),
+                        })
+                    }
+                    None => Output::none(),
+                }
+            }
+            cmd_parser::Commands::ControlRestart => {
+                return Reply::ControlRestart;
+            }
+            cmd_parser::Commands::Terminate { task_id } => match tasks.get_mut(task_id) {
+                Some(((bot_name, _, _), task)) => {
+                    let status = Status::of_task(task_id, bot_name, task);
+                    let result = match (status.state, task) {
+                        (State::Running, Ok(task)) => {
+                            task.child.kill().unwrap();
+                            "killed"
+                        }
+                        (State::Exited, _) => "exited",
+                        _ => "failed",
+                    };
+                    let output = stop_output(&status, result);
+                    Output::new(format!("some {}", output.text), output.json)
+                }
+                None => Output::none(),
+            },
+            cmd_parser::Commands::Conclude { bot_name, shard } => {
+                match select_instances(bots, bot_name, *shard) {
+                    Some(keys) => {
+                        for_each_instance(&keys, *shard, |key| match bot_instances.get_mut(key) {
+                            Some(instance) => {
+                                let status = Status::of_instance(key, instance, None);
+                                let output = match (status.state, instance) {
+                                    (State::Running, _) => return Output::of_status(&status),
+                                    (_, Ok(instance)) => {
+                                        let (stdout, stderr) = instance.finish_output();
+                                        output_with_logs(&status, &stdout, &stderr)
+                                    }
+                                    (_, Err(_)) => Output::of_status(&status),
+                                };
+                                let instance = bot_instances.remove(key).unwrap();
+                                history.record(key.clone(), instance, EndReason::Conclude);
+                                output
+                            }
+                            None => Output::none(),
+                        })
+                    }
+                    None => Output::none(),
+                }
+            }
+            cmd_parser::Commands::Wait { task_id, timeout } => match tasks.get_mut(task_id) {
+                Some(((bot_name, _, _), task)) => {
+                    let status = Status::of_task(task_id, bot_name, task);
+                    match status.state {
+                        State::Running => {
+                            return Reply::Wait {
+                                task_id: task_id.clone(),
+                                timeout: timeout.map(std::time::Duration::from_secs),
+                            };
+                        }
+                        State::Exited => Output::new("some started exited\n", status.json()),
+                        _ => Output::new("some failed\n", status.json()),
+                    }
+                }
+                None => Output::none(),
+            },
+            cmd_parser::Commands::Finish { task_id } => match tasks.get_mut(task_id) {
+                Some(((bot_name, _, _), task)) => {
+                    let status = Status::of_task(task_id, bot_name, task);
+                    match (status.state, task) {
+                        (State::Exited, Ok(task)) => {
+                            let stdout = task.stdout.finish();
+                            let stderr = task.stderr.finish();
+                            tasks.remove(task_id);
+                            output_with_logs(&status, &stdout, &stderr)
+                        }
+                        (State::Running, _) => Output::of_status(&status),
+                        _ => Output::new("some failed\n", status.json()),
+                    }
+                }
+                None => Output::none(),
+            },
+            // subscriptions are per client, so they're handled before reaching the hub
+            cmd_parser::Commands::Cancel
+            | cmd_parser::Commands::Confirm { .. }
+            | cmd_parser::Commands::Subscribe { .. }
+            | cmd_parser::Commands::Unsubscribe { .. }
+            | cmd_parser::Commands::Hello { .. } => Output::none(),
+            cmd_parser::Commands::SelfUpdate => {
+                let busy = match self_update {
+                    SelfUpdate::Idle => false,
+                    // the task might have been removed by `finish` before its exit is handled
+                    SelfUpdate::Pulling(task_id) | SelfUpdate::Building(task_id) => {
+                        tasks.contains_key(task_id)
+                    }
+                    SelfUpdate::Ready => true,
+                };
+                match &settings.self_repo_path {
+                    None => Output::new(
+                        "some no_repo\n",
+                        json!({ "task_id": null, "error": "no_repo" }),
+                    ),
+                    Some(_) if busy => {
+                        Output::new("some busy\n", json!({ "task_id": null, "error": "busy" }))
+                    }
+                    Some(repo_path) => {
+                        let mut pull = std::process::Command::new("git");
+                        pull.current_dir(repo_path).arg("pull");
+                        let task_id = add_task(
+                            tasks,
+                            task_serial_counter,
+                            SELF_TASK_NAME,
+                            TaskType::Pull,
+                            Ok(pull),
+                            &settings.task_priority,
+                            notify_exit,
+                            redactor,
+                            stream,
+                        );
+                        *self_update = match &tasks[&task_id].1 {
+                            Ok(_) => SelfUpdate::Pulling(task_id.clone()),
+                            Err(_) => SelfUpdate::Idle,
+                        };
+                        Output::new(format!("some {}\n", task_id), json!({ "task_id": task_id }))
+                    }
+                }
+            }
+            cmd_parser::Commands::Exit => {
+                return Reply::Exit;
+            }
+        })
+    }
+
+    /// the executable built by `self-update`, once it's built and no task is running anymore,
+    /// which should then be switched to with `handoff`
+    pub fn take_self_update(&mut self) -> Option<PathBuf> {
+        if !matches!(self.self_update, SelfUpdate::Ready) {
+            return None;
+        }
+        // a running task would be left unreaped by the new executable
+        let running = self.tasks.values_mut().any(|(_, task)| {
+            task.as_mut()
+                .is_ok_and(|task| task.child.try_wait().unwrap().is_none())
+        });
+        if running {
+            return None;
+        }
+        self.self_update = SelfUpdate::Idle;
+        let repo_path = self.settings.self_repo_path.as_ref()?;
+        Some(repo_path.join("target/release").join(SELF_TASK_NAME))
+    }
+
+    /// describes the hub for the executable `self-update` switches to, which resumes it with `resume`
+    ///
+    /// the hub itself is left as is, so it's still usable if switching fails
+    pub fn handoff(&mut self) -> Result<Handoff, String> {
+        let mut instances = Vec::new();
+        for (key, instance) in self.bot_instances.iter_mut() {
+            let instance = match instance {
+                Ok(instance) => Ok(instance
+                    .handoff()
+                    .map_err(|err| format!("Failed checking {}, {}", instance_name(key), err))?),
+                Err(err) => Err(err.clone()),
+            };
+            instances.push((key.clone(), instance));
+        }
+        Ok(Handoff {
+            instances,
+            overrides: self.overrides.clone(),
+            history: self.history.settled(),
+            task_serial_counter: self.task_serial_counter,
+            stream_seq: self.stream.next_seq(),
+        })
+    }
+
+    /// records how switching to the executable built by `self-update` ended, returning the notification of it
+    ///
+    /// `self-update` (`resumed`|(`rolled-back` *Reason*))
+    pub fn self_update_switched(&mut self, rolled_back: Option<&str>) -> String {
+        let details = match rolled_back {
+            Some(reason) => format!("rolled-back {}", reason),
+            None => "resumed".to_string(),
+        };
+        self.events.push(Event::new(
+            EventKind::SelfUpdate,
+            SELF_TASK_NAME,
+            details.clone(),
+        ));
+        format!("self-update {}\n", details)
+    }
+
+    /// the answer to a deferred `wait` once the task exits, `None` if the task is still running
+    pub fn finish_wait(&mut self, task_id: &str) -> Option<Output> {
+        match self.tasks.get_mut(task_id) {
+            Some(((bot_name, _, _), task)) => {
+                let status = Status::of_task(task_id, bot_name, task);
+                (status.state != State::Running).then(|| {
+                    Output::new(
+                        "some started waiting exited\n",
+                        status.json_with(json!({ "waiting": "exited" })),
+                    )
+                })
+            }
+            // the task is removed by `finish` from another client while waiting
+            None => Some(Output::none()),
+        }
+    }
+
+    /// kills `control_bot` and starts a new instance of it, returning stdout and stdin of the new one
+    pub fn restart_control_bot(&mut self) -> Result<(ChildStdout, ChildStdin), String> {
+        let control_bot = self
+            .control_bot
+            .clone()
+            .ok_or_else(|| "control_bot isn't configured".to_string())?;
+        let control_key = (control_bot.clone(), None);
+        let old_control_bot = self.bot_instances.get_mut(&control_key).unwrap();
+        let old_control_bot = old_control_bot.as_mut().unwrap();
+        old_control_bot.kill().map_err(|err| err.to_string())?;
+
+        let bot = &self.bots[&control_bot];
+        let mut new_control_bot = spawn_bot(
+            bot,
+            None,
+            self.overrides.get(&control_bot),
+            &self.settings.bot_log_dir,
+            &self.redactor,
+        )?;
+        let control_io = (
+            new_control_bot.stdout.take().unwrap(),
+            new_control_bot.stdin.take().unwrap(),
+        );
+        old_control_bot.wait().unwrap();
+        let old_control_bot = self.bot_instances.remove(&control_key).unwrap();
+        self.history.record(
+            control_key.clone(),
+            old_control_bot,
+            EndReason::ControlRestart,
+        );
+        let tap = Tap::instance(&self.stream, &control_key);
+        self.bot_instances.insert(
+            control_key,
+            Ok(Instance::new(
+                new_control_bot,
+                &self.redactor,
+                &tap,
+                "control-restart",
+                bot.head_commit(),
+            )),
+        );
+        self.watch_instances();
+        Ok(control_io)
+    }
+
+    /// reaps the instance or task that exited as `pid`, returning the notification of its exit if it's a instance
+    ///
+    /// `exit` *BotName* `exited` *ExitCode* [`signal` *SignalName*] [`limit` *LimitName*]
+    pub fn handle_exit(&mut self, pid: u32) -> Option<String> {
+        if let Some((task_id, ((bot_name, task_type, _), child))) = self
+            .tasks
+            .iter_mut()
+            .filter_map(|(task_id, (task, spawned))| {
+                Some((task_id, (task, &mut spawned.as_mut().ok()?.child)))
+            })
+            .find(|(_, (_, child))| child.id() == pid)
+        {
+            let status = child.try_wait().unwrap()?;
+            let details = format!(
+                "{} {} {} {}",
+                task_id,
+                bot_name,
+                task_type,
+                exit_description(&status)
+            );
+            self.events
+                .push(Event::new(EventKind::TaskFinished, bot_name, details));
+            let task_id = task_id.clone();
+            return self.advance_self_update(&task_id, &status);
+        }
+
+        // the instance might have been removed (e.g. by `restart`) before the exit is handled
+        let (key, instance) = self
+            .bot_instances
+            .iter_mut()
+            .filter_map(|(key, instance)| Some((key, instance.as_mut().ok()?)))
+            .find(|(_, instance)| instance.pid() == pid)?;
+        let exit = instance.try_wait().unwrap()?;
+        let description = describe_exit(&exit, instance, self.bots[&key.0].limits());
+        self.history.record_exit(key, instance);
+        self.events.push(Event::new(
+            EventKind::BotExited,
+            &key.0,
+            format!("{} {}", instance_name(key), description),
+        ));
+        let notification = format!("exit {} {}\n", instance_name(key), description);
+        self.save_state();
+        Some(notification)
+    }
+
+    /// moves `self-update` on once its task `task_id` exited with `status`, returning the notification of it
+    ///
+    /// `self-update` (`building` *TaskID*)|`ready`|(`failed` *TaskID* (*ExitDescription*|*FailureDescription*))
+    fn advance_self_update(
+        &mut self,
+        task_id: &str,
+        status: &std::process::ExitStatus,
+    ) -> Option<String> {
+        let details = match std::mem::replace(&mut self.self_update, SelfUpdate::Idle) {
+            SelfUpdate::Pulling(pull) if pull == task_id && status.success() => {
+                let mut build = std::process::Command::new("cargo");
+                build
+                    .current_dir(self.settings.self_repo_path.as_ref()?)
+                    .args(["build", "--release"]);
+                if let Some(build_jobs) = self.settings.build_jobs {
+                    build.env("CARGO_BUILD_JOBS", build_jobs.to_string());
+                }
+                let build_id = add_task(
+                    &mut self.tasks,
+                    &mut self.task_serial_counter,
+                    SELF_TASK_NAME,
+                    TaskType::Build,
+                    Ok(build),
+                    &self.settings.task_priority,
+                    &self.notify_exit,
+                    &self.redactor,
+                    &self.stream,
+                );
+                match &self.tasks[&build_id].1 {
+                    Ok(_) => {
+                        self.self_update = SelfUpdate::Building(build_id.clone());
+                        format!("building {}", build_id)
+                    }
+                    Err(err) => format!("failed {} {}", build_id, err),
+                }
+            }
+            SelfUpdate::Building(build) if build == task_id && status.success() => {
+                self.self_update = SelfUpdate::Ready;
+                "ready".to_string()
+            }
+            SelfUpdate::Pulling(running) | SelfUpdate::Building(running) if running == task_id => {
+                format!("failed {} {}", task_id, exit_description(status))
+            }
+            self_update => {
+                self.self_update = self_update;
+                return None;
+            }
+        };
+        self.events.push(Event::new(
+            EventKind::SelfUpdate,
+            SELF_TASK_NAME,
+            details.clone(),
+        ));
+        Some(format!("self-update {}\n", details))
+    }
+
+    /// samples every running instance of a bot with thresholds, and acts on the ones newly exceeding them,
+    /// returning a notification for each of them
+    ///
+    /// `watchdog` *BotName* *Excess* (`notify`|(`restart` *RestartOutput*))
+    pub fn check_thresholds(&mut self) -> Vec<String> {
+        let mut notifications = Vec::new();
+        for (key, excess) in
+            check_thresholds(&self.bots, &mut self.bot_instances, &mut self.monitor)
+        {
+            let action = match self.bots[&key.0].watchdog().action {
+                // the control bot can only be restarted with `control-restart`
+                WatchdogAction::Restart if Some(&key.0) != self.control_bot.as_ref() => {
+                    let restarted = restart_instance(
+                        &self.bots,
+                        &mut self.bot_instances,
+                        &self.overrides,
+                        &mut self.history,
+                        &self.redactor,
+                        &self.stream,
+                        &self.settings.bot_log_dir,
+                        &key,
+                        EndReason::Watchdog,
+                    );
+                    self.monitor.set_exceeding(&key, false);
+                    format!("restart {}", restarted.text)
+                }
+                _ => "notify\n".to_string(),
+            };
+            let details = format!("{} {} {}", instance_name(&key), excess, action);
+            self.events
+                .push(Event::new(EventKind::Watchdog, &key.0, details.clone()));
+            notifications.push(format!("watchdog {}", details));
+        }
+        self.watch_instances();
+        self.save_state();
+        notifications
+    }
+
+    /// watches the exit of every instance that isn't watched yet, see `Instance::watch_exit`,
+    /// which are the ones started since the last call
+    fn watch_instances(&mut self) {
+        for (key, instance) in self.bot_instances.iter_mut() {
+            if let Ok(instance) = instance {
+                if instance.watch_exit(self.notify_exit.clone()) {
+                    let mut details = format!("{} {}", instance_name(key), instance.start_reason());
+                    if let Some(commit) = instance.commit() {
+                        details.push_str(&format!(" commit {}", commit));
+                    }
+                    self.events
+                        .push(Event::new(EventKind::BotStarted, &key.0, details));
+                }
+            }
+        }
+    }
+
+    /// whether the exit of any instance or task has to be checked by `poll_exits`, since it can't be watched
+    pub fn polls_exits(&self) -> bool {
+        self.bot_instances
+            .values()
+            .any(|instance| instance.as_ref().is_ok_and(Instance::is_polled))
+            || self
+                .tasks
+                .values()
+                .any(|(_, task)| task.as_ref().is_ok_and(|task| task.polled))
+    }
+
+    /// the pids of every instance and task which can't be watched and exited since the last call,
+    /// the exits of which are handled by `handle_exit` like the ones of watched ones
+    pub fn poll_exits(&mut self) -> Vec<u32> {
+        let instances = self
+            .bot_instances
+            .values_mut()
+            .filter_map(|instance| instance.as_mut().ok())
+            .filter_map(|instance| instance.poll_exit().then(|| instance.pid()));
+        let tasks = self
+            .tasks
+            .values_mut()
+            .filter_map(|(_, task)| task.as_mut().ok())
+            .filter_map(|task| {
+                if !task.polled || !matches!(task.child.try_wait(), Ok(Some(_))) {
+                    return None;
+                }
+                task.polled = false;
+                Some(task.child.id())
+            });
+        instances.chain(tasks).collect()
+    }
+
+    /// the end of stderr of every instance recorded in `history` since the last call, which isn't done yet,
+    /// along with the pid of the instance, see `record_finished`
+    ///
+    /// it's waited on off the command loop, since it might take up to `FINISH_TIMEOUT`
+    pub fn take_finishing(&mut self) -> Vec<(u32, Finishing)> {
+        self.history.take_finishing()
+    }
+
+    /// adds the record of the instance `pid` to `history` once the end of its stderr is done
+    pub fn record_finished(&mut self, pid: u32) {
+        self.history.finish(pid);
+    }
+
+    /// takes every event happened since the last call
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// every event and line of output, which events taken by `take_events` should be published to
+    pub fn stream(&self) -> &Stream {
+        &self.stream
+    }
+
+    /// records every running detached instance in the state file, if they changed since the last time
+    fn save_state(&mut self) {
+        let mut instances: Vec<DetachedInstance> = self
+            .bot_instances
+            .iter_mut()
+            .filter_map(|(key, instance)| {
+                let instance = instance
+                    .as_mut()
+                    .ok()
+                    .filter(|instance| instance.is_detached())?;
+                if instance.try_wait().unwrap().is_some() {
+                    return None;
+                }
+                Some(DetachedInstance {
+                    bot: key.0.clone(),
+                    shard: key.1,
+                    pid: instance.pid(),
+                    start_time: resources::start_time(instance.pid())?,
+                    started_at: instance
+                        .started_at()
+                        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    start_reason: instance.start_reason().to_string(),
+                    commit: instance.commit().map(str::to_string),
+                })
+            })
+            .collect();
+        instances.sort_by(|a, b| (&a.bot, a.shard).cmp(&(&b.bot, b.shard)));
+        let state = SavedState { instances };
+        if state == self.saved_state {
+            return;
+        }
+        match state.save(&self.settings.state_file) {
+            Ok(()) => self.saved_state = state,
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+
+    /// kills every running instance, except detached ones which are left for the next bothub to adopt
+    pub fn shutdown(mut self) {
+        self.save_state();
+        for (_, instance) in self.bot_instances {
+            if let Ok(mut instance) = instance {
+                if !instance.is_detached()
+                    && instance
+                        .try_wait()
+                        .expect("Failed to check child status")
+                        .is_none()
+                {
+                    instance.kill().expect("Failed to kill running child");
+                }
+            }
+        }
+    }
+}
+
+/// the log files a detached instance writes its stdout and stderr to
+fn log_paths(log_dir: &Path, key: &InstanceKey) -> (PathBuf, PathBuf) {
+    let name = instance_name(key);
+    (
+        log_dir.join(format!("{}.stdout.log", name)),
+        log_dir.join(format!("{}.stderr.log", name)),
+    )
+}
+
+/// spawns a bot, or a shard of it, with stdin, stdout and stderr piped,
+/// or with stdin closed and stdout and stderr written to (truncated) log files in `log_dir` if the bot is detached
+///
+/// the output of a detached bot reaches the log files through `spawn_relay`, so they are redacted,
+/// and they are only readable by the owner
+fn spawn_bot(
+    bot: &bot_parser::Bot,
+    shard: Option<u32>,
+    overrides: Option<&bot_parser::Overrides>,
+    log_dir: &Path,
+    redactor: &Redactor,
+) -> Result<std::process::Child, String> {
+    let mut command = bot.run(shard, overrides);
+    if bot.is_detached() {
+        let create = |path: &Path| {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+            std::fs::create_dir_all(log_dir)
+                .and_then(|_| {
+                    std::fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .mode(0o600)
+                        .open(path)
+                })
+                // `mode` only applies to a new file
+                .and_then(|file| {
+                    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+                        .map(|_| file)
+                })
+                .map_err(|err| format!("Failed creating {}, {}", path.display(), err))
+        };
+        let (stdout, stderr) = log_paths(log_dir, &(bot.name().to_string(), shard));
+        command
+            .stdin(std::process::Stdio::null())
+            .stdout(spawn_relay(create(&stdout)?, redactor)?)
+            .stderr(spawn_relay(create(&stderr)?, redactor)?)
+            // so a ^C in the terminal of bothub doesn't reach it
+            .process_group(0);
+    } else {
+        command
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+    }
+    command.spawn().map_err(|err| err.to_string())
+}
+
+/// starts `dcbothub redact-log`, which copies what is written to the returned pipe to `log`,
+/// scrubbing every secret `redactor` knows of
+///
+/// the relay detaches itself and only exits once every writer of the pipe is gone,
+/// so like the detached bot writing to it, it outlives bothub
+fn spawn_relay(log: std::fs::File, redactor: &Redactor) -> Result<std::io::PipeWriter, String> {
+    let (reader, writer) =
+        std::io::pipe().map_err(|err| format!("Failed creating a pipe, {}", err))?;
+    let exe = std::env::current_exe()
+        .map_err(|err| format!("Failed finding the executable of bothub, {}", err))?;
+    // only waits for the relay to detach
+    let status = std::process::Command::new(exe)
+        .arg("redact-log")
+        .env(
+            SECRETS_VAR,
+            serde_json::to_string(&redactor.secrets()).unwrap(),
+        )
+        .stdin(reader)
+        .stdout(log)
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map_err(|err| format!("Failed starting redact-log, {}", err))?;
+    if !status.success() {
+        return Err(format!("redact-log failed, {}", status));
+    }
+    Ok(writer)
+}
+
+/// spawns a instance of a bot, or a shard of it, see `spawn_bot`
+fn spawn_instance(
+    bot: &bot_parser::Bot,
+    shard: Option<u32>,
+    overrides: Option<&bot_parser::Overrides>,
+    log_dir: &Path,
+    redactor: &Redactor,
+    stream: &Stream,
+    start_reason: &str,
+) -> Result<Instance, String> {
+    let child = spawn_bot(bot, shard, overrides, log_dir, redactor)?;
+    let key = (bot.name().to_string(), shard);
+    let tap = Tap::instance(stream, &key);
+    if !bot.is_detached() {
+        return Ok(Instance::new(
+            child,
+            redactor,
+            &tap,
+            start_reason,
+            bot.head_commit(),
+        ));
+    }
+    let (stdout, stderr) = log_paths(log_dir, &key);
+    Ok(Instance::new_detached(
+        child,
+        (&stdout, &stderr),
+        redactor,
+        &tap,
+        start_reason,
+        bot.head_commit(),
+    ))
+}
+
+/// spawns a task and adds it to `tasks` under a new task id, which is returned
+///
+/// the task is added as failed if it can't be created or spawned
+#[allow(clippy::too_many_arguments)]
+fn add_task(
+    tasks: &mut Tasks,
+    task_serial_counter: &mut u32,
+    bot_name: &str,
+    task_type: TaskType,
+    task: Result<std::process::Command, String>,
+    priority: &Priority,
+    notify_exit: &ExitNotifier,
+    redactor: &Redactor,
+    stream: &Stream,
+) -> String {
+    let task_id = format!("{:08}", task_serial_counter);
+    let tap = Tap::task(stream, bot_name, &task_id);
+    tasks.insert(
+        task_id.clone(),
+        (
+            (bot_name.to_string(), task_type, *task_serial_counter),
+            task.and_then(|task| spawn_task(task, priority, notify_exit, redactor, &tap)),
+        ),
+    );
+    *task_serial_counter += 1;
+    task_id
+}
+
+/// spawns a task with stdin, stdout and stderr piped, at the priority configured for tasks
+///
+/// the task is watched like instances are, so `notify_exit` is called as soon as it exits,
+/// and its output is captured like theirs, so it never blocks on a full pipe
+fn spawn_task(
+    mut command: std::process::Command,
+    priority: &Priority,
+    notify_exit: &ExitNotifier,
+    redactor: &Redactor,
+    tap: &Tap,
+) -> Result<Task, String> {
+    priority.apply(&mut command);
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|err| err.to_string())?;
+    let pid = child.id();
+    let notify_exit = notify_exit.clone();
+    let polled = watch_pid(pid, move || notify_exit(pid)).is_err();
+    let stdout =
+        instance::Output::capture(child.stdout.take().unwrap(), redactor.clone(), tap.clone());
+    let stderr =
+        instance::Output::capture(child.stderr.take().unwrap(), redactor.clone(), tap.stderr());
+    Ok(Task {
+        child,
+        stdout,
+        stderr,
+        polled,
+    })
+}
+
+/// the instances a command refers to with a bot name and an optional shard id,
+/// `None` if the bot doesn't exist or doesn't run the given shard
+fn select_instances(bots: &Bots, bot_name: &str, shard: Option<u32>) -> Option<Vec<InstanceKey>> {
+    let shards = bots.get(bot_name)?.shards();
+    match shard {
+        Some(shard) => shards
+            .contains(&Some(shard))
+            .then(|| vec![(bot_name.to_string(), Some(shard))]),
+        None => Some(
+            shards
+                .into_iter()
+                .map(|shard| (bot_name.to_string(), shard))
+                .collect(),
+        ),
+    }
+}
+
+/// every instance `restart-all` restarts, which is every shard of every bot except `control_bot`, ordered by bot name
+fn restarted_by_all(bots: &Bots, control_bot: &Option<String>) -> Vec<InstanceKey> {
+    let mut bot_names: Vec<&String> = bots
+        .keys()
+        .filter(|bot_name| Some(*bot_name) != control_bot.as_ref())
+        .collect();
+    bot_names.sort();
+    bot_names
+        .into_iter()
+        .flat_map(|bot_name| select_instances(bots, bot_name, None).unwrap_or_default())
+        .collect()
+}
+
+/// restarts a instance, killing it first if it's running, and records the stopped one in `history`
+///
+/// `some` (`none`|`exited`|`killed`|`failed`) (`spawned`|`failed`)
+#[allow(clippy::too_many_arguments)]
+fn restart_instance(
+    bots: &Bots,
+    bot_instances: &mut BotInstances,
+    overrides: &BotOverrides,
+    history: &mut History,
+    redactor: &Redactor,
+    stream: &Stream,
+    log_dir: &Path,
+    key: &InstanceKey,
+    end_reason: EndReason,
+) -> Output {
+    let bot = &bots[&key.0];
+    let stopped = match bot_instances.get_mut(key) {
+        Some(Ok(instance)) => match instance.try_wait().unwrap() {
+            Some(_) => "exited",
+            None => {
+                instance.kill().unwrap();
+                instance.wait().unwrap();
+                "killed"
+            }
+        },
+        Some(Err(_)) => "failed",
+        None => "none",
+    };
+    let mut instance = spawn_instance(
+        bot,
+        key.1,
+        overrides.get(&key.0),
+        log_dir,
+        redactor,
+        stream,
+        &end_reason.to_string(),
+    );
+    let status = Status::of_instance(key, &mut instance, None);
+    let started = if instance.is_ok() {
+        "spawned"
+    } else {
+        "failed"
+    };
+    if let Some(old_instance) = bot_instances.insert(key.clone(), instance) {
+        history.record(key.clone(), old_instance, end_reason);
+    }
+    Output::new(
+        format!("some {} {}\n", stopped, started),
+        status.json_with(json!({ "stopped": stopped, "result": started })),
+    )
+}
+
+/// samples every running instance of a bot with thresholds,
+/// returning the instances that newly exceed them along with the description of the excess
+fn check_thresholds(
+    bots: &Bots,
+    bot_instances: &mut BotInstances,
+    monitor: &mut Monitor,
+) -> Vec<(InstanceKey, String)> {
+    let mut exceeding = Vec::new();
+    for (key, instance) in bot_instances.iter_mut() {
+        let watchdog = bots[&key.0].watchdog();
+        let instance = match instance {
+            Ok(instance) if watchdog.is_enabled() => instance,
+            _ => continue,
+        };
+        if instance.try_wait().unwrap().is_some() {
+            monitor.set_exceeding(key, false);
+            continue;
+        }
+        let excess = monitor
+            .sample(key, instance.pid())
+            .and_then(|(usage, _)| watchdog.check(&usage));
+        if monitor.set_exceeding(key, excess.is_some()) {
+            exceeding.push((key.clone(), excess.unwrap()));
+        }
+    }
+    exceeding.sort();
+    exceeding
+}
+
+/// joins the output of `f` on every selected instance,
+/// each prefixed with `#ShardID` if a whole sharded bot is selected
+///
+/// the json output is a array of the json output of every instance, with the bot name and the shard id added
+fn for_each_instance<F>(keys: &[InstanceKey], shard: Option<u32>, mut f: F) -> Output
+where
+    F: FnMut(&InstanceKey) -> Output,
+{
+    let mut output = Output::list();
+    for key in keys {
+        let Output { text, mut json } = f(key);
+        if let Value::Object(json) = &mut json {
+            json.insert("bot".to_string(), json!(key.0));
+            json.insert("shard".to_string(), json!(key.1));
+        }
+        match key.1 {
+            Some(shard_id) if shard.is_none() => {
+                output.push(format!("#{} {}", shard_id, text), json);
+            }
+            _ => output.push(text, json),
+        }
+    }
+    output
+}
+
+/// (`started` (`running`|(`exited` *ExitCode* [`signal` *SignalName*] [`limit` *LimitName*])) *Details*)|(`failed` *FailureDescription*)
+///
+/// *LimitName* is the key in `limits` the instance most likely died from hitting, and *Details* is `pid` *PID* `since` *StartTime* `uptime` *Uptime* `restarts` *RestartCount* `commit` *Commit*
+fn instance_status(
+    key: &InstanceKey,
+    instance: &mut Result<Instance, String>,
+    history: &History,
+    limits: &Limits,
+) -> Output {
+    let status = Status::of_instance(key, instance, Some(limits));
+    match instance {
+        Ok(instance) => {
+            let since = humantime::format_rfc3339_seconds(instance.started_at()).to_string();
+            let restarts = history.restarts(key);
+            Output::new(
+                format!(
+                    "{} pid {} since {} uptime {} restarts {} commit {}",
+                    status.text(),
+                    instance.pid(),
+                    since,
+                    format_uptime(instance.uptime()),
+                    restarts,
+                    instance.commit().unwrap_or("none")
+                ),
+                status.json_with(json!({
+                    "since": since,
+                    "uptime_seconds": instance.uptime().as_secs(),
+                    "restarts": restarts,
+                    "commit": instance.commit(),
+                })),
+            )
+        }
+        Err(_) => Output::new(status.text(), status.json()),
+    }
+}
+
+/// *TaskID*`\t`*BotName* *TaskType* *SerialNumber* *Status*
+fn task_status(
+    task_id: &str,
+    ((bot_name, task_type, serial_number), task): &mut (
+        (String, TaskType, u32),
+        Result<Task, String>,
+    ),
+) -> Output {
+    let status = Status::of_task(task_id, bot_name, task);
+    Output::new(
+        format!(
+            "{}\t{} {} {} {}",
+            task_id,
+            bot_name,
+            task_type,
+            serial_number,
+            status.text()
+        ),
+        status.json_with(json!({
+            "task_type": task_type.to_string(),
+            "serial_number": serial_number,
+        })),
+    )
+}
+
+/// (`started` (`exited`|`killed`))|`failed`, the output of stopping a instance or task with the given status
+fn stop_output(status: &Status, result: &str) -> Output {
+    let text = match result {
+        "failed" => "failed\n".to_string(),
+        result => format!("started {}\n", result),
+    };
+    Output::new(text, status.json_with(json!({ "result": result })))
+}
+
+/// `exited` (*ExitCode*|`unknown`) [`signal` *SignalName*] [`limit` *LimitName*], see `instance_status`
+fn describe_exit(exit: &Exit, instance: &Instance, limits: &Limits) -> String {
+    match exit
+        .status()
+        .and_then(|status| limits.hit_by(status, &instance.output().1))
+    {
+        Some(limit) => format!("{} limit {}", exit.description(), limit),
+        None => exit.description(),
+    }
+}
+
+/// builds a `[[bot]]` table from the arguments of `add-bot`
+fn new_bot_table(
+    bot_name: &str,
+    repo: &Option<String>,
+    executable: &Option<String>,
+    token_file: &Option<String>,
+    build_args: &[String],
+    run_args: &[String],
+    shards: &Option<u32>,
+) -> Result<toml::value::Table, String> {
+    let mut table = toml::value::Table::new();
+    table.insert("name".to_string(), bot_name.into());
+    if let Some(repo) = repo {
+        table.insert("repo_path".to_string(), repo.as_str().into());
+    }
+    if let Some(executable) = executable {
+        table.insert("executable_path".to_string(), executable.as_str().into());
+    }
+    if !build_args.is_empty() {
+        table.insert("build_args".to_string(), build_args.to_vec().into());
+    }
+    if !run_args.is_empty() {
+        table.insert("run_args".to_string(), run_args.to_vec().into());
+    }
+    if let Some(token_file) = token_file {
+        table.insert("token".to_string(), read_token_file(token_file)?.into());
+    }
+    if let Some(shards) = shards {
+        table.insert("shards".to_string(), (*shards as i64).into());
+    }
+    Ok(table)
+}
+
+/// reads the token in `token_file`, given to `add-bot` and `set-token`
+fn read_token_file(token_file: &str) -> Result<String, String> {
+    let token = std::fs::read_to_string(token_file)
+        .map_err(|err| format!("Failed to read {}, {}", token_file, err))?;
+    Ok(token.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// the tokens of every bot, both the configured one and the overridden one,
+/// since clearing the overrides brings the configured token back
+fn tokens_in_use<'a>(
+    bots: &'a crate::Bots,
+    overrides: &'a crate::BotOverrides,
+) -> Vec<(&'a str, &'a str)> {
+    bot_parser::configured_tokens(bots.values())
+        .chain(overrides.iter().filter_map(|(bot_name, overrides)| {
+            Some((bot_name.as_str(), overrides.token.as_deref()?))
+        }))
+        .collect()
+}
+
+/// parses `KEY=VALUE` pairs given to `set-env`
+fn parse_env_vars(vars: &[String]) -> Result<Vec<(String, String)>, String> {
+    vars.iter()
+        .map(|var| match var.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(format!("{} isn't in the form of KEY=VALUE", var)),
+        })
+        .collect()
+}
+
+/// (`set`|`saved`|(`err` *Description*)), depending on whether and how an override is saved to bots.toml
+fn save_output(saved: Option<Result<(), String>>) -> Output {
+    match saved {
+        None => Output::result("some set\n"),
+        Some(Ok(_)) => Output::result("some saved\n"),
+        Some(Err(err)) => Output::error("some err", &err),
+    }
+}
+
+/// the status of a stopped instance or task with its output,
+/// which is the status, the line counts of stdout and stderr, then stdout and stderr in text
+fn output_with_logs(status: &Status, stdout: &[String], stderr: &[String]) -> Output {
+    Output::new(
+        format_output(&format!("some {}", status.text()), stdout, stderr),
+        status.json_with(json!({ "stdout": stdout, "stderr": stderr })),
+    )
+}
+
+/// the exit status, the line counts of stdout and stderr, then stdout and stderr
+fn format_output(status: &str, stdout: &[String], stderr: &[String]) -> String {
+    let mut output = format!("{}\n", status);
+    output.push_str(&format!("{} {} \n", stdout.len() + 1, stderr.len() + 1));
+    for line in stdout {
+        output.push_str(line);
+        output.push('\n');
+    }
+    output.push('\n');
+    for line in stderr {
+        output.push_str(line);
+        output.push('\n');
+    }
+    output.push('\n');
+    output
+}
+
+/// joins items in a line, separated by spaces
+fn join_line<T: std::fmt::Display>(items: &[T]) -> String {
+    let mut output = String::new();
+    for item in items {
+        output.push_str(&format!("{} ", item));
+    }
+    let mut output = output.trim_end().to_string();
+    output.push('\n');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN_A: &str = "MTExMTExMTExMTExMTExMTEx.BotTok.EN3Sh4rD3dB0tsRuN0n3Pr0c3sSP3rSh4rd";
+    const TOKEN_B: &str = "MjIyMjIyMjIyMjIyMjIyMjIy.BotTok.EN3Sh4rD3dB0tsRuN0n3Pr0c3sSP3rSh4rd";
+    // another token of the bot user of `TOKEN_A`
+    const TOKEN_A2: &str = "MTExMTExMTExMTExMTExMTEx.R3s3tT.N3wT0k3nAft3rR3s3tt1ngTh3B0tUs3r";
+
+    fn bots(tokens: &[(&str, &str)]) -> crate::Bots {
+        tokens
+            .iter()
+            .map(|(name, token)| {
+                let table = [
+                    ("name", *name),
+                    ("executable_path", "/bin/true"),
+                    ("token", *token),
+                ]
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), toml::Value::from(value)))
+                .collect();
+                let bot = bot_parser::Bot::from_toml_table(&table).unwrap();
+                (name.to_string(), bot)
+            })
+            .collect()
+    }
+
+    const ROLES: &str = r#"
+        default_role = "viewer"
+        local_role = "admin"
+
+        [[bot]]
+        name = "bot_a"
+        executable_path = "/bin/true"
+
+        [[role]]
+        name = "viewer"
+        commands = ["list-status"]
+
+        [[role]]
+        name = "admin"
+        commands = ["*"]
+        callers = ["3"]
+    "#;
+
+    fn hub(config: &str) -> Hub {
+        let (bots, settings) = bot_parser::parse_config(config).unwrap();
+        Hub::new(bots, settings, std::sync::Arc::new(|_| {}))
+    }
+
+    #[test]
+    fn denies_callers_named_by_local_clients() {
+        let hub = hub(ROLES);
+        // anything able to write to the control socket could claim to be the admin
+        for command in [
+            cmd_parser::Commands::Exit,
+            cmd_parser::Commands::Confirm {
+                token: "00".to_string(),
+            },
+        ] {
+            let denied = hub.authorize(Some("3"), true, &command).unwrap_err();
+            assert_eq!(
+                denied.text,
+                "denied a caller is only accepted from control_bot and the HTTP API\n"
+            );
+        }
+        assert!(hub
+            .authorize(Some("3"), false, &cmd_parser::Commands::Exit)
+            .is_ok());
+        assert!(hub
+            .authorize(None, true, &cmd_parser::Commands::Exit)
+            .is_ok());
+        assert!(hub
+            .authorize(None, false, &cmd_parser::Commands::Exit)
+            .is_err());
+    }
+
+    #[test]
+    fn checks_restart_all_against_every_bot_it_restarts() {
+        let config = r#"
+            control_bot = "bot_c"
+            confirm_threshold = 2
+            default_role = "operator"
+
+            [[bot]]
+            name = "bot_a"
+            executable_path = "/bin/true"
+            shards = 2
+
+            [[bot]]
+            name = "bot_b"
+            executable_path = "/bin/true"
+
+            [[bot]]
+            name = "bot_c"
+            executable_path = "/bin/true"
+
+            [[role]]
+            name = "operator"
+            commands = ["restart-all"]
+            bots = ["bot_a", "bot_c"]
+
+            [[role]]
+            name = "admin"
+            commands = ["restart-all"]
+            callers = ["3"]
+        "#;
+        let raised = hub(&config.replace("confirm_threshold = 2", "confirm_threshold = 3"));
+        let hub = hub(config);
+        let restart_all = cmd_parser::Commands::RestartAll;
+        // `control_bot` isn't restarted, so neither is it counted nor checked
+        assert_eq!(
+            restarted_by_all(&hub.bots, &hub.control_bot),
+            [
+                ("bot_a".to_string(), Some(0)),
+                ("bot_a".to_string(), Some(1)),
+                ("bot_b".to_string(), None)
+            ]
+        );
+        assert!(hub.needs_confirmation(&restart_all));
+        assert!(!raised.needs_confirmation(&restart_all));
+        assert_eq!(
+            hub.authorize(None, false, &restart_all).unwrap_err().text,
+            "denied a command without a caller with role operator isn't allowed to run restart-all on bot_b\n"
+        );
+        assert!(hub.authorize(Some("3"), false, &restart_all).is_ok());
+    }
+
+    #[test]
+    fn parses_env_vars() {
+        let vars = ["A=1", "B=", "C=x=y"].map(str::to_string);
+        assert_eq!(
+            parse_env_vars(&vars).unwrap(),
+            [("A", "1"), ("B", ""), ("C", "x=y")]
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_env_vars_without_a_key() {
+        for var in ["A", "=1", ""] {
+            assert_eq!(
+                parse_env_vars(&["B=2".to_string(), var.to_string()]),
+                Err(format!("{} isn't in the form of KEY=VALUE", var))
+            );
+        }
+    }
+
+    #[test]
+    fn reads_a_token_without_the_line_break() {
+        let path = std::env::temp_dir().join(format!("dcbothub-token-{}", std::process::id()));
+        std::fs::write(&path, format!("{}\r\n", TOKEN_A)).unwrap();
+        let token = read_token_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(token.as_deref(), Ok(TOKEN_A));
+        assert!(read_token_file(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn checks_tokens_against_overridden_tokens() {
+        let bots = bots(&[("bot_a", TOKEN_A), ("bot_c", TOKEN_A2)]);
+        let mut overrides = crate::BotOverrides::new();
+        overrides.entry("bot_c".to_string()).or_default().token = Some(TOKEN_B.to_string());
+        let tokens = tokens_in_use(&bots, &overrides);
+
+        // both the configured and the overridden token of bot_c are in use
+        assert!(bot_parser::verify_unique_token("bot_b", TOKEN_B, tokens.clone()).is_err());
+        assert!(bot_parser::verify_unique_token("bot_b", TOKEN_A2, tokens.clone()).is_err());
+        // a bot may take its own tokens back
+        assert!(bot_parser::verify_unique_token("bot_c", TOKEN_B, tokens.clone()).is_ok());
+        // a token of the same bot user as another bot is in use as well
+        assert_eq!(
+            bot_parser::verify_unique_token("bot_b", TOKEN_A, [("bot_c", TOKEN_A2)]),
+            Err("bot.token belongs to the same bot user as the token of bot_c!".to_string())
+        );
+        assert!(bot_parser::verify_unique_token("bot_b", "not a token", tokens).is_err());
+    }
+}
@@ -0,0 +1,872 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::redact::Redactor;
+use crate::stream::Tap;
+
+/// how many lines of stdout/stderr are kept for every bot instance, older lines are dropped
+pub const MAX_CAPTURED_LINES: usize = 10000;
+
+/// how long to wait for the output of a exited process to be closed,
+/// which might never happen if the process left a child holding it
+pub const FINISH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// how often a followed log file is checked for new lines once its end is reached
+pub const FOLLOW_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// output of a child process, read line by line by a separate thread so the child never blocks on a full pipe
+///
+/// every line is redacted before it's stored, and published to `tap`
+#[derive(Debug)]
+pub struct Output {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    reader: Option<std::thread::JoinHandle<()>>,
+    /// tells the reader of a followed log file that nothing is written to it anymore
+    closed: Option<Arc<AtomicBool>>,
+    /// set by the reader once the stream is read to the end
+    finished: Arc<AtomicBool>,
+    /// when waiting for the stream to be closed is given up on, set once it's first waited on
+    deadline: Option<std::time::Instant>,
+    /// a duplicate of the captured stream, which stays open until the output is dropped
+    /// so it can be handed to the executable `self-update` switches to
+    stream: Option<OwnedFd>,
+}
+
+impl Output {
+    pub fn capture<R: Read + AsFd + Send + 'static>(
+        stream: R,
+        redactor: Redactor,
+        tap: Tap,
+    ) -> Output {
+        Output::capture_after(stream, Vec::new(), redactor, tap)
+    }
+
+    /// captures a stream following `lines` captured from it before, by the bothub before `self-update`
+    fn capture_after<R: Read + AsFd + Send + 'static>(
+        stream: R,
+        lines: Vec<String>,
+        redactor: Redactor,
+        tap: Tap,
+    ) -> Output {
+        let duplicate = stream.as_fd().try_clone_to_owned().ok();
+        let lines = Arc::new(Mutex::new(VecDeque::from(lines)));
+        let finished = Arc::new(AtomicBool::new(false));
+        let reader_lines = lines.clone();
+        let reader_finished = finished.clone();
+        let reader = std::thread::spawn(move || {
+            for line in BufReader::new(stream).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                let line = redactor.redact(&line);
+                tap.publish(&line);
+                push_line(&reader_lines, line);
+            }
+            reader_finished.store(true, Ordering::Release);
+        });
+        Output {
+            lines,
+            reader: Some(reader),
+            closed: None,
+            finished,
+            deadline: None,
+            stream: duplicate,
+        }
+    }
+
+    /// captures the lines of a log file a detached instance writes to, from the start of it,
+    /// waiting for more lines at the end of it until `finish` is called
+    ///
+    /// only lines written after it's opened are published to `tap`, since the rest was published by whoever wrote it
+    pub fn follow(path: &Path, redactor: Redactor, tap: Tap) -> Result<Output, String> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| format!("Failed opening {}, {}", path.display(), err))?;
+        let written = file
+            .metadata()
+            .map_err(|err| format!("Failed reading {}, {}", path.display(), err))?
+            .len();
+        let lines = Arc::new(Mutex::new(VecDeque::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        let reader_lines = lines.clone();
+        let reader_closed = closed.clone();
+        let reader_finished = finished.clone();
+        let reader = std::thread::spawn(move || {
+            let mut file = BufReader::new(file);
+            let mut line = String::new();
+            let mut read = 0;
+            let push = |line: &str, read: u64| {
+                let line = redactor.redact(line);
+                if read > written {
+                    tap.publish(&line);
+                }
+                push_line(&reader_lines, line);
+            };
+            loop {
+                // checked before reading, so everything written before `finish` is still read
+                let closed = reader_closed.load(Ordering::Acquire);
+                match file.read_line(&mut line) {
+                    Ok(0) if closed => {
+                        if !line.is_empty() {
+                            push(&line, read);
+                        }
+                        break;
+                    }
+                    Ok(0) => std::thread::sleep(FOLLOW_INTERVAL),
+                    // a line without a newline might still be being written
+                    Ok(length) if !line.ends_with('\n') && !closed => read += length as u64,
+                    Ok(length) => {
+                        read += length as u64;
+                        push(line.trim_end_matches('\n'), read);
+                        line.clear();
+                    }
+                    Err(_) => break,
+                }
+            }
+            reader_finished.store(true, Ordering::Release);
+        });
+        Ok(Output {
+            lines,
+            reader: Some(reader),
+            closed: Some(closed),
+            finished,
+            deadline: None,
+            stream: None,
+        })
+    }
+
+    /// lines captured so far
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// a handle on the end of the output, which can be waited on elsewhere, such as off the command loop
+    ///
+    /// a followed log file is read to its end, and it's given up on `FINISH_TIMEOUT` after it's first called
+    pub fn finishing(&mut self) -> Finishing {
+        if let Some(closed) = &self.closed {
+            closed.store(true, Ordering::Release);
+        }
+        let deadline = *self
+            .deadline
+            .get_or_insert_with(|| std::time::Instant::now() + FINISH_TIMEOUT);
+        Finishing {
+            lines: self.lines.clone(),
+            finished: self.finished.clone(),
+            deadline,
+        }
+    }
+
+    /// waits (at most `FINISH_TIMEOUT`) for the stream to be closed, then returns every captured line
+    pub fn finish(&mut self) -> Vec<String> {
+        let lines = self.finishing().wait();
+        if self
+            .reader
+            .as_ref()
+            .is_some_and(|reader| reader.is_finished())
+        {
+            self.reader.take().unwrap().join().unwrap();
+        }
+        lines
+    }
+}
+
+/// the end of a captured output, see `Output::finishing`
+#[derive(Debug, Clone)]
+pub struct Finishing {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    finished: Arc<AtomicBool>,
+    deadline: std::time::Instant,
+}
+
+impl Finishing {
+    /// whether the stream is read to the end, or waiting for it is given up on
+    pub fn is_done(&self) -> bool {
+        self.finished.load(Ordering::Acquire) || std::time::Instant::now() >= self.deadline
+    }
+
+    /// waits until `is_done`, then returns every captured line
+    pub fn wait(&self) -> Vec<String> {
+        while !self.is_done() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        self.lines()
+    }
+
+    /// lines captured so far
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// the captured lines of a output along with the stream they're captured from, see `Instance::handoff`
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct OutputHandoff {
+    fd: RawFd,
+    lines: Vec<String>,
+}
+
+/// adds a line to captured lines, dropping the oldest line if there are too many
+fn push_line(lines: &Mutex<VecDeque<String>>, line: String) {
+    let mut lines = lines.lock().unwrap();
+    if lines.len() >= MAX_CAPTURED_LINES {
+        lines.pop_front();
+    }
+    lines.push_back(line);
+}
+
+/// called with the pid of a watched instance as soon as it exits, see `Instance::watch_exit`
+pub type ExitNotifier = Arc<dyn Fn(u32) + Send + Sync>;
+
+/// how the exit of a instance is noticed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Watch {
+    /// not watched yet
+    None,
+    /// watched with a pidfd, which calls the `ExitNotifier` as soon as it exits
+    Pidfd,
+    /// checked with `try_wait` by `poll_exit`, since it can't be watched with a pidfd
+    Polled,
+    /// the exit is noticed already, by `poll_exit` or by the bothub before `self-update`
+    Noticed,
+}
+
+/// how a instance exited
+///
+/// only the parent of a process learns its exit status, so it's unknown for instances adopted from a previous bothub
+///
+/// it's the raw wait status, or `null` if it's unknown, in json
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(into = "Option<i32>", from = "Option<i32>")]
+pub enum Exit {
+    Status(std::process::ExitStatus),
+    Unknown,
+}
+
+impl Exit {
+    pub fn status(&self) -> Option<&std::process::ExitStatus> {
+        match self {
+            Self::Status(status) => Some(status),
+            Self::Unknown => None,
+        }
+    }
+
+    /// same as `exit_description`, or `exited unknown` if the exit status is unknown
+    pub fn description(&self) -> String {
+        match self {
+            Self::Status(status) => exit_description(status),
+            Self::Unknown => "exited unknown".to_string(),
+        }
+    }
+}
+
+impl From<Exit> for Option<i32> {
+    fn from(exit: Exit) -> Option<i32> {
+        use std::os::unix::process::ExitStatusExt;
+
+        exit.status().map(|status| status.into_raw())
+    }
+}
+
+impl From<Option<i32>> for Exit {
+    fn from(status: Option<i32>) -> Exit {
+        use std::os::unix::process::ExitStatusExt;
+
+        match status {
+            Some(status) => Exit::Status(std::process::ExitStatus::from_raw(status)),
+            None => Exit::Unknown,
+        }
+    }
+}
+
+/// the process of a instance
+#[derive(Debug)]
+enum Process {
+    Child(std::process::Child),
+    /// a detached bot started by a previous bothub, which isn't a child of this one,
+    /// told apart from a later process reusing its pid by its start time
+    Adopted {
+        pid: u32,
+        start_time: u64,
+    },
+    /// a child of the bothub before `self-update`, which this one inherits along with its stdin
+    Inherited {
+        pid: u32,
+        stdin: Option<std::process::ChildStdin>,
+        /// the exit status, once the child is reaped
+        exit: Option<Exit>,
+    },
+}
+
+/// a instance as it's handed to the executable `self-update` switches to, see `Instance::handoff`
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct InstanceHandoff {
+    pid: u32,
+    /// the start time of a adopted instance, which isn't a child of bothub
+    adopted_start_time: Option<u64>,
+    /// the exit status, if the child is already reaped
+    exit: Option<Exit>,
+    detached: bool,
+    started_at: std::time::SystemTime,
+    exited_at: Option<std::time::SystemTime>,
+    start_reason: String,
+    commit: Option<String>,
+    stdin: Option<RawFd>,
+    /// `None` for a detached instance, the output of which is followed again from its log files
+    stdout: Option<OutputHandoff>,
+    stderr: Option<OutputHandoff>,
+}
+
+impl InstanceHandoff {
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    /// every file descriptor the instance is handed with, which must be kept open across `exec`
+    pub fn fds(&self) -> Vec<RawFd> {
+        let outputs = [&self.stdout, &self.stderr];
+        let outputs = outputs.into_iter().flatten().map(|output| output.fd);
+        self.stdin.into_iter().chain(outputs).collect()
+    }
+}
+
+/// a started bot process, along with the captured stdout and stderr of it
+///
+/// stdout isn't captured if it is taken before creating the instance, as is the case for `control_bot`,
+/// and the output of a detached instance is captured from the log files it writes to instead
+#[derive(Debug)]
+pub struct Instance {
+    process: Process,
+    detached: bool,
+    started_at: std::time::SystemTime,
+    /// shared with the thread watching the exit of the child, if there is one
+    exited_at: Arc<Mutex<Option<std::time::SystemTime>>>,
+    watch: Watch,
+    start_reason: String,
+    commit: Option<String>,
+    stdout: Option<Output>,
+    stderr: Option<Output>,
+}
+
+impl Instance {
+    /// `start_reason` describes why the instance is started, e.g. `startup` or `restart`,
+    /// and `commit` is the commit the executable is built from, if known
+    pub fn new(
+        mut child: std::process::Child,
+        redactor: &Redactor,
+        tap: &Tap,
+        start_reason: &str,
+        commit: Option<String>,
+    ) -> Instance {
+        let stdout = child
+            .stdout
+            .take()
+            .map(|stdout| Output::capture(stdout, redactor.clone(), tap.clone()));
+        let stderr = child
+            .stderr
+            .take()
+            .map(|stderr| Output::capture(stderr, redactor.clone(), tap.stderr()));
+        Instance {
+            process: Process::Child(child),
+            detached: false,
+            started_at: std::time::SystemTime::now(),
+            exited_at: Arc::new(Mutex::new(None)),
+            watch: Watch::None,
+            start_reason: start_reason.to_string(),
+            commit,
+            stdout,
+            stderr,
+        }
+    }
+
+    /// a detached instance, which writes its stdout and stderr to the log files `stdout` and `stderr` rather than to bothub
+    pub fn new_detached(
+        child: std::process::Child,
+        (stdout, stderr): (&Path, &Path),
+        redactor: &Redactor,
+        tap: &Tap,
+        start_reason: &str,
+        commit: Option<String>,
+    ) -> Instance {
+        let mut instance = Instance::new(child, redactor, tap, start_reason, commit);
+        instance.detached = true;
+        // the log files are created before the child is spawned
+        instance.stdout = Output::follow(stdout, redactor.clone(), tap.clone()).ok();
+        instance.stderr = Output::follow(stderr, redactor.clone(), tap.stderr()).ok();
+        instance
+    }
+
+    /// adopts a detached instance started by a previous bothub,
+    /// failing if `pid` isn't running anymore or is reused by another process
+    #[allow(clippy::too_many_arguments)]
+    pub fn adopt(
+        pid: u32,
+        start_time: u64,
+        started_at: std::time::SystemTime,
+        (stdout, stderr): (&Path, &Path),
+        redactor: &Redactor,
+        tap: &Tap,
+        start_reason: &str,
+        commit: Option<String>,
+    ) -> Result<Instance, String> {
+        if crate::resources::start_time(pid) != Some(start_time) {
+            return Err(format!("pid {} isn't running anymore", pid));
+        }
+        Ok(Instance {
+            process: Process::Adopted { pid, start_time },
+            detached: true,
+            started_at,
+            exited_at: Arc::new(Mutex::new(None)),
+            watch: Watch::None,
+            start_reason: start_reason.to_string(),
+            commit,
+            stdout: Some(Output::follow(stdout, redactor.clone(), tap.clone())?),
+            stderr: Some(Output::follow(stderr, redactor.clone(), tap.stderr())?),
+        })
+    }
+
+    /// describes the instance for the executable `self-update` switches to, which resumes it with `resume`
+    ///
+    /// the instance itself is left as is, so it's still usable if switching fails
+    pub fn handoff(&mut self) -> std::io::Result<InstanceHandoff> {
+        let exit = self.try_wait()?;
+        let output = |output: &Option<Output>| {
+            let output = output.as_ref()?;
+            Some(OutputHandoff {
+                fd: output.stream.as_ref()?.as_raw_fd(),
+                lines: output.lines(),
+            })
+        };
+        let (stdin, adopted_start_time) = match &self.process {
+            Process::Child(child) => (child.stdin.as_ref().map(AsRawFd::as_raw_fd), None),
+            Process::Adopted { start_time, .. } => (None, Some(*start_time)),
+            Process::Inherited { stdin, .. } => (stdin.as_ref().map(AsRawFd::as_raw_fd), None),
+        };
+        let (stdout, stderr) = match self.detached {
+            true => (None, None),
+            false => (output(&self.stdout), output(&self.stderr)),
+        };
+        Ok(InstanceHandoff {
+            pid: self.pid(),
+            adopted_start_time,
+            exit,
+            detached: self.detached,
+            started_at: self.started_at,
+            exited_at: self.exited_at(),
+            start_reason: self.start_reason.clone(),
+            commit: self.commit.clone(),
+            stdin,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// resumes a instance handed over by the bothub before `self-update`,
+    /// the output of a detached instance is followed from the log files `stdout` and `stderr` again
+    ///
+    /// # Safety
+    ///
+    /// the file descriptors in `handoff` must be open and owned by nothing else
+    pub unsafe fn resume(
+        handoff: InstanceHandoff,
+        (stdout, stderr): (&Path, &Path),
+        redactor: &Redactor,
+        tap: &Tap,
+    ) -> Instance {
+        let output = |output: Option<OutputHandoff>, tap: Tap| {
+            output.map(|output| {
+                let stream = std::fs::File::from_raw_fd(output.fd);
+                Output::capture_after(stream, output.lines, redactor.clone(), tap)
+            })
+        };
+        let process = match handoff.adopted_start_time {
+            Some(start_time) => Process::Adopted {
+                pid: handoff.pid,
+                start_time,
+            },
+            None => Process::Inherited {
+                pid: handoff.pid,
+                stdin: handoff
+                    .stdin
+                    .map(|fd| std::process::ChildStdin::from(OwnedFd::from_raw_fd(fd))),
+                exit: handoff.exit,
+            },
+        };
+        let (stdout, stderr) = if handoff.detached {
+            (
+                Output::follow(stdout, redactor.clone(), tap.clone()).ok(),
+                Output::follow(stderr, redactor.clone(), tap.stderr()).ok(),
+            )
+        } else {
+            (
+                output(handoff.stdout, tap.clone()),
+                output(handoff.stderr, tap.stderr()),
+            )
+        };
+        Instance {
+            process,
+            detached: handoff.detached,
+            started_at: handoff.started_at,
+            exited_at: Arc::new(Mutex::new(handoff.exited_at)),
+            // a exit noticed before `self-update` is handled already
+            watch: match handoff.exited_at {
+                Some(_) => Watch::Noticed,
+                None => Watch::None,
+            },
+            start_reason: handoff.start_reason,
+            commit: handoff.commit,
+            stdout,
+            stderr,
+        }
+    }
+
+    pub fn pid(&self) -> u32 {
+        match &self.process {
+            Process::Child(child) => child.id(),
+            Process::Adopted { pid, .. } | Process::Inherited { pid, .. } => *pid,
+        }
+    }
+
+    /// whether the instance keeps running after bothub exits
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    /// stdin of the instance, which only a instance that isn't detached has
+    pub fn stdin(&mut self) -> Option<&mut std::process::ChildStdin> {
+        match &mut self.process {
+            Process::Child(child) => child.stdin.as_mut(),
+            Process::Adopted { .. } => None,
+            Process::Inherited { stdin, .. } => stdin.as_mut(),
+        }
+    }
+
+    /// sends a SIGKILL to the instance, which does nothing if a adopted instance isn't running anymore
+    pub fn kill(&mut self) -> std::io::Result<()> {
+        match &mut self.process {
+            Process::Child(child) => child.kill(),
+            Process::Adopted { pid, start_time } => {
+                if crate::resources::start_time(*pid) != Some(*start_time) {
+                    return Ok(());
+                }
+                if unsafe { libc::kill(*pid as libc::pid_t, libc::SIGKILL) } != 0 {
+                    let err = std::io::Error::last_os_error();
+                    if err.raw_os_error() != Some(libc::ESRCH) {
+                        return Err(err);
+                    }
+                }
+                Ok(())
+            }
+            Process::Inherited {
+                pid, exit: None, ..
+            } => {
+                if unsafe { libc::kill(*pid as libc::pid_t, libc::SIGKILL) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            }
+            // already reaped, like `Child::kill` of a reaped child
+            Process::Inherited { .. } => Ok(()),
+        }
+    }
+
+    /// same as `Child::try_wait`, but also remembers when the exit is first noticed
+    pub fn try_wait(&mut self) -> std::io::Result<Option<Exit>> {
+        let exit = match &mut self.process {
+            Process::Child(child) => child.try_wait()?.map(Exit::Status),
+            Process::Adopted { pid, start_time } => {
+                (crate::resources::start_time(*pid) != Some(*start_time)).then_some(Exit::Unknown)
+            }
+            Process::Inherited { pid, exit, .. } => match exit {
+                Some(exit) => Some(*exit),
+                None => wait_pid(*pid, libc::WNOHANG)?.inspect(|reaped| *exit = Some(*reaped)),
+            },
+        };
+        if exit.is_some() {
+            self.exited_at
+                .lock()
+                .unwrap()
+                .get_or_insert_with(std::time::SystemTime::now);
+        }
+        Ok(exit)
+    }
+
+    /// same as `Child::wait`, but also remembers when the child exited
+    ///
+    /// a adopted instance can't be waited for, so it's checked until it's gone
+    pub fn wait(&mut self) -> std::io::Result<Exit> {
+        let exit = match &mut self.process {
+            Process::Child(child) => Exit::Status(child.wait()?),
+            Process::Inherited {
+                exit: Some(exit), ..
+            } => *exit,
+            Process::Inherited { pid, exit, .. } => {
+                let reaped = wait_pid(*pid, 0)?.unwrap();
+                *exit = Some(reaped);
+                reaped
+            }
+            Process::Adopted { .. } => loop {
+                if let Some(exit) = self.try_wait()? {
+                    break exit;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            },
+        };
+        self.exited_at
+            .lock()
+            .unwrap()
+            .get_or_insert_with(std::time::SystemTime::now);
+        Ok(exit)
+    }
+
+    /// watches the child with a pidfd on a separate thread,
+    /// which records the exit time and calls `notify` with the pid as soon as the child exits
+    ///
+    /// a child which can't be watched (e.g. if pidfd isn't supported), or is already reaped by `try_wait`,
+    /// is left to `poll_exit` instead
+    ///
+    /// returns whether the child wasn't watched before and hadn't exited yet
+    pub fn watch_exit(&mut self, notify: ExitNotifier) -> bool {
+        if self.watch != Watch::None {
+            return false;
+        }
+        // a reaped pid might have been reused already
+        if self.exited_at().is_some() {
+            self.watch = Watch::Polled;
+            return false;
+        }
+        let pid = self.pid();
+        let exited_at = self.exited_at.clone();
+        self.watch = match watch_pid(pid, move || {
+            exited_at
+                .lock()
+                .unwrap()
+                .get_or_insert_with(std::time::SystemTime::now);
+            notify(pid);
+        }) {
+            Ok(_) => Watch::Pidfd,
+            Err(_) => Watch::Polled,
+        };
+        true
+    }
+
+    /// whether the exit of the child has to be noticed by `poll_exit`
+    pub fn is_polled(&self) -> bool {
+        self.watch == Watch::Polled
+    }
+
+    /// checks whether a child which isn't watched with a pidfd exited, which is true only once
+    pub fn poll_exit(&mut self) -> bool {
+        if self.watch != Watch::Polled || !matches!(self.try_wait(), Ok(Some(_))) {
+            return false;
+        }
+        self.watch = Watch::Noticed;
+        true
+    }
+
+    pub fn started_at(&self) -> std::time::SystemTime {
+        self.started_at
+    }
+
+    /// when the exit of the child is first noticed (which is right when it exits if it's watched),
+    /// `None` if it isn't noticed yet
+    pub fn exited_at(&self) -> Option<std::time::SystemTime> {
+        *self.exited_at.lock().unwrap()
+    }
+
+    pub fn start_reason(&self) -> &str {
+        &self.start_reason
+    }
+
+    pub fn commit(&self) -> Option<&str> {
+        self.commit.as_deref()
+    }
+
+    /// how long the child has been running, or had run before it exited
+    pub fn uptime(&self) -> std::time::Duration {
+        self.exited_at()
+            .unwrap_or_else(std::time::SystemTime::now)
+            .duration_since(self.started_at)
+            .unwrap_or_default()
+    }
+
+    /// stdout and stderr lines captured so far
+    pub fn output(&self) -> (Vec<String>, Vec<String>) {
+        (
+            self.stdout.as_ref().map_or_else(Vec::new, Output::lines),
+            self.stderr.as_ref().map_or_else(Vec::new, Output::lines),
+        )
+    }
+
+    /// the end of stderr (see `Output::finishing`), `None` if it isn't captured
+    pub fn finishing_stderr(&mut self) -> Option<Finishing> {
+        self.stderr.as_mut().map(Output::finishing)
+    }
+
+    /// waits for stdout and stderr to be closed (see `Output::finish`), then returns every captured line of them
+    ///
+    /// should only be called after the child exited
+    pub fn finish_output(&mut self) -> (Vec<String>, Vec<String>) {
+        (
+            self.stdout.as_mut().map_or_else(Vec::new, Output::finish),
+            self.stderr.as_mut().map_or_else(Vec::new, Output::finish),
+        )
+    }
+}
+
+/// reaps the child `pid` with `waitpid`, `None` if it's still running and `options` has `WNOHANG`
+fn wait_pid(pid: u32, options: libc::c_int) -> std::io::Result<Option<Exit>> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let mut status = 0;
+    loop {
+        match unsafe { libc::waitpid(pid as libc::pid_t, &mut status, options) } {
+            -1 if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => {}
+            -1 => return Err(std::io::Error::last_os_error()),
+            0 => return Ok(None),
+            _ => {
+                return Ok(Some(Exit::Status(std::process::ExitStatus::from_raw(
+                    status,
+                ))))
+            }
+        }
+    }
+}
+
+/// calls `on_exit` on a separate thread as soon as the child process `pid` exits,
+/// failing if the process can't be watched, e.g. when pidfd isn't supported
+///
+/// the process shouldn't be reaped before this is called, otherwise the pid might have been reused
+pub fn watch_pid<F>(pid: u32, on_exit: F) -> std::io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if pidfd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as libc::c_int) };
+    std::thread::spawn(move || {
+        // a pidfd becomes readable once the process exits
+        let mut pollfd = libc::pollfd {
+            fd: pidfd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            if unsafe { libc::poll(&mut pollfd, 1, -1) } > 0 {
+                break;
+            }
+            if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+                return;
+            }
+        }
+        on_exit();
+    });
+    Ok(())
+}
+
+/// `exited` *ExitCode*, followed by `signal` *SignalName* if the process is terminated by a signal
+///
+/// *ExitCode* is -1 if the process is terminated by a signal
+pub fn exit_description(status: &std::process::ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+
+    match status.signal() {
+        Some(signal) => format!("exited -1 signal {}", signal_name(signal)),
+        None => format!("exited {}", status.code().unwrap_or(-1)),
+    }
+}
+
+/// the name of a unix signal, e.g. `SIGKILL`, or `SIG` followed by the number for unknown signals
+pub fn signal_name(signal: i32) -> String {
+    match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGCHLD => "SIGCHLD",
+        libc::SIGCONT => "SIGCONT",
+        libc::SIGSTOP => "SIGSTOP",
+        libc::SIGTSTP => "SIGTSTP",
+        libc::SIGTTIN => "SIGTTIN",
+        libc::SIGTTOU => "SIGTTOU",
+        libc::SIGURG => "SIGURG",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGVTALRM => "SIGVTALRM",
+        libc::SIGPROF => "SIGPROF",
+        libc::SIGWINCH => "SIGWINCH",
+        libc::SIGIO => "SIGIO",
+        libc::SIGSYS => "SIGSYS",
+        signal => return format!("SIG{}", signal),
+    }
+    .to_string()
+}
+
+/// a duration in the compact form of e.g. `3h2m5s`, without whitespace
+pub fn format_uptime(uptime: std::time::Duration) -> String {
+    humantime::format_duration(std::time::Duration::from_secs(uptime.as_secs()))
+        .to_string()
+        .replace(' ', "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::Stream;
+    use std::io::Write;
+
+    fn capture() -> (Output, std::io::PipeWriter) {
+        let (reader, writer) = std::io::pipe().unwrap();
+        let tap = Tap::instance(&Stream::new(1), &("bot_a".to_string(), None));
+        (Output::capture(reader, Redactor::new(), tap), writer)
+    }
+
+    #[test]
+    fn finishes_once_the_stream_is_closed() {
+        let (mut output, mut writer) = capture();
+        let finishing = output.finishing();
+        writeln!(writer, "first").unwrap();
+        assert!(!finishing.is_done());
+        writeln!(writer, "last").unwrap();
+        drop(writer);
+        assert_eq!(finishing.wait(), ["first", "last"]);
+        assert!(finishing.is_done());
+        assert_eq!(output.finish(), ["first", "last"]);
+    }
+
+    #[test]
+    fn gives_up_on_a_stream_left_open() {
+        let (mut output, mut writer) = capture();
+        writeln!(writer, "held by a child").unwrap();
+        let start = std::time::Instant::now();
+        let finishing = output.finishing();
+        assert_eq!(finishing.wait(), ["held by a child"]);
+        assert!(start.elapsed() >= FINISH_TIMEOUT);
+        // the deadline is set by the first call, so it isn't waited on again
+        let start = std::time::Instant::now();
+        assert_eq!(output.finish(), ["held by a child"]);
+        assert!(start.elapsed() < FINISH_TIMEOUT);
+    }
+}
@@ -1,5 +1,25 @@
+pub mod acl;
 pub mod bot_parser;
+pub mod bot_writer;
 pub mod cmd_parser;
+pub mod confirmation;
+pub mod control_socket;
+pub mod daemon;
+pub mod dashboard;
+pub mod events;
+pub mod history;
+pub mod http;
+pub mod hub;
+pub mod instance;
+pub mod limits;
+pub mod protocol;
+pub mod redact;
+pub mod resources;
+pub mod self_update;
+pub mod state;
+pub mod status;
+pub mod stream;
+pub mod tui;
 
 #[derive(std::fmt::Debug)]
 pub enum TaskType {
@@ -25,8 +45,27 @@ impl std::fmt::Display for TaskType {
 }
 
 pub type Bots = std::collections::HashMap<String, bot_parser::Bot>;
-pub type BotInstances = std::collections::HashMap<String, Result<std::process::Child, String>>;
-pub type Tasks = std::collections::HashMap<
-    String,
-    ((String, TaskType, u32), Result<std::process::Child, String>),
->;
+/// runtime overrides of bots, keyed by bot name
+pub type BotOverrides = std::collections::HashMap<String, bot_parser::Overrides>;
+/// a bot name and the shard id of the instance, which is `None` if the bot isn't sharded
+pub type InstanceKey = (String, Option<u32>);
+pub type BotInstances = std::collections::HashMap<InstanceKey, Result<instance::Instance, String>>;
+pub type Tasks = std::collections::HashMap<String, ((String, TaskType, u32), Result<Task, String>)>;
+
+/// a spawned task, along with its captured stdout and stderr
+#[derive(std::fmt::Debug)]
+pub struct Task {
+    pub child: std::process::Child,
+    pub stdout: instance::Output,
+    pub stderr: instance::Output,
+    /// whether the task can't be watched with a pidfd, so its exit is checked by `Hub::poll_exits`
+    pub polled: bool,
+}
+
+/// the name used to refer to a bot instance in command outputs, `bot_name#shard_id` for sharded bots
+pub fn instance_name((bot_name, shard): &InstanceKey) -> String {
+    match shard {
+        Some(shard) => format!("{}#{}", bot_name, shard),
+        None => bot_name.clone(),
+    }
+}
@@ -0,0 +1,347 @@
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+/// the keys of a limits table, along with the resource each of them limits
+const RESOURCES: [(&str, Resource); 5] = [
+    ("address_space_mb", libc::RLIMIT_AS),
+    ("cpu_seconds", libc::RLIMIT_CPU),
+    ("open_files", libc::RLIMIT_NOFILE),
+    ("processes", libc::RLIMIT_NPROC),
+    ("core_size", libc::RLIMIT_CORE),
+];
+
+#[cfg(target_env = "gnu")]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type Resource = libc::c_int;
+
+/// resource limits of a process, applied with `setrlimit` right before it execs
+///
+/// `address_space_mb` and `core_size` are in megabytes
+///
+/// `address_space_mb` limits the virtual address space rather than the memory in use,
+/// which is usually much larger, e.g. for a tokio runtime or the arenas of glibc,
+/// and it's also accepted as `memory_mb`
+///
+/// `processes` is `RLIMIT_NPROC`, which the kernel checks against every process (and thread)
+/// of the user running bothub, not only the ones of the bot, so it fails the bot once the user has too many
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub address_space_mb: Option<u64>,
+    pub cpu_seconds: Option<u64>,
+    pub open_files: Option<u64>,
+    pub processes: Option<u64>,
+    pub core_size: Option<u64>,
+}
+
+impl Limits {
+    /// parses a limits table such as `bot.limits`, `name` is used in error descriptions
+    pub fn from_toml(value: Option<&toml::Value>, name: &str) -> Result<Limits, String> {
+        let table = match value {
+            Some(toml::Value::Table(table)) => table,
+            Some(_) => return Err(format!("bot.{} should be a table!", name)),
+            None => return Ok(Limits::default()),
+        };
+        if table.contains_key("memory_mb") && table.contains_key("address_space_mb") {
+            return Err(format!(
+                "bot.{}.memory_mb is the same limit as address_space_mb, only one of them should present!",
+                name
+            ));
+        }
+        let mut limits = Limits::default();
+        for (key, value) in table {
+            // a core size of 0 disables core dumps, while any other limit of 0 makes the bot fail to even exec
+            let limit = match value {
+                toml::Value::Integer(limit) if *limit >= 0 && key == "core_size" => *limit as u64,
+                toml::Value::Integer(limit) if *limit > 0 => *limit as u64,
+                _ if key == "core_size" => {
+                    return Err(format!(
+                        "bot.{}.{} should be a non-negative integer!",
+                        name, key
+                    ))
+                }
+                _ => {
+                    return Err(format!(
+                        "bot.{}.{} should be a positive integer!",
+                        name, key
+                    ))
+                }
+            };
+            match key.as_str() {
+                // what the virtual address space is limited with is often thought of as memory
+                "address_space_mb" | "memory_mb" => limits.address_space_mb = Some(limit),
+                "cpu_seconds" => limits.cpu_seconds = Some(limit),
+                "open_files" => limits.open_files = Some(limit),
+                "processes" => limits.processes = Some(limit),
+                "core_size" => limits.core_size = Some(limit),
+                _ => return Err(format!("bot.{}.{} isn't a known limit!", name, key)),
+            }
+        }
+        Ok(limits)
+    }
+
+    fn values(&self) -> [Option<u64>; 5] {
+        [
+            self.address_space_mb,
+            self.cpu_seconds,
+            self.open_files,
+            self.processes,
+            self.core_size,
+        ]
+    }
+
+    /// makes `command` set the limits on the child process before it execs
+    ///
+    /// the child fails to start if any of the limits can't be set
+    pub fn apply(&self, command: &mut std::process::Command) {
+        if self.values().iter().all(Option::is_none) {
+            return;
+        }
+        let limits = *self;
+        let hook = move || {
+            for ((key, resource), limit) in RESOURCES.iter().zip(limits.values()) {
+                let limit = match limit {
+                    Some(limit) if *key == "address_space_mb" || *key == "core_size" => {
+                        limit.saturating_mul(1024 * 1024)
+                    }
+                    Some(limit) => limit,
+                    None => continue,
+                };
+                // the cpu time hard limit is a second later, so the process gets a SIGXCPU
+                // (which `hit_by` recognizes) before the SIGKILL
+                let hard_limit = match *key {
+                    "cpu_seconds" => limit.saturating_add(1),
+                    _ => limit,
+                };
+                let rlimit = libc::rlimit {
+                    rlim_cur: limit as libc::rlim_t,
+                    rlim_max: hard_limit as libc::rlim_t,
+                };
+                // setrlimit is async-signal-safe, so it's fine to call it between fork and exec
+                if unsafe { libc::setrlimit(*resource, &rlimit) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        };
+        unsafe {
+            command.pre_exec(hook);
+        }
+    }
+
+    /// which limit a process exiting with `status` most likely hit, judging from the status and the end of its stderr
+    pub fn hit_by(
+        &self,
+        status: &std::process::ExitStatus,
+        stderr: &[String],
+    ) -> Option<&'static str> {
+        if status.success() {
+            return None;
+        }
+        if self.cpu_seconds.is_some() && status.signal() == Some(libc::SIGXCPU) {
+            return Some("cpu_seconds");
+        }
+        let stderr_contains = |patterns: &[&str]| {
+            stderr
+                .iter()
+                .rev()
+                .take(20)
+                .any(|line| patterns.iter().any(|pattern| line.contains(pattern)))
+        };
+        if self.address_space_mb.is_some()
+            && stderr_contains(&[
+                "memory allocation of",
+                "Cannot allocate memory",
+                "out of memory",
+                "MemoryError",
+            ])
+        {
+            return Some("address_space_mb");
+        }
+        if self.open_files.is_some() && stderr_contains(&["Too many open files", "Error 24"]) {
+            return Some("open_files");
+        }
+        // only failures to create a process or thread, since EAGAIN is reported for plenty else,
+        // e.g. a non-blocking socket with nothing to read
+        if self.processes.is_some()
+            && stderr_contains(&[
+                "fork: Resource temporarily unavailable",
+                "fork: retry: Resource temporarily unavailable",
+                "failed to spawn thread",
+                "pthread_create failed",
+                "failed to create new OS thread",
+                "can't start new thread",
+            ])
+        {
+            return Some("processes");
+        }
+        None
+    }
+
+    /// describes the limits in lines like `Bot::inspect`, each as `name` *Key* *Value*
+    pub fn inspect(&self, name: &str) -> String {
+        let mut output = String::new();
+        for ((key, _), limit) in RESOURCES.iter().zip(self.values()) {
+            if let Some(limit) = limit {
+                output.push_str(&format!("{} {} {}\n", name, key, limit));
+            }
+        }
+        output
+    }
+}
+
+/// the io scheduling class of a process, see ioprio_set(2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    /// only gets disk time when no other process needs it
+    Idle,
+    /// the default class, with a priority from 0 (highest) to 7 (lowest)
+    BestEffort(u8),
+}
+
+impl std::fmt::Display for IoClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Idle => write!(f, "idle"),
+            Self::BestEffort(priority) => write!(f, "best-effort {}", priority),
+        }
+    }
+}
+
+/// cpu and io scheduling priority of a process, applied right before it execs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Priority {
+    /// the nice value, from -20 (highest priority) to 19 (lowest priority)
+    pub nice: i32,
+    pub io_class: IoClass,
+}
+
+impl Priority {
+    /// makes `command` set the priority of the child process before it execs
+    ///
+    /// the child fails to start if the priority can't be set, e.g. a negative nice value without privileges
+    pub fn apply(&self, command: &mut std::process::Command) {
+        let priority = *self;
+        let hook = move || {
+            // setpriority and ioprio_set are async-signal-safe, so it's fine to call them between fork and exec
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, priority.nice) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // see linux/ioprio.h
+            const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+            const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+            let ioprio = match priority.io_class {
+                IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+                IoClass::BestEffort(level) => 2 << IOPRIO_CLASS_SHIFT | level as libc::c_long,
+            };
+            if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        };
+        unsafe {
+            command.pre_exec(hook);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(limits: &str) -> Result<Limits, String> {
+        let value: toml::Value = toml::from_str(limits).unwrap();
+        Limits::from_toml(Some(&value), "limits")
+    }
+
+    #[test]
+    fn parses_every_limit() {
+        assert_eq!(
+            parse("address_space_mb = 4096\ncpu_seconds = 60\nopen_files = 1024\nprocesses = 64\ncore_size = 0"),
+            Ok(Limits {
+                address_space_mb: Some(4096),
+                cpu_seconds: Some(60),
+                open_files: Some(1024),
+                processes: Some(64),
+                core_size: Some(0),
+            })
+        );
+        assert_eq!(Limits::from_toml(None, "limits"), Ok(Limits::default()));
+    }
+
+    #[test]
+    fn accepts_memory_mb_as_address_space_mb() {
+        assert_eq!(
+            parse("memory_mb = 512"),
+            Ok(Limits {
+                address_space_mb: Some(512),
+                ..Limits::default()
+            })
+        );
+        assert_eq!(
+            parse("memory_mb = 0"),
+            Err("bot.limits.memory_mb should be a positive integer!".to_string())
+        );
+        assert_eq!(
+            parse("memory_mb = 512\naddress_space_mb = 512"),
+            Err("bot.limits.memory_mb is the same limit as address_space_mb, only one of them should present!".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_zero_limits_but_core_size() {
+        for key in ["address_space_mb", "cpu_seconds", "open_files", "processes"] {
+            assert_eq!(
+                parse(&format!("{} = 0", key)),
+                Err(format!("bot.limits.{} should be a positive integer!", key))
+            );
+        }
+        assert_eq!(
+            parse("core_size = -1"),
+            Err("bot.limits.core_size should be a non-negative integer!".to_string())
+        );
+        assert!(parse("cpu_seconds = \"60\"").is_err());
+    }
+
+    #[test]
+    fn only_blames_processes_for_failing_to_create_one() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let limits = Limits {
+            processes: Some(64),
+            ..Limits::default()
+        };
+        let failed = std::process::ExitStatus::from_raw(1 << 8);
+        let hit_by = |line: &str| limits.hit_by(&failed, &[line.to_string()]);
+        assert_eq!(
+            hit_by("bash: fork: retry: Resource temporarily unavailable"),
+            Some("processes")
+        );
+        assert_eq!(
+            hit_by("thread 'main' panicked at 'failed to spawn thread: Os { code: 11, kind: WouldBlock, message: \"Resource temporarily unavailable\" }'"),
+            Some("processes")
+        );
+        assert_eq!(
+            hit_by("Error: reading the gateway, Resource temporarily unavailable (os error 11)"),
+            None
+        );
+        assert_eq!(
+            Limits::default().hit_by(
+                &failed,
+                &["bash: fork: Resource temporarily unavailable".to_string()]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_limits() {
+        assert_eq!(
+            parse("max_rss_mb = 512"),
+            Err("bot.limits.max_rss_mb isn't a known limit!".to_string())
+        );
+        assert_eq!(
+            Limits::from_toml(Some(&toml::Value::Integer(1)), "build_limits"),
+            Err("bot.build_limits should be a table!".to_string())
+        );
+    }
+}
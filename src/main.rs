@@ -1,600 +1,1173 @@
 use clap::Parser;
-use dcbothub::{bot_parser, cmd_parser, BotInstances, Bots, TaskType, Tasks};
+use dcbothub::confirmation::Confirmations;
+use dcbothub::events::Subscription;
+use dcbothub::hub::{Hub, Output, Reply, EXIT_POLL_INTERVAL};
+use dcbothub::instance::ExitNotifier;
+use dcbothub::protocol::{self, Protocol};
+use dcbothub::redact::{self, Redactor};
+use dcbothub::self_update::{self, Resumed};
+use dcbothub::stream::Stream;
+use dcbothub::{bot_parser, cmd_parser};
+use dcbothub::{control_socket, daemon, http, tui};
 use rustyline::error::ReadlineError;
-use std::collections::HashMap;
-use std::io::{self, BufRead, BufWriter, Read, Write};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::net::TcpListener;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdin, ChildStdout};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+
+/// bothub runs in the foreground, controlled from stdin or `control_bot`, without a mode
+#[derive(Parser, Debug)]
+#[command(name = "dcbothub", version)]
+struct Args {
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Mode {
+    /// run bothub in the background, controlled through `control_socket` (and `control_bot`)
+    Daemon,
+    /// send commands to a running bothub through `control_socket`
+    Ctl(control_socket::Ctl),
+    /// run bothub in the foreground with a dashboard in the terminal, or attach the dashboard to a running bothub
+    Tui(tui::Tui),
+    /// copy stdin to stdout a line at a time, redacted, in the background, which is how detached bots write their log files
+    #[command(hide = true)]
+    RedactLog,
+}
+
+/// what bothub is controlled with from the terminal it's started in, besides `control_bot`
+enum Terminal {
+    /// nothing, in daemon mode
+    Detached,
+    /// the prompt, unless `control_bot` is configured
+    Prompt,
+    /// the dashboard of `dcbothub tui`, connected like a connection to the control socket
+    Tui(UnixStream),
+}
 
 fn main() {
+    let (daemon, tui) = match Args::parse().mode {
+        Some(Mode::Ctl(args)) => {
+            if let Err(err) = control_socket::ctl(args) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Mode::Tui(args)) if args.attach => {
+            if let Err(err) = tui::attach(args) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Mode::RedactLog) => {
+            if let Err(err) = redact_log() {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Mode::Tui(_)) => (false, true),
+        Some(Mode::Daemon) => (true, false),
+        None => (false, false),
+    };
+    // handed over by the bothub before `self-update`, along with the lock, the socket and every bot
+    let mut resumed = self_update::take();
+
     // read in and verify bots.toml
-    let (bots, control_bot) = match bot_parser::parse_bots() {
+    let (bots, settings) = match bot_parser::parse_bots() {
         Ok(botnctrl) => botnctrl,
         Err(_) => {
+            if let Some(resumed) = &resumed {
+                self_update::roll_back(resumed, "bots.toml is invalid");
+            }
             return;
         }
     };
+    if daemon && resumed.is_none() && settings.control_socket.is_none() {
+        println!("Daemon mode requires control_socket in bots.toml to be controlled through");
+        return;
+    }
 
-    // start every bot in bots.toml
-    let mut bot_instances = HashMap::new();
-    for (name, bot) in &bots {
-        bot_instances.insert(
-            name.clone(),
-            bot.run()
-                .stdin(std::process::Stdio::piped())
-                .stdout(std::process::Stdio::piped())
-                .stderr(std::process::Stdio::piped())
-                .spawn()
-                .map_err(|err| err.to_string()),
-        );
+    // held until bothub exits, so a second bothub with the same bots.toml refuses to start
+    let lock = match &resumed {
+        Some(resumed) => Ok(unsafe { std::fs::File::from_raw_fd(resumed.handoff.lock) }),
+        None => daemon::lock(&settings.lock_file),
+    };
+    let mut lock = match lock {
+        Ok(lock) => lock,
+        Err(err) => {
+            println!("Failed taking the lock:\n\t{}", err);
+            return;
+        }
+    };
+
+    // bound before any bot starts, so bothub fails early if the socket is taken
+    let control_socket = match &mut resumed {
+        Some(resumed) => resumed
+            .handoff
+            .control_socket
+            .take()
+            .map(|(path, fd)| (path, unsafe { UnixListener::from_raw_fd(fd) })),
+        None => match &settings.control_socket {
+            Some(path) => match control_socket::bind(path, settings.control_socket_mode) {
+                Ok(listener) => Some((path.clone(), listener)),
+                Err(err) => {
+                    println!("Failed listening on control_socket:\n\t{}", err);
+                    return;
+                }
+            },
+            None => None,
+        },
+    };
+
+    // bound before any bot starts as well, while a listener handed over is kept if it's still on the same address
+    let inherited_http = resumed
+        .as_mut()
+        .and_then(|resumed| resumed.handoff.http.take())
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .filter(|listener| listener.local_addr().ok() == settings.http_address);
+    let http = match (inherited_http, settings.http_address) {
+        (Some(listener), _) => Some(listener),
+        (None, Some(address)) => match http::bind(address) {
+            Ok(listener) => Some(listener),
+            // bothub before `self-update` is gone, so it carries on without the HTTP API rather than exiting
+            Err(err) if resumed.is_some() => {
+                eprintln!("Failed listening on http_address, {}", err);
+                None
+            }
+            Err(err) => {
+                println!("Failed listening on http_address:\n\t{}", err);
+                if let Some((path, _)) = control_socket {
+                    let _ = std::fs::remove_file(path);
+                }
+                return;
+            }
+        },
+        (None, None) => None,
+    };
+
+    // detached before the runtime and any thread start, since only the forking thread is forked,
+    // which the bothub before `self-update` already is
+    let pid_file = settings.pid_file.clone();
+    if daemon && resumed.is_none() {
+        match daemon::detach(&settings.log_file) {
+            Ok(Some(pid)) => {
+                // written here rather than by the detached bothub, so they exist once this process exits
+                let written = daemon::write_pid(&mut lock, pid)
+                    .and_then(|_| std::fs::write(&pid_file, format!("{}\n", pid)));
+                if let Err(err) = written {
+                    println!("Failed writing the pid of the detached bothub, {}", err);
+                }
+                println!(
+                    "bothub is running in the background with pid {}, logging to {}",
+                    pid,
+                    settings.log_file.display()
+                );
+                return;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                println!("Failed running in the background:\n\t{}", err);
+                if let Some((path, _)) = control_socket {
+                    let _ = std::fs::remove_file(path);
+                }
+                return;
+            }
+        }
+    }
+
+    // the dashboard runs on its own thread, talking to bothub through a pair of connected sockets
+    let mut dashboard = None;
+    let terminal = match (daemon, tui) {
+        (true, _) => Terminal::Detached,
+        (false, false) => Terminal::Prompt,
+        (false, true) => {
+            let started = tui::take_terminal(&settings.log_file).and_then(|screen| {
+                let (hub_end, tui_end) = UnixStream::pair()
+                    .map_err(|err| format!("Failed connecting the dashboard, {}", err))?;
+                Ok((screen, hub_end, tui_end))
+            });
+            match started {
+                Ok((screen, hub_end, tui_end)) => {
+                    dashboard = Some(std::thread::spawn(move || {
+                        if let Err(err) = tui::run(screen, tui_end, true) {
+                            eprintln!("{}", err);
+                        }
+                    }));
+                    Terminal::Tui(hub_end)
+                }
+                Err(err) => {
+                    println!("Failed starting the dashboard:\n\t{}", err);
+                    if let Some((path, _)) = control_socket {
+                        let _ = std::fs::remove_file(path);
+                    }
+                    return;
+                }
+            }
+        }
+    };
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the async runtime")
+        .block_on(run(
+            bots,
+            settings,
+            control_socket,
+            http,
+            lock.as_raw_fd(),
+            terminal,
+            resumed,
+        ));
+
+    // the terminal is restored once the dashboard sees bothub is gone
+    if let Some(dashboard) = dashboard {
+        let _ = dashboard.join();
+    }
+
+    if daemon {
+        if let Err(err) = std::fs::remove_file(&pid_file) {
+            eprintln!("Failed removing {}, {}", pid_file.display(), err);
+        }
     }
+    drop(lock);
+}
 
-    let mut tasks: Tasks = HashMap::new();
+/// starts every bot (or resumes every bot handed over after `self-update`) and listens to every client
+/// until `exit` is invoked, controlled from the terminal as `terminal` tells
+async fn run(
+    bots: dcbothub::Bots,
+    settings: bot_parser::Settings,
+    control_socket: Option<(PathBuf, UnixListener)>,
+    http: Option<TcpListener>,
+    lock: RawFd,
+    terminal: Terminal,
+    resumed: Option<Resumed>,
+) {
+    let http_tokens = settings.http_tokens.clone();
+    let (events, mut receive_events) = mpsc::unbounded_channel();
+    let notify_exit: ExitNotifier = {
+        let events = events.clone();
+        Arc::new(move |pid| {
+            let _ = events.send(Event::Exited(pid));
+        })
+    };
 
-    match &control_bot {
-        Some(control_bot) => {
-            let _control_bot = match bot_instances.get_mut(control_bot).unwrap() {
-                Ok(control_bot) => control_bot,
+    // why switching to the new executable was rolled back, if bothub is resumed after `self-update`
+    let mut switched = None;
+    let mut resumed_control_bot = None;
+    let (mut hub, control_io) = match resumed {
+        Some(resumed) => {
+            switched = Some(resumed.rolled_back);
+            let (hub, notifications) =
+                unsafe { Hub::resume(bots, settings, notify_exit, resumed.handoff.hub) };
+            for notification in notifications {
+                eprint!("{}", hub.redact(&notification));
+            }
+            let control_io = resumed.handoff.control_bot.map(|control_bot| {
+                resumed_control_bot = Some((control_bot.protocol, control_bot.subscription));
+                unsafe {
+                    (
+                        ChildStdout::from(OwnedFd::from_raw_fd(control_bot.stdout)),
+                        ChildStdin::from(OwnedFd::from_raw_fd(control_bot.stdin)),
+                    )
+                }
+            });
+            (hub, control_io)
+        }
+        None => {
+            // start every bot (and every shard of them) in bots.toml, except detached ones still running from before
+            let mut hub = Hub::new(bots, settings, notify_exit);
+            for notification in hub.adopt() {
+                eprint!("{}", hub.redact(&notification));
+            }
+            match hub.start() {
+                Ok(control_io) => (hub, control_io),
                 Err(err) => {
-                    println!("Failed starting control_bot:\n\t{}", err.to_string());
+                    println!("Failed starting control_bot:\n\t{}", err);
+                    hub.shutdown();
+                    if let Some((path, _)) = control_socket {
+                        let _ = std::fs::remove_file(path);
+                    }
                     return;
                 }
-            };
+            }
+        }
+    };
+    // nobody could have subscribed to the events of the startup
+    hub.take_events();
 
-            let bot_in =
-                std::sync::Mutex::new(io::BufReader::new(_control_bot.stdout.take().unwrap()));
-            let bot_out = std::sync::Mutex::new(BufWriter::new(_control_bot.stdin.take().unwrap()));
-
-            cmd_loop(
-                &bots,
-                &mut bot_instances,
-                &mut tasks,
-                || {
-                    let mut input = String::new();
-                    bot_in
-                        .lock()
-                        .unwrap()
-                        .read_line(&mut input)
-                        .expect("Failed reading line from control_bot");
-                    Ok(input)
-                },
-                |o| {
-                    let mut bot_out = bot_out.lock().unwrap();
-                    write!(bot_out, "{}\n", o.lines().count())
-                        .expect("Failed writing output to control_bot");
-                    write!(bot_out, "{}", o).expect("Failed writing output to control_bot");
-                    bot_out
-                        .flush()
-                        .expect("Failed flushing output to control_bot");
-                    Ok(())
-                },
-                |o| Ok(eprint!("{o}")),
-                |bot_instances| {
-                    let _control_bot = bot_instances.get_mut(control_bot).unwrap();
-                    let _control_bot = _control_bot.as_mut().unwrap();
-                    _control_bot.kill().map_err(|err| err.to_string())?;
-                    let mut out_buffer = String::new();
-                    _control_bot
-                        .stderr
-                        .take()
-                        .unwrap()
-                        .read_to_string(&mut out_buffer)
-                        .unwrap();
-
-                    let mut new_control_bot = bots
-                        .get(control_bot)
-                        .unwrap()
-                        .run()
-                        .stdin(std::process::Stdio::piped())
-                        .stdout(std::process::Stdio::piped())
-                        .stderr(std::process::Stdio::piped())
-                        .spawn()
-                        .map_err(|err| err.to_string())?;
-                    *bot_in.lock().unwrap() =
-                        io::BufReader::new(new_control_bot.stdout.take().unwrap());
-                    *bot_out.lock().unwrap() =
-                        BufWriter::new(new_control_bot.stdin.take().unwrap());
-                    _control_bot.try_wait().unwrap();
-                    assert!(bot_instances.remove(control_bot).is_some());
-                    bot_instances.insert(control_bot.clone(), Ok(new_control_bot));
-                    Ok(())
-                },
-            )
-            .unwrap();
+    if let Some((_, listener)) = &control_socket {
+        accept_connections(listener.try_clone().unwrap(), events.clone());
+    }
+    if let Some(listener) = &http {
+        serve_http(
+            listener.try_clone().unwrap(),
+            http_tokens,
+            events.clone(),
+            hub.stream().clone(),
+        );
+    }
+    notify_terminate(events.clone());
+
+    let mut clients = Clients::new(events);
+    match control_io {
+        Some((control_stdout, control_stdin)) => {
+            let id = clients.add_control_bot(control_stdout, control_stdin);
+            if let Some((protocol, subscription)) = resumed_control_bot {
+                let client = clients.clients.get_mut(&id).unwrap();
+                client.protocol = protocol;
+                client.subscription = subscription;
+            }
         }
+        None if !matches!(terminal, Terminal::Prompt) => {}
         None => {
             let mut rl = rustyline::Editor::<()>::new().expect("Failed to create a terminal input");
             if rl.load_history("rustyline_history").is_err() {
                 println!("No previous history.");
             }
 
-            cmd_loop(
-                &bots,
-                &mut bot_instances,
-                &mut tasks,
-                || loop {
+            // ^C and ^D close the input, which exits bothub
+            let id = clients.add(
+                move || loop {
                     match rl.readline(">>> ") {
                         Ok(line) => {
-                            if line != "" {
+                            if !line.is_empty() {
                                 rl.add_history_entry(line.as_str());
-                                break Ok(line);
+                                // the reader is never told bothub exits, so the history is saved right away
+                                if let Err(err) = rl.save_history("rustyline_history") {
+                                    eprintln!("Failed saving history, {}", err);
+                                }
+                                break Some(Ok(line));
                             }
                         }
                         Err(ReadlineError::Interrupted) => {
                             println!("^C");
-                            break Ok("exit".to_string());
+                            break None;
                         }
                         Err(ReadlineError::Eof) => {
                             println!("^D");
-                            break Ok("exit".to_string());
+                            break None;
                         }
-                        Err(err) => break Err(format!("Error reading line: {}", err.to_string())),
+                        Err(err) => break Some(Err(format!("Error reading line: {}", err))),
                     }
                 },
-                |o| Ok(print!("{o}")),
-                |o| Ok(eprint!("{o}")),
-                |_| Ok(()),
-            )
-            .unwrap();
+                |message| {
+                    match message {
+                        Message::Reply(o) | Message::Line(o) => print!("{o}"),
+                    }
+                    Ok(())
+                },
+            );
+            clients.terminal = Some(id);
+        }
+    }
+    // kept to tell the dashboard bothub is gone, since the client reading it holds the connection open
+    let mut dashboard = None;
+    if let Terminal::Tui(stream) = terminal {
+        match (stream.try_clone(), stream.try_clone()) {
+            (Ok(input), Ok(held)) => {
+                // the dashboard quits by closing its end of the connection, which exits bothub
+                clients.terminal = Some(clients.add_framed("the dashboard", input, stream));
+                dashboard = Some(held);
+            }
+            (Err(err), _) | (_, Err(err)) => eprintln!("Failed connecting the dashboard, {}", err),
+        }
+    }
+
+    if let Some(rolled_back) = switched {
+        self_update::finish();
+        let notification = hub.self_update_switched(rolled_back.as_deref());
+        eprint!("{}", hub.redact(&notification));
+        clients.push_events(&mut hub);
+    }
+
+    let held = Held {
+        lock,
+        control_socket: control_socket
+            .as_ref()
+            .map(|(path, listener)| (path.clone(), listener.as_raw_fd())),
+        http: http.as_ref().map(TcpListener::as_raw_fd),
+    };
+    cmd_loop(&mut hub, &mut clients, &held, &mut receive_events)
+        .await
+        .unwrap();
 
-            rl.save_history("rustyline_history").unwrap();
+    hub.shutdown();
+    if let Some((path, _)) = control_socket {
+        if let Err(err) = std::fs::remove_file(&path) {
+            eprintln!("Failed removing {}, {}", path.display(), err);
         }
     }
+    if let Some(dashboard) = dashboard {
+        let _ = dashboard.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// runs `Mode::RedactLog`, with the secrets to scrub in `redact::SECRETS_VAR`
+///
+/// the relay forks into a new session, so bothub only waits for it to detach,
+/// and the relay isn't left to bothub to reap when it exits, or killed along with it
+fn redact_log() -> Result<(), String> {
+    let secrets: Vec<String> = serde_json::from_str(
+        &std::env::var(redact::SECRETS_VAR)
+            .map_err(|err| format!("Failed reading {}, {}", redact::SECRETS_VAR, err))?,
+    )
+    .map_err(|err| format!("Failed parsing {}, {}", redact::SECRETS_VAR, err))?;
+    let redactor = Redactor::new();
+    for secret in &secrets {
+        redactor.add_secret(secret);
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => {
+            return Err(format!(
+                "Failed forking, {}",
+                std::io::Error::last_os_error()
+            ))
+        }
+        0 => unsafe {
+            libc::setsid();
+        },
+        _ => return Ok(()),
+    }
+    redact::redact_lines(io::stdin().lock(), io::stdout().lock(), &redactor)
+        .map_err(|err| format!("Failed relaying the output, {}", err))
+}
+
+/// sends `Event::Terminated` to the command loop once bothub receives a SIGTERM
+fn notify_terminate(events: UnboundedSender<Event>) {
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            tokio::spawn(async move {
+                sigterm.recv().await;
+                let _ = events.send(Event::Terminated);
+            });
+        }
+        Err(err) => eprintln!("Failed listening for SIGTERM, {}", err),
+    }
+}
 
-    for (_, child) in bot_instances {
-        match child {
-            Ok(mut child) => {
-                if child
-                    .try_wait()
-                    .expect("Failed to check child status")
-                    .is_none()
-                {
-                    child.kill().expect("Failed to kill running child");
+/// accepts connections to the control socket on a separate thread, sending each to the command loop
+fn accept_connections(listener: UnixListener, events: UnboundedSender<Event>) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if events.send(Event::Connected(stream)).is_err() {
+                        break;
+                    }
                 }
+                Err(err) => eprintln!("Failed accepting a connection to control_socket, {}", err),
             }
-            Err(_) => (),
         }
+    });
+}
+
+/// serves the HTTP API, executing every request in the command loop like a command of any other client
+fn serve_http(
+    listener: TcpListener,
+    tokens: Vec<http::HttpToken>,
+    events: UnboundedSender<Event>,
+    stream: Stream,
+) {
+    let execute: http::Execute = Arc::new(move |cli| {
+        let (reply, output) = std::sync::mpsc::channel();
+        events.send(Event::Request(cli, reply)).ok()?;
+        output.recv().ok()
+    });
+    if let Err(err) = http::serve(listener, tokens, execute, stream) {
+        eprintln!("{}", err);
     }
 }
 
-fn cmd_loop<F1, F2, F3, F4>(
-    bots: &Bots,
-    bot_instances: &mut BotInstances,
-    tasks: &mut Tasks,
-    mut get_input: F1,
-    mut print_output: F2,
-    mut print_error: F3,
-    mut control_restart: F4,
-) -> Result<(), String>
-where
-    F1: FnMut() -> Result<String, String>,
-    F2: FnMut(&str) -> Result<(), String>,
-    F3: FnMut(&str) -> Result<(), String>,
-    F4: FnMut(&mut BotInstances) -> Result<(), String>,
-{
-    let mut task_serial_counter = 0;
-    // start listening to stdin/control_bot for commands
-    loop {
-        let input = get_input()?;
+type ClientId = usize;
 
-        let parsed = cmd_parser::Cli::try_parse_from(
-            "dcbothub"
-                .split_whitespace()
-                .chain(input.split_whitespace()),
-        );
+/// what is written to a client
+enum Message<'a> {
+    /// the output of a command in the text protocol
+    Reply(&'a str),
+    /// a line written as is, such as a event or anything in the json protocol
+    Line(&'a str),
+}
 
-        let mut is_restart = false;
+/// writes a message to a client
+type ClientWriter = Box<dyn FnMut(Message) -> Result<(), String>>;
 
-        let command_output = match &parsed {
-            Ok(cli) => match &cli.command {
-                cmd_parser::Commands::List => {
-                    let mut output = String::new();
-                    for name in bots.keys() {
-                        output.push_str(&name);
-                        output.push(' ');
-                    }
-                    let mut output = output.trim_end().to_string();
-                    output.push('\n');
-                    output
-                }
-                cmd_parser::Commands::ListExisting => {
-                    let mut output = String::new();
-                    for name in bot_instances.keys() {
-                        output.push_str(&name);
-                        output.push(' ');
-                    }
-                    let mut output = output.trim_end().to_string();
-                    output.push('\n');
-                    output
-                }
-                cmd_parser::Commands::ListExecuting => {
-                    let mut output = String::new();
-                    for name in tasks.keys() {
-                        output.push_str(&name);
-                        output.push(' ');
+/// what the command loop waits for, besides timers
+enum Event {
+    /// a line of input from a client
+    Input(ClientId, String),
+    /// a client closed its input, or reading it failed
+    Closed(ClientId),
+    /// a connection to the control socket, which becomes a client
+    Connected(UnixStream),
+    /// a command from the HTTP API or the web dashboard, the output of which is sent back
+    Request(cmd_parser::Cli, std::sync::mpsc::Sender<Output>),
+    /// a watched instance or task exited
+    Exited(u32),
+    /// stderr of the instance recorded in `history` is read to the end, see `Hub::take_finishing`
+    Finished(u32),
+    /// bothub received a SIGTERM, which is handled like `exit`
+    Terminated,
+}
+
+/// what a reply needs to know about the request it answers
+struct Request {
+    /// the id of the request in the json protocol, which is echoed back in the reply
+    id: Value,
+    /// whether the output of the command is asked for in json with `--json`
+    json_output: bool,
+}
+
+/// a `wait` a client is blocked on
+struct Waiting {
+    request: Request,
+    task_id: String,
+    deadline: Option<Instant>,
+}
+
+/// a source of commands, such as the terminal, `control_bot` or a connection to the control socket
+struct Client {
+    write: ClientWriter,
+    /// allows the reader of the client to read one more input
+    ready: std::sync::mpsc::Sender<()>,
+    waiting: Option<Waiting>,
+    /// inputs received while waiting, executed once the wait is over
+    queued: VecDeque<String>,
+    subscription: Subscription,
+    protocol: Protocol,
+    /// the input is closed, so the client is removed once its queued inputs are executed
+    closed: bool,
+}
+
+/// every client commands are read from
+///
+/// the input of every client is read on a separate thread, one input at a time,
+/// and the next input is only read after the previous one is handled
+struct Clients {
+    clients: HashMap<ClientId, Client>,
+    next_id: ClientId,
+    events: UnboundedSender<Event>,
+    /// the client of `control_bot`, along with duplicates of its stdout and stdin for `self-update` to hand over
+    control_bot: Option<(ClientId, OwnedFd, OwnedFd)>,
+    /// the commands waiting to be confirmed
+    confirmations: Confirmations,
+    /// the client of the terminal bothub runs in, the prompt or the dashboard, which exits bothub once it's closed
+    terminal: Option<ClientId>,
+}
+
+impl Clients {
+    fn new(events: UnboundedSender<Event>) -> Clients {
+        Clients {
+            clients: HashMap::new(),
+            next_id: 0,
+            events,
+            control_bot: None,
+            confirmations: Confirmations::default(),
+            terminal: None,
+        }
+    }
+
+    /// adds a client, `read` is called for every input and returns `None` once the input is closed
+    /// (or the description of how reading failed, which closes the input as well),
+    /// and `write` is called with the output of every command and every event the client subscribed to
+    fn add<R, W>(&mut self, mut read: R, write: W) -> ClientId
+    where
+        R: FnMut() -> Option<Result<String, String>> + Send + 'static,
+        W: FnMut(Message) -> Result<(), String> + 'static,
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (ready, read_requested) = std::sync::mpsc::channel();
+        let events = self.events.clone();
+        std::thread::spawn(move || {
+            while read_requested.recv().is_ok() {
+                let (event, closed) = match read() {
+                    Some(Ok(input)) => (Event::Input(id, input), false),
+                    // the input is given up on, rather than bothub
+                    Some(Err(err)) => {
+                        eprintln!("{}", err);
+                        (Event::Closed(id), true)
                     }
-                    let mut output = output.trim_end().to_string();
-                    output.push('\n');
-                    output
+                    None => (Event::Closed(id), true),
+                };
+                if events.send(event).is_err() || closed {
+                    break;
                 }
-                cmd_parser::Commands::ListStatus => {
-                    let mut output = String::new();
-                    for (name, instance) in bot_instances.iter_mut() {
-                        output.push_str(&format!(
-                            "{} {} {}",
-                            name,
-                            if instance.is_ok() {
-                                "started"
-                            } else {
-                                "failed"
-                            },
-                            instance.as_mut().map_or_else(
-                                |error| error.to_string(),
-                                |child| child.try_wait().unwrap().map_or_else(
-                                    || "running".to_string(),
-                                    |status| format!("exited {}", status.code().unwrap_or(-1))
-                                )
-                            )
-                        ));
-                        output.push('\n');
-                    }
-                    output
+            }
+        });
+        ready.send(()).unwrap();
+        self.clients.insert(
+            id,
+            Client {
+                write: Box::new(write),
+                ready,
+                waiting: None,
+                queued: VecDeque::new(),
+                subscription: Subscription::default(),
+                protocol: Protocol::Text,
+                closed: false,
+            },
+        );
+        id
+    }
+
+    /// adds `control_bot` as a client
+    fn add_control_bot(
+        &mut self,
+        control_stdout: ChildStdout,
+        control_stdin: ChildStdin,
+    ) -> ClientId {
+        let fds = control_stdout
+            .as_fd()
+            .try_clone_to_owned()
+            .and_then(|stdout| Ok((stdout, control_stdin.as_fd().try_clone_to_owned()?)));
+        let id = self.add_framed("control_bot", control_stdout, control_stdin);
+        self.control_bot = match fds {
+            Ok((stdout, stdin)) => Some((id, stdout, stdin)),
+            Err(err) => {
+                eprintln!("Failed duplicating the pipes of control_bot, {}", err);
+                None
+            }
+        };
+        id
+    }
+
+    /// `control_bot` as it's handed over by `self-update`, if it's still a client
+    fn control_bot_handoff(&self) -> Option<self_update::ControlBot> {
+        let (id, stdout, stdin) = self.control_bot.as_ref()?;
+        let client = self.clients.get(id)?;
+        Some(self_update::ControlBot {
+            stdout: stdout.as_raw_fd(),
+            stdin: stdin.as_raw_fd(),
+            protocol: client.protocol,
+            subscription: client.subscription.clone(),
+        })
+    }
+
+    /// whether a client is blocked on `wait`
+    fn waiting(&self) -> bool {
+        self.clients.values().any(|client| client.waiting.is_some())
+    }
+
+    /// adds a connection to the control socket as a client, which is spoken to just like `control_bot`
+    ///
+    /// writes time out so a connection which stopped reading can't block bothub, and the connection is dropped instead
+    fn add_connection(&mut self, stream: UnixStream) {
+        let input = match stream.try_clone() {
+            Ok(input) => input,
+            Err(err) => {
+                eprintln!("Failed accepting a connection to control_socket, {}", err);
+                return;
+            }
+        };
+        if let Err(err) = stream.set_write_timeout(Some(std::time::Duration::from_secs(10))) {
+            eprintln!("Failed accepting a connection to control_socket, {}", err);
+            return;
+        }
+        self.add_framed("a connection to control_socket", input, stream);
+    }
+
+    /// adds a client which is replied with the line count of the output followed by the output
+    /// in the text protocol, while anything else is written as is
+    fn add_framed<R, W>(&mut self, name: &'static str, input: R, output: W) -> ClientId
+    where
+        R: io::Read + Send + 'static,
+        W: Write + 'static,
+    {
+        let mut input = io::BufReader::new(input);
+        let mut output = BufWriter::new(output);
+        self.add(
+            move || {
+                let mut line = String::new();
+                match input.read_line(&mut line) {
+                    Ok(0) => None,
+                    Ok(_) => Some(Ok(line)),
+                    Err(err) => Some(Err(format!("Failed reading line from {}, {}", name, err))),
                 }
-                cmd_parser::Commands::ListTasks => {
-                    let mut output = String::new();
-                    for (id, ((bot_name, task_type, serial_number), instance)) in tasks.iter_mut() {
-                        output.push_str(&format!(
-                            "{id}\t{bot_name} {task_type} {serial_number} {} {}",
-                            if instance.is_ok() {
-                                "started"
-                            } else {
-                                "failed"
-                            },
-                            instance.as_mut().map_or_else(
-                                |error| error.to_string(),
-                                |child| child.try_wait().unwrap().map_or_else(
-                                    || "running".to_string(),
-                                    |status| format!("exited {}", status.code().unwrap_or(-1))
-                                )
-                            )
-                        ));
-                        output.push('\n');
-                    }
-                    output
+            },
+            move |message| {
+                match message {
+                    Message::Reply(o) => writeln!(output, "{}", o.lines().count())
+                        .and_then(|_| write!(output, "{}", o)),
+                    Message::Line(line) => write!(output, "{}", line),
                 }
-                cmd_parser::Commands::Status { bot_name } => {
-                    match bot_instances.get_mut(bot_name) {
-                        Some(instance) => {
-                            format!(
-                                "some {} {}\n",
-                                if instance.is_ok() {
-                                    "started"
-                                } else {
-                                    "failed"
-                                },
-                                instance.as_mut().map_or_else(
-                                    |error| error.to_string(),
-                                    |child| child.try_wait().unwrap().map_or_else(
-                                        || "running".to_string(),
-                                        |status| format!("exited {}", status.code().unwrap_or(-1))
-                                    )
-                                )
-                            )
-                        }
-                        None => "none\n".to_string(),
-                    }
+                .and_then(|_| output.flush())
+                .map_err(|err| format!("Failed writing output to {}, {}", name, err))
+            },
+        )
+    }
+
+    /// replies `output` to a request of a client in the protocol it speaks,
+    /// removing the client if it can't be written to
+    ///
+    /// in the text protocol, the output is replied in json in a line if the request asked for it
+    fn reply(&mut self, id: ClientId, request: &Request, output: &Output) {
+        let protocol = match self.clients.get(&id) {
+            Some(client) => client.protocol,
+            None => return,
+        };
+        match protocol {
+            Protocol::Text if request.json_output => {
+                self.write(id, Message::Reply(&format!("{}\n", output.json)))
+            }
+            Protocol::Text => self.write(id, Message::Reply(&output.text)),
+            Protocol::Json => self.write(
+                id,
+                Message::Line(&protocol::reply(&request.id, &output.text, &output.json)),
+            ),
+        }
+    }
+
+    /// replies to a invalid request of a client,
+    /// which is a empty reply along with `error` on stderr of bothub in the text protocol
+    fn reply_error(&mut self, id: ClientId, request_id: &Value, error: &str) {
+        let protocol = match self.clients.get(&id) {
+            Some(client) => client.protocol,
+            None => return,
+        };
+        match protocol {
+            Protocol::Text => {
+                self.write(id, Message::Reply(""));
+                eprint!("{}", error);
+            }
+            Protocol::Json => self.write(id, Message::Line(&protocol::error(request_id, error))),
+        }
+    }
+
+    fn write(&mut self, id: ClientId, message: Message) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            if let Err(err) = (client.write)(message) {
+                eprintln!("{}", err);
+                self.clients.remove(&id);
+            }
+        }
+    }
+
+    /// pushes the events happened in the hub to every client subscribed to them
+    fn push_events(&mut self, hub: &mut Hub) {
+        for mut event in hub.take_events() {
+            event.details = hub.redact(&event.details);
+            hub.stream().publish_event(&event);
+            let subscribed: Vec<(ClientId, Protocol)> = self
+                .clients
+                .iter()
+                .filter(|(_, client)| client.subscription.matches(&event))
+                .map(|(id, client)| (*id, client.protocol))
+                .collect();
+            for (id, client_protocol) in subscribed {
+                let line = match client_protocol {
+                    Protocol::Text => event.line(),
+                    Protocol::Json => protocol::event(&event),
+                };
+                self.write(id, Message::Line(&line));
+            }
+        }
+    }
+
+    /// allows a client to send one more input
+    fn ready(&self, id: ClientId) {
+        if let Some(client) = self.clients.get(&id) {
+            let _ = client.ready.send(());
+        }
+    }
+}
+
+/// what bothub holds besides the hub and the clients, which `self-update` hands over as well
+struct Held {
+    lock: RawFd,
+    control_socket: Option<(PathBuf, RawFd)>,
+    http: Option<RawFd>,
+}
+
+/// listens to every client for commands, and handles exits and timers, until `exit` is invoked
+///
+/// switches to the executable built by `self-update` once no client is blocked on `wait`
+async fn cmd_loop(
+    hub: &mut Hub,
+    clients: &mut Clients,
+    held: &Held,
+    events: &mut UnboundedReceiver<Event>,
+) -> Result<(), String> {
+    let mut next_sample = Instant::now() + hub.sample_interval();
+    let mut next_poll = Instant::now() + EXIT_POLL_INTERVAL;
+    loop {
+        // recorded once stderr is read to the end, which would hold up the loop if it's waited on here
+        for (pid, stderr) in hub.take_finishing() {
+            let events = clients.events.clone();
+            tokio::task::spawn_blocking(move || {
+                stderr.wait();
+                let _ = events.send(Event::Finished(pid));
+            });
+        }
+
+        let mut deadline = clients
+            .clients
+            .values()
+            .filter_map(|client| client.waiting.as_ref()?.deadline)
+            .fold(next_sample, Instant::min);
+        // exits which can't be watched are only noticed by checking them every now and then
+        if hub.polls_exits() {
+            deadline = deadline.min(next_poll);
+        }
+
+        let exit = match tokio::time::timeout_at(deadline, events.recv()).await {
+            Ok(Some(Event::Input(id, input))) => {
+                let exit = handle_input(hub, clients, id, input)?;
+                clients.ready(id);
+                exit
+            }
+            // only whoever sits at the terminal can close it, so it isn't checked against roles like `exit`
+            Ok(Some(Event::Closed(id))) if clients.terminal == Some(id) => {
+                eprintln!("The terminal is closed, exiting");
+                true
+            }
+            Ok(Some(Event::Closed(id))) => {
+                if let Some(client) = clients.clients.get_mut(&id) {
+                    client.closed = true;
                 }
-                cmd_parser::Commands::TaskStatus { task_id } => match tasks.get_mut(task_id) {
-                    Some(((bot_name, task_type, serial_number), instance)) => {
-                        format!(
-                            "some {task_id}\t{bot_name} {task_type} {serial_number} {} {}\n",
-                            if instance.is_ok() {
-                                "started"
-                            } else {
-                                "failed"
-                            },
-                            instance.as_mut().map_or_else(
-                                |error| error.to_string(),
-                                |child| child.try_wait().unwrap().map_or_else(
-                                    || "running".to_string(),
-                                    |status| format!("exited {}", status.code().unwrap_or(-1))
-                                )
-                            )
-                        )
-                    }
-                    None => "none\n".to_string(),
-                },
-                cmd_parser::Commands::Clean { bot_name } => match bots.get(bot_name) {
-                    Some(bot) => {
-                        if bot.has_repo() {
-                            let task_id = format!("{:08}", task_serial_counter);
-                            tasks.insert(
-                                task_id.clone(),
-                                (
-                                    (bot_name.clone(), TaskType::Clean, task_serial_counter),
-                                    bot.clean()
-                                        .unwrap()
-                                        .stdin(std::process::Stdio::piped())
-                                        .stdout(std::process::Stdio::piped())
-                                        .stderr(std::process::Stdio::piped())
-                                        .spawn()
-                                        .map_err(|err| err.to_string()),
-                                ),
-                            );
-                            task_serial_counter += 1;
-                            format!("some {}\n", task_id)
-                        } else {
-                            "some no_repo\n".to_string()
-                        }
-                    }
-                    None => "none\n".to_string(),
-                },
-                cmd_parser::Commands::CleanAll { bot_name } => match bots.get(bot_name) {
-                    Some(bot) => {
-                        if bot.has_repo() {
-                            let task_id = format!("{:08}", task_serial_counter);
-                            tasks.insert(
-                                task_id.clone(),
-                                (
-                                    (bot_name.clone(), TaskType::Clean, task_serial_counter),
-                                    bot.clean_all()
-                                        .unwrap()
-                                        .stdin(std::process::Stdio::piped())
-                                        .stdout(std::process::Stdio::piped())
-                                        .stderr(std::process::Stdio::piped())
-                                        .spawn()
-                                        .map_err(|err| err.to_string()),
-                                ),
-                            );
-                            task_serial_counter += 1;
-                            format!("some {}\n", task_id)
-                        } else {
-                            "some no_repo\n".to_string()
-                        }
-                    }
-                    None => "none\n".to_string(),
-                },
-                cmd_parser::Commands::Build { bot_name } => match bots.get(bot_name) {
-                    Some(bot) => {
-                        if bot.has_repo() {
-                            let task_id = format!("{:08}", task_serial_counter);
-                            tasks.insert(
-                                task_id.clone(),
-                                (
-                                    (bot_name.clone(), TaskType::Build, task_serial_counter),
-                                    bot.build()
-                                        .unwrap()
-                                        .stdin(std::process::Stdio::piped())
-                                        .stdout(std::process::Stdio::piped())
-                                        .stderr(std::process::Stdio::piped())
-                                        .spawn()
-                                        .map_err(|err| err.to_string()),
-                                ),
-                            );
-                            task_serial_counter += 1;
-                            format!("some {}\n", task_id)
-                        } else {
-                            "some no_repo\n".to_string()
-                        }
-                    }
-                    None => "none\n".to_string(),
-                },
-                cmd_parser::Commands::Pull { bot_name } => match bots.get(bot_name) {
-                    Some(bot) => {
-                        if bot.has_repo() {
-                            let task_id = format!("{:08}", task_serial_counter);
-                            tasks.insert(
-                                task_id.clone(),
+                execute_queued(hub, clients, id)?
+            }
+            Ok(Some(Event::Connected(stream))) => {
+                clients.add_connection(stream);
+                false
+            }
+            Ok(Some(Event::Request(cli, reply))) => {
+                // requests over the network are never from a local client
+                let admitted = clients.confirmations.admit(
+                    hub,
+                    cli.caller.clone(),
+                    false,
+                    cli.yes,
+                    cli.command,
+                );
+                let (output, exit) = match admitted {
+                    Err(reply) => (hub.redact_output(&reply), false),
+                    // only `GET /stream` subscribes, which is replied with the bots it may stream,
+                    // and streams their output along with their events
+                    Ok(cmd_parser::Commands::Subscribe { events, bots }) => {
+                        let scope = &["logs", "subscribe"];
+                        match hub.scope(cli.caller.as_deref(), false, scope, &bots) {
+                            Ok(bots) => {
+                                let mut subscription = Subscription::default();
+                                subscription.subscribe(&events, &bots);
                                 (
-                                    (bot_name.clone(), TaskType::Pull, task_serial_counter),
-                                    bot.pull()
-                                        .unwrap()
-                                        .stdin(std::process::Stdio::piped())
-                                        .stdout(std::process::Stdio::piped())
-                                        .stderr(std::process::Stdio::piped())
-                                        .spawn()
-                                        .map_err(|err| err.to_string()),
-                                ),
-                            );
-                            task_serial_counter += 1;
-                            format!("some {}\n", task_id)
-                        } else {
-                            "some no_repo\n".to_string()
-                        }
-                    }
-                    None => "none\n".to_string(),
-                },
-                cmd_parser::Commands::Start { bot_name } => {
-                    if bot_instances.contains_key(bot_name) {
-                        "exists\n".to_string()
-                    } else {
-                        match bots.get(bot_name) {
-                            Some(bot) => {
-                                bot_instances.insert(
-                                    bot_name.clone(),
-                                    bot.run()
-                                        .stdin(std::process::Stdio::piped())
-                                        .stdout(std::process::Stdio::piped())
-                                        .stderr(std::process::Stdio::piped())
-                                        .spawn()
-                                        .map_err(|err| err.to_string()),
-                                );
-                                "none some spawned\n".to_string()
+                                    Output::new(subscription.describe(), subscription.json()),
+                                    false,
+                                )
+                            }
+                            Err(denied) => {
+                                eprint!("{}", hub.redact(&denied.text));
+                                (hub.redact_output(&denied), false)
                             }
-                            None => "none none\n".to_string(),
                         }
                     }
+                    // only commands replied right away are mapped onto by the HTTP API,
+                    // but `confirm` could still confirm a `exit` of the same caller from `control_bot`
+                    Ok(command) => match hub.execute(&command) {
+                        Reply::Output(output) => (hub.redact_output(&output), false),
+                        Reply::Exit => (Output::none(), true),
+                        _ => (Output::none(), false),
+                    },
+                };
+                let _ = reply.send(output);
+                exit
+            }
+            Ok(Some(Event::Exited(pid))) => {
+                if let Some(notification) = hub.handle_exit(pid) {
+                    eprint!("{}", hub.redact(&notification));
                 }
-                cmd_parser::Commands::Msg { bot_name, message } => {
-                    match bot_instances.get_mut(bot_name) {
-                        Some(Ok(child)) => match child.try_wait().unwrap() {
-                            Some(_) => "started exited\n".to_string(),
-                            None => match child.stdin.as_mut() {
-                                Some(stdin) => {
-                                    let mut bot_out = BufWriter::new(stdin);
-                                    write!(bot_out, "{}\n", message.join(" ")).unwrap();
-                                    bot_out.flush().unwrap();
-                                    "started running written\n".to_string()
-                                }
-                                None => "started running failed\n".to_string(),
-                            },
-                        },
-                        Some(Err(_)) => "failed\n".to_string(),
-                        None => "none\n".to_string(),
+                // so the exit is pushed before the replies to the waits on it
+                clients.push_events(hub);
+                finish_waits(hub, clients)?
+            }
+            Ok(Some(Event::Finished(pid))) => {
+                hub.record_finished(pid);
+                false
+            }
+            Ok(Some(Event::Terminated)) => {
+                eprintln!("Received SIGTERM, exiting");
+                true
+            }
+            Ok(None) => return Err("Every sender of events is dropped".to_string()),
+            Err(_) => {
+                if Instant::now() >= next_sample {
+                    next_sample = Instant::now() + hub.sample_interval();
+                    for notification in hub.check_thresholds() {
+                        eprint!("{}", hub.redact(&notification));
                     }
                 }
-                cmd_parser::Commands::Verify { bot_name } => match bot_name {
-                    Some(bot_name) => match bots.get(bot_name) {
-                        Some(bot) => match bot.verify() {
-                            Ok(_) => "some ok\n".to_string(),
-                            Err(err) => format!("some err {}\n", err),
-                        },
-                        None => "none\n".to_string(),
-                    },
-                    None => {
-                        let mut output = String::new();
-                        for (bot_name, bot) in bots {
-                            output.push_str(&match bot.verify() {
-                                Ok(_) => format!("{} ok\n", bot_name),
-                                Err(err) => format!("{} err {}\n", bot_name, err),
-                            });
-                        }
-                        output
+                if Instant::now() >= next_poll {
+                    next_poll = Instant::now() + EXIT_POLL_INTERVAL;
+                    // handled like the exits of watched instances and tasks
+                    for pid in hub.poll_exits() {
+                        let _ = clients.events.send(Event::Exited(pid));
                     }
-                },
-                cmd_parser::Commands::Kill { bot_name } => match bot_instances.get_mut(bot_name) {
-                    Some(Ok(child)) => match child.try_wait().unwrap() {
-                        Some(_) => "started exited\n".to_string(),
-                        None => {
-                            child.kill().unwrap();
-                            "started killed\n".to_string()
-                        }
-                    },
-                    Some(Err(_)) => "failed\n".to_string(),
-                    None => "none\n".to_string(),
-                },
-                cmd_parser::Commands::ControlRestart => {
-                    control_restart(bot_instances)?;
-                    is_restart = true;
-                    "".to_string()
                 }
-                cmd_parser::Commands::Terminate { task_id } => match tasks.get_mut(task_id) {
-                    Some((_, Ok(child))) => match child.try_wait().unwrap() {
-                        Some(_) => "some started exited\n".to_string(),
-                        None => {
-                            child.kill().unwrap();
-                            "some started killed\n".to_string()
-                        }
-                    },
-                    Some((_, Err(_))) => "some failed\n".to_string(),
-                    None => "none\n".to_string(),
-                },
-                cmd_parser::Commands::Conclude { bot_name } => {
-                    match bot_instances.get_mut(bot_name) {
-                        Some(Ok(child)) => match child.try_wait().unwrap() {
-                            Some(status) => {
-                                let mut output = String::new();
-                                output.push_str(&format!(
-                                    "some started exited {}\n",
-                                    status.code().unwrap_or(-1)
-                                ));
-                                let mut child_out = String::new();
-                                child
-                                    .stdout
-                                    .take()
-                                    .unwrap()
-                                    .read_to_string(&mut child_out)
-                                    .unwrap();
-                                child_out.push('\n');
-                                let mut child_err = String::new();
-                                child
-                                    .stderr
-                                    .take()
-                                    .unwrap()
-                                    .read_to_string(&mut child_err)
-                                    .unwrap();
-                                child_err.push('\n');
-                                output.push_str(&format!(
-                                    "{} {} \n",
-                                    child_out.lines().count(),
-                                    child_err.lines().count()
-                                ));
-                                output.push_str(&child_out);
-                                output.push_str(&child_err);
-                                bot_instances.remove(bot_name);
-                                output
-                            }
-                            None => "some started running\n".to_string(),
-                        },
-                        Some(Err(err)) => {
-                            let output = format!("some failed {}\n", err);
-                            bot_instances.remove(bot_name);
-                            output
-                        }
-                        None => "none\n".to_string(),
-                    }
+                finish_waits(hub, clients)?
+            }
+        };
+        if exit {
+            return Ok(());
+        }
+        clients.push_events(hub);
+
+        if !clients.waiting() {
+            if let Some(executable) = hub.take_self_update() {
+                switch_executable(hub, clients, held, &executable);
+                clients.push_events(hub);
+            }
+        }
+    }
+}
+
+/// switches to `executable` built by `self-update`, handing over every bot, `control_bot`, the lock and the listeners,
+/// which returns only if switching fails
+///
+/// connections to the control socket are closed by switching, and have to connect again
+fn switch_executable(hub: &mut Hub, clients: &Clients, held: &Held, executable: &Path) {
+    eprintln!("self-update switching to {}", executable.display());
+    // the previous executable would fail just as well on a invalid bots.toml, leaving nothing to roll back to
+    let checked = match bot_parser::parse_bots() {
+        Ok(_) => self_update::check(executable),
+        Err(_) => Err("bots.toml is invalid".to_string()),
+    };
+    let err = match checked.and_then(|_| hub.handoff()) {
+        Ok(handoff) => {
+            let handoff = self_update::Handoff::new(
+                held.lock,
+                held.control_socket.clone(),
+                held.http,
+                clients.control_bot_handoff(),
+                handoff,
+            );
+            self_update::switch(executable, &handoff)
+        }
+        Err(err) => err,
+    };
+    let notification = hub.self_update_switched(Some(&err));
+    eprint!("{}", hub.redact(&notification));
+}
+
+/// handles a input of a client, returning whether bothub should exit
+///
+/// inputs of a waiting client are queued, except `cancel`
+fn handle_input(
+    hub: &mut Hub,
+    clients: &mut Clients,
+    id: ClientId,
+    input: String,
+) -> Result<bool, String> {
+    // the client might have been removed, e.g. the old control bot after `control-restart`
+    let client = match clients.clients.get_mut(&id) {
+        Some(client) => client,
+        None => return Ok(false),
+    };
+    if client.waiting.is_none() {
+        return execute_input(hub, clients, id, &input);
+    }
+
+    match protocol::parse(client.protocol, &input) {
+        (
+            request_id,
+            Ok(cmd_parser::Cli {
+                command: cmd_parser::Commands::Cancel,
+                json,
+                ..
+            }),
+        ) => {
+            let waiting = client.waiting.take().unwrap();
+            clients.reply(
+                id,
+                &waiting.request,
+                &Output::new(
+                    "some started waiting cancelled\n",
+                    json!({ "task_id": waiting.task_id, "waiting": "cancelled" }),
+                ),
+            );
+            let request = Request {
+                id: request_id,
+                json_output: json,
+            };
+            clients.reply(
+                id,
+                &request,
+                &Output::new("some\n", json!({ "result": "cancelled" })),
+            );
+            execute_queued(hub, clients, id)
+        }
+        _ => {
+            client.queued.push_back(input);
+            Ok(false)
+        }
+    }
+}
+
+/// executes a input of a client that isn't waiting, returning whether bothub should exit
+fn execute_input(
+    hub: &mut Hub,
+    clients: &mut Clients,
+    id: ClientId,
+    input: &str,
+) -> Result<bool, String> {
+    let from_control_bot = clients
+        .control_bot
+        .as_ref()
+        .is_some_and(|(control_bot, _, _)| *control_bot == id);
+    let client = match clients.clients.get_mut(&id) {
+        Some(client) => client,
+        None => return Ok(false),
+    };
+    let (request, command, caller, yes) = match protocol::parse(client.protocol, input) {
+        (id, Ok(cli)) => (
+            Request {
+                id,
+                json_output: cli.json,
+            },
+            cli.command,
+            cli.caller,
+            cli.yes,
+        ),
+        (request_id, Err(err)) => {
+            clients.reply_error(id, &request_id, &hub.redact(&err));
+            return Ok(false);
+        }
+    };
+    // `control_bot` relays commands for whoever talks to it, so it isn't a local client
+    let local = !from_control_bot;
+    let admitted = clients
+        .confirmations
+        .admit(hub, caller.clone(), local, yes, command);
+    let command = match admitted {
+        Ok(command) => command,
+        Err(reply) => {
+            clients.reply(id, &request, &hub.redact_output(&reply));
+            return Ok(false);
+        }
+    };
+    let client = clients.clients.get_mut(&id).unwrap();
+    let reply = match &command {
+        // `subscribe` is allowed on every bot, but only pushes events about the bots the roles allow
+        cmd_parser::Commands::Subscribe { events, bots } => {
+            match hub.scope(caller.as_deref(), local, &["subscribe"], bots) {
+                Ok(bots) => {
+                    client.subscription.subscribe(events, &bots);
+                    Reply::Output(Output::new(
+                        client.subscription.describe(),
+                        client.subscription.json(),
+                    ))
                 }
-                cmd_parser::Commands::Wait { task_id } => match tasks.get_mut(task_id) {
-                    Some((_, Ok(child))) => match child.try_wait().unwrap() {
-                        Some(_) => "some started exited\n".to_string(),
-                        None => {
-                            child.wait().unwrap();
-                            "some started waiting exited\n".to_string()
-                        }
-                    },
-                    Some((_, Err(_))) => "some failed\n".to_string(),
-                    None => "none\n".to_string(),
-                },
-                cmd_parser::Commands::Finish { task_id } => match tasks.get_mut(task_id) {
-                    Some((_, Ok(child))) => match child.try_wait().unwrap() {
-                        Some(status) => {
-                            let mut output = String::new();
-                            output.push_str(&format!(
-                                "some started exited {}\n",
-                                status.code().unwrap_or(-1)
-                            ));
-                            let mut child_out = String::new();
-                            child
-                                .stdout
-                                .take()
-                                .unwrap()
-                                .read_to_string(&mut child_out)
-                                .unwrap();
-                            child_out.push('\n');
-                            let mut child_err = String::new();
-                            child
-                                .stderr
-                                .take()
-                                .unwrap()
-                                .read_to_string(&mut child_err)
-                                .unwrap();
-                            child_err.push('\n');
-                            output.push_str(&format!(
-                                "{} {} \n",
-                                child_out.lines().count(),
-                                child_err.lines().count()
-                            ));
-                            output.push_str(&child_out);
-                            output.push_str(&child_err);
-                            tasks.remove(task_id);
-                            output
-                        }
-                        None => "some started running\n".to_string(),
-                    },
-                    Some((_, Err(_))) => "some failed\n".to_string(),
-                    None => "none\n".to_string(),
-                },
-                cmd_parser::Commands::Exit => {
-                    break;
+                Err(denied) => {
+                    eprint!("{}", hub.redact(&denied.text));
+                    Reply::Output(denied)
                 }
-            },
-            Err(_) => "".to_string(),
-        };
+            }
+        }
+        cmd_parser::Commands::Unsubscribe { events } => {
+            client.subscription.unsubscribe(events);
+            Reply::Output(Output::new(
+                client.subscription.describe(),
+                client.subscription.json(),
+            ))
+        }
+        cmd_parser::Commands::Hello { protocol } => {
+            client.protocol = *protocol;
+            match protocol {
+                Protocol::Text => {
+                    Reply::Output(Output::new("some text\n", json!({ "protocol": "text" })))
+                }
+                Protocol::Json => {
+                    clients.write(id, Message::Line(&protocol::hello(&request.id)));
+                    return Ok(false);
+                }
+            }
+        }
+        command => hub.execute(command),
+    };
 
-        if !is_restart {
-            print_output(&command_output)?;
+    match reply {
+        Reply::Output(output) => clients.reply(id, &request, &hub.redact_output(&output)),
+        Reply::Wait { task_id, timeout } => {
+            if let Some(client) = clients.clients.get_mut(&id) {
+                client.waiting = Some(Waiting {
+                    request,
+                    task_id,
+                    deadline: timeout.map(|timeout| Instant::now() + timeout),
+                });
+            }
+        }
+        Reply::ControlRestart => {
+            let (control_stdout, control_stdin) = hub.restart_control_bot()?;
+            clients.clients.remove(&id);
+            clients.add_control_bot(control_stdout, control_stdin);
+        }
+        Reply::Exit => return Ok(true),
+    }
+    Ok(false)
+}
+
+/// executes the inputs queued while a client was waiting, until another wait begins,
+/// and removes the client if its input is closed and nothing is left to execute
+fn execute_queued(hub: &mut Hub, clients: &mut Clients, id: ClientId) -> Result<bool, String> {
+    while let Some(client) = clients.clients.get_mut(&id) {
+        if client.waiting.is_some() {
+            break;
+        }
+        let input = match client.queued.pop_front() {
+            Some(input) => input,
+            None => {
+                if client.closed {
+                    clients.clients.remove(&id);
+                    eprintln!("A client closed its input");
+                }
+                break;
+            }
+        };
+        if execute_input(hub, clients, id, &input)? {
+            return Ok(true);
         }
-        if parsed.is_err() {
-            print_error(&format!("{}", parsed.unwrap_err()))?;
+    }
+    Ok(false)
+}
+
+/// answers every wait whose task exited or whose timeout passed, returning whether bothub should exit
+fn finish_waits(hub: &mut Hub, clients: &mut Clients) -> Result<bool, String> {
+    let now = Instant::now();
+    let mut finished = Vec::new();
+    for (id, client) in clients.clients.iter_mut() {
+        let waiting = match &client.waiting {
+            Some(waiting) => waiting,
+            None => continue,
+        };
+        let output = match hub.finish_wait(&waiting.task_id) {
+            Some(output) => output,
+            None if waiting.deadline.is_some_and(|deadline| deadline <= now) => Output::new(
+                "some started waiting timeout\n",
+                json!({ "task_id": waiting.task_id, "waiting": "timeout" }),
+            ),
+            None => continue,
+        };
+        let waiting = client.waiting.take().unwrap();
+        finished.push((*id, waiting.request, output));
+    }
+    for (id, request, output) in finished {
+        clients.reply(id, &request, &hub.redact_output(&output));
+        if execute_queued(hub, clients, id)? {
+            return Ok(true);
         }
     }
-    Ok(())
+    Ok(false)
 }
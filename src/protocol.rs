@@ -0,0 +1,227 @@
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::cmd_parser;
+use crate::events::Event;
+
+/// the protocol a client speaks, switched with `hello`
+///
+/// every client starts with `text`, in which `control_bot` is replied with the line count of the output
+/// followed by the output, and `json` is the json-lines protocol, in which every request and reply is a json object in a line
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Text,
+    Json,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Text => "text",
+                Self::Json => "json",
+            }
+        )
+    }
+}
+
+/// the version of the json protocol, bumped on incompatible changes
+pub const JSON_VERSION: u32 = 1;
+
+/// a request in the json protocol, e.g. `{"id": 1, "command": "status bot_a"}`
+#[derive(Deserialize)]
+struct Request {
+    /// echoed back in the reply, `null` if it's missing
+    #[serde(default)]
+    id: Value,
+    /// the same as `--caller`
+    #[serde(default)]
+    caller: Option<String>,
+    command: CommandLine,
+}
+
+/// a command either as a line, or as arguments which may contain whitespace
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CommandLine {
+    Line(String),
+    Args(Vec<String>),
+}
+
+/// parses a input of a client speaking `protocol`,
+/// returning the id of the request (always `null` in `text`) and the command or the description of why it's invalid
+pub fn parse(protocol: Protocol, input: &str) -> (Value, Result<cmd_parser::Cli, String>) {
+    let (id, caller, args) = match protocol {
+        Protocol::Text => (
+            Value::Null,
+            None,
+            input.split_whitespace().map(str::to_string).collect(),
+        ),
+        Protocol::Json => match serde_json::from_str::<Request>(input) {
+            Ok(Request {
+                id,
+                caller,
+                command: CommandLine::Line(line),
+            }) => (
+                id,
+                caller,
+                line.split_whitespace().map(str::to_string).collect(),
+            ),
+            Ok(Request {
+                id,
+                caller,
+                command: CommandLine::Args(args),
+            }) => (id, caller, args),
+            Err(err) => {
+                // the id is still echoed back if the request is a valid json object
+                let id = serde_json::from_str::<Value>(input)
+                    .ok()
+                    .and_then(|request| request.get("id").cloned())
+                    .unwrap_or(Value::Null);
+                return (id, Err(format!("Invalid request, {}\n", err)));
+            }
+        },
+    };
+    let cli = cmd_parser::Cli::try_parse_from(std::iter::once("dcbothub".to_string()).chain(args))
+        .map(|mut cli| {
+            cli.caller = cli.caller.or(caller);
+            cli
+        })
+        .map_err(|err| err.to_string());
+    (id, cli)
+}
+
+/// `{"type": "reply", "id": ..., "output": ..., "result": ...}` in a line,
+/// with `output` being the output in text, and `result` being the output in json
+pub fn reply(id: &Value, output: &str, result: &Value) -> String {
+    line(json!({ "type": "reply", "id": id, "output": output, "result": result }))
+}
+
+/// `{"type": "error", "id": ..., "error": ...}` in a line, for invalid requests
+pub fn error(id: &Value, error: &str) -> String {
+    line(json!({ "type": "error", "id": id, "error": error }))
+}
+
+/// `{"type": "hello", "id": ..., "protocol": "json", "version": ...}` in a line
+pub fn hello(id: &Value) -> String {
+    line(json!({
+        "type": "hello",
+        "id": id,
+        "protocol": Protocol::Json.to_string(),
+        "version": JSON_VERSION,
+    }))
+}
+
+/// `{"type": "event", "event": ..., "bot": ..., "details": ...}` in a line
+pub fn event(event: &Event) -> String {
+    line(json!({
+        "type": "event",
+        "event": event.kind.to_string(),
+        "bot": event.bot_name,
+        "details": event.details.trim_end(),
+    }))
+}
+
+fn line(object: Value) -> String {
+    format!("{}\n", object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cmd_parser::Commands;
+
+    #[test]
+    fn parses_text_input() {
+        let (id, cli) = parse(Protocol::Text, "status  bot_a --json --caller 42\n");
+        let cli = cli.unwrap();
+        assert_eq!(id, Value::Null);
+        assert!(
+            matches!(cli.command, Commands::Status { bot_name, shard: None } if bot_name == "bot_a")
+        );
+        assert!(cli.json);
+        assert_eq!(cli.caller.as_deref(), Some("42"));
+        // a request in json is just a invalid command in text
+        assert!(parse(Protocol::Text, r#"{"command": "list"}"#).1.is_err());
+    }
+
+    #[test]
+    fn parses_json_requests() {
+        let (id, cli) = parse(
+            Protocol::Json,
+            r#"{"id": "a", "caller": "42", "command": "restart bot_a"}"#,
+        );
+        assert_eq!(id, json!("a"));
+        let cli = cli.unwrap();
+        assert!(matches!(cli.command, Commands::Restart { .. }));
+        assert!(!cli.json);
+        assert_eq!(cli.caller.as_deref(), Some("42"));
+
+        // arguments are taken as they are, whitespace included
+        let (id, cli) = parse(
+            Protocol::Json,
+            r#"{"id": 7, "command": ["set-env", "bot_a", "GREETING=hello world"]}"#,
+        );
+        assert_eq!(id, json!(7));
+        assert!(
+            matches!(cli.unwrap().command, Commands::SetEnv { vars, .. } if vars == ["GREETING=hello world"])
+        );
+
+        let (id, cli) = parse(Protocol::Json, r#"{"command": "list"}"#);
+        assert_eq!(id, Value::Null);
+        assert!(matches!(cli.unwrap().command, Commands::List));
+    }
+
+    #[test]
+    fn prefers_the_caller_of_the_command() {
+        let (_, cli) = parse(
+            Protocol::Json,
+            r#"{"caller": "42", "command": "list --caller 7"}"#,
+        );
+        assert_eq!(cli.unwrap().caller.as_deref(), Some("7"));
+        let (_, cli) = parse(Protocol::Json, r#"{"command": "list"}"#);
+        assert_eq!(cli.unwrap().caller, None);
+    }
+
+    #[test]
+    fn echoes_the_id_of_invalid_requests() {
+        let (id, cli) = parse(Protocol::Json, r#"{"id": 3, "command": 1}"#);
+        assert_eq!(id, json!(3));
+        assert!(cli.unwrap_err().starts_with("Invalid request, "));
+        let (id, cli) = parse(Protocol::Json, r#"{"id": 4, "command": "frobnicate"}"#);
+        assert_eq!(id, json!(4));
+        assert!(cli.is_err());
+        let (id, cli) = parse(Protocol::Json, "status bot_a");
+        assert_eq!(id, Value::Null);
+        assert!(cli.unwrap_err().starts_with("Invalid request, "));
+    }
+
+    #[test]
+    fn writes_a_object_in_a_line() {
+        let written = reply(
+            &json!(1),
+            "some saved\nsecond line\n",
+            &json!({ "result": "saved" }),
+        );
+        assert!(written.ends_with('\n'));
+        assert_eq!(written.lines().count(), 1);
+        assert_eq!(
+            serde_json::from_str::<Value>(&written).unwrap(),
+            json!({
+                "type": "reply",
+                "id": 1,
+                "output": "some saved\nsecond line\n",
+                "result": { "result": "saved" },
+            })
+        );
+        assert_eq!(
+            serde_json::from_str::<Value>(&error(&Value::Null, "Invalid request")).unwrap(),
+            json!({ "type": "error", "id": null, "error": "Invalid request" })
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use std::io::{BufRead, Write};
+use std::sync::{Arc, RwLock};
+
+/// what every secret is replaced with in outputs
+pub const REDACTED: &str = "[REDACTED]";
+
+/// secrets shorter than this are not redacted, since scrubbing them would mangle unrelated output
+pub const MIN_SECRET_LEN: usize = 4;
+
+/// the variable `dcbothub redact-log` is given the secrets to scrub in, as a json array of strings
+pub const SECRETS_VAR: &str = "DCBOTHUB_SECRETS";
+
+/// scrubs configured secrets, and anything shaped like a discord token, out of text
+///
+/// cloning a `Redactor` is cheap, and clones share the same set of secrets
+#[derive(Clone, Debug)]
+pub struct Redactor {
+    secrets: Arc<RwLock<Vec<String>>>,
+    token_pattern: regex::Regex,
+}
+
+impl Redactor {
+    pub fn new() -> Redactor {
+        Redactor {
+            secrets: Arc::new(RwLock::new(Vec::new())),
+            token_pattern: regex::Regex::new(
+                r"[A-Za-z0-9_-]{23,28}\.[A-Za-z0-9_-]{6,7}\.[A-Za-z0-9_-]{27,}",
+            )
+            .unwrap(),
+        }
+    }
+
+    /// creates a `Redactor` with the secrets of every given bot
+    pub fn from_bots(bots: &crate::Bots) -> Redactor {
+        let redactor = Redactor::new();
+        for bot in bots.values() {
+            for secret in bot.secrets() {
+                redactor.add_secret(secret);
+            }
+        }
+        redactor
+    }
+
+    pub fn add_secret(&self, secret: &str) {
+        if secret.len() < MIN_SECRET_LEN {
+            return;
+        }
+        let mut secrets = self.secrets.write().unwrap();
+        if !secrets.iter().any(|known| known == secret) {
+            secrets.push(secret.to_string());
+            // longer secrets go first so a secret containing another one is scrubbed as a whole
+            secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        }
+    }
+
+    /// every secret scrubbed, longest first
+    pub fn secrets(&self) -> Vec<String> {
+        self.secrets.read().unwrap().clone()
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for secret in self.secrets.read().unwrap().iter() {
+            if text.contains(secret.as_str()) {
+                text = text.replace(secret.as_str(), REDACTED);
+            }
+        }
+        self.token_pattern.replace_all(&text, REDACTED).into_owned()
+    }
+
+    /// redacts every string in a json value, keys included
+    pub fn redact_json(&self, value: &serde_json::Value) -> serde_json::Value {
+        use serde_json::Value;
+
+        match value {
+            Value::String(text) => Value::String(self.redact(text)),
+            Value::Array(items) => {
+                Value::Array(items.iter().map(|item| self.redact_json(item)).collect())
+            }
+            Value::Object(object) => Value::Object(
+                object
+                    .iter()
+                    .map(|(key, value)| (self.redact(key), self.redact_json(value)))
+                    .collect(),
+            ),
+            value => value.clone(),
+        }
+    }
+}
+
+/// copies `input` to `output` a line at a time, redacting every line,
+/// which is how the output of a detached bot reaches its log files
+///
+/// bytes which aren't valid UTF-8 are replaced, like any other output of bothub
+pub fn redact_lines(
+    mut input: impl BufRead,
+    mut output: impl Write,
+    redactor: &Redactor,
+) -> std::io::Result<()> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        output.write_all(redactor.redact(&String::from_utf8_lossy(&line)).as_bytes())?;
+        output.flush()?;
+    }
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Redactor::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "MTA0NjU0MjMyNTQ4Nzg3NDE5MQ.BotTok.EN3Sh4rD3dB0tsRuN0n3Pr0c3sSP3rSh4rd";
+
+    #[test]
+    fn redacts_tokens_without_secrets() {
+        let redactor = Redactor::new();
+        assert_eq!(redactor.redact(TOKEN), REDACTED);
+        assert_eq!(
+            redactor.redact(&format!("logging in with \"{}\"\n", TOKEN)),
+            format!("logging in with \"{}\"\n", REDACTED)
+        );
+    }
+
+    #[test]
+    fn keeps_what_only_looks_like_a_token() {
+        let redactor = Redactor::new();
+        for text in [
+            "version 1.2.3",
+            "bot_a.shard_0.log",
+            // each with a part one character too short
+            "MTA0NjU0MjMyNTQ4Nzg3ND.BotTok.EN3Sh4rD3dB0tsRuN0n3Pr0c3sSP3rSh4rd",
+            "MTA0NjU0MjMyNTQ4Nzg3NDE5MQ.BotTo.EN3Sh4rD3dB0tsRuN0n3Pr0c3sSP3rSh4rd",
+            "MTA0NjU0MjMyNTQ4Nzg3NDE5MQ.BotTok.EN3Sh4rD3dB0tsRuN0n3Pr0c3s",
+            // a part split by a character no token contains
+            "MTA0NjU0MjMyNTQ4Nzg3NDE5MQ.Bot+ok.EN3Sh4rD3dB0tsRuN0n3Pr0c3sSP3rSh4rd",
+        ] {
+            assert_eq!(redactor.redact(text), text);
+        }
+    }
+
+    #[test]
+    fn redacts_secrets_from_min_secret_len() {
+        let redactor = Redactor::new();
+        let short = "x".repeat(MIN_SECRET_LEN - 1);
+        let long = "y".repeat(MIN_SECRET_LEN);
+        redactor.add_secret(&short);
+        redactor.add_secret(&long);
+        assert_eq!(
+            redactor.redact(&format!("{} {}", short, long)),
+            format!("{} {}", short, REDACTED)
+        );
+    }
+
+    #[test]
+    fn redacts_a_secret_containing_another_as_a_whole() {
+        let redactor = Redactor::new();
+        redactor.add_secret("hunter2");
+        redactor.add_secret("hunter22hunter");
+        assert_eq!(
+            redactor.redact("hunter22hunter hunter2"),
+            "[REDACTED] [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn clones_share_secrets() {
+        let redactor = Redactor::new();
+        let clone = redactor.clone();
+        redactor.add_secret("hunter2");
+        assert_eq!(clone.redact("hunter2"), REDACTED);
+    }
+
+    #[test]
+    fn redacts_every_line_copied() {
+        let redactor = Redactor::new();
+        redactor.add_secret("hunter2");
+        let mut input = format!("login {}\nhunter2 ok\n", TOKEN).into_bytes();
+        input.extend_from_slice(b"\xff\nno newline");
+        let mut output = Vec::new();
+        redact_lines(input.as_slice(), &mut output, &redactor).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "login [REDACTED]\n[REDACTED] ok\n\u{fffd}\nno newline"
+        );
+    }
+
+    #[test]
+    fn redacts_keys_and_values_of_json() {
+        let redactor = Redactor::new();
+        redactor.add_secret("hunter2");
+        let value = serde_json::json!({ "hunter2": ["hunter2", 2, { "token": TOKEN }] });
+        assert_eq!(
+            redactor.redact_json(&value),
+            serde_json::json!({ REDACTED: [REDACTED, 2, { "token": REDACTED }] })
+        );
+    }
+}
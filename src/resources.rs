@@ -0,0 +1,183 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::InstanceKey;
+
+/// resource usage of a process tree, sampled from /proc
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+    /// resident set size in bytes
+    pub rss: u64,
+    /// user and system cpu time
+    pub cpu_time: Duration,
+    pub threads: u64,
+    pub open_files: u64,
+    pub processes: u64,
+}
+
+impl Usage {
+    pub fn rss_mb(&self) -> u64 {
+        self.rss / 1024 / 1024
+    }
+}
+
+/// the pids of every process running at the moment, along with their parent pids
+fn list_processes() -> HashMap<u32, u32> {
+    let mut processes = HashMap::new();
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return processes,
+    };
+    for entry in entries.flatten() {
+        let pid = match entry.file_name().to_str().and_then(|pid| pid.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if let Some(stat) = read_stat(pid) {
+            processes.insert(pid, stat.ppid);
+        }
+    }
+    processes
+}
+
+struct Stat {
+    state: char,
+    ppid: u32,
+    /// utime + stime in clock ticks
+    cpu_ticks: u64,
+    threads: u64,
+    /// resident set size in pages
+    rss_pages: u64,
+    /// when the process started, in clock ticks since boot
+    start_time: u64,
+}
+
+/// parses /proc/PID/stat, see proc(5) for the fields
+fn read_stat(pid: u32) -> Option<Stat> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name might contain whitespace and parentheses, so only parse after the last `)`
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // fields[0] is the 3rd field (state) of proc(5)
+    let field = |n: usize| -> Option<u64> { fields.get(n - 3)?.parse().ok() };
+    Some(Stat {
+        state: fields.first()?.chars().next()?,
+        ppid: field(4)? as u32,
+        cpu_ticks: field(14)? + field(15)?,
+        threads: field(20)?,
+        rss_pages: field(24)?,
+        start_time: field(22)?,
+    })
+}
+
+/// when a process started, in clock ticks since boot, which tells it apart from a later process reusing its pid
+///
+/// returns `None` if the process doesn't exist or has exited (but isn't reaped yet)
+pub fn start_time(pid: u32) -> Option<u64> {
+    read_stat(pid)
+        .filter(|stat| stat.state != 'Z' && stat.state != 'X')
+        .map(|stat| stat.start_time)
+}
+
+/// samples the resource usage of a process and all of its descendants
+///
+/// returns `None` if the process doesn't exist anymore
+pub fn sample(pid: u32) -> Option<Usage> {
+    read_stat(pid)?;
+    // sysconf can't fail with these names
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+
+    let processes = list_processes();
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(
+            processes
+                .iter()
+                .filter(|(_, ppid)| **ppid == parent)
+                .map(|(pid, _)| *pid),
+        );
+        i += 1;
+    }
+
+    let mut usage = Usage::default();
+    let mut cpu_ticks = 0;
+    for pid in &tree {
+        // the process might have exited in the meantime
+        let stat = match read_stat(*pid) {
+            Some(stat) => stat,
+            None => continue,
+        };
+        cpu_ticks += stat.cpu_ticks;
+        usage.threads += stat.threads;
+        usage.rss += stat.rss_pages * page_size;
+        usage.processes += 1;
+        usage.open_files +=
+            std::fs::read_dir(format!("/proc/{}/fd", pid)).map_or(0, |fds| fds.count() as u64);
+    }
+    usage.cpu_time = Duration::from_millis(cpu_ticks * 1000 / ticks_per_second);
+    Some(usage)
+}
+
+/// the latest sample of every instance, used to calculate the cpu usage between samples
+#[derive(Debug, Default)]
+pub struct Monitor {
+    samples: HashMap<InstanceKey, (u32, Instant, Usage)>,
+    /// instances exceeding their thresholds on the latest sample
+    exceeding: HashSet<InstanceKey>,
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor::default()
+    }
+
+    /// samples a instance running as `pid`, returning the usage,
+    /// and the cpu usage in percent since the last sample of the same process if there is one
+    pub fn sample(&mut self, key: &InstanceKey, pid: u32) -> Option<(Usage, Option<f64>)> {
+        let usage = match sample(pid) {
+            Some(usage) => usage,
+            None => {
+                self.samples.remove(key);
+                return None;
+            }
+        };
+        let now = Instant::now();
+        let cpu_percent = self
+            .samples
+            .get(key)
+            .filter(|(last_pid, _, _)| *last_pid == pid)
+            .and_then(|(_, last_at, last)| {
+                let elapsed = now.duration_since(*last_at).as_secs_f64();
+                let cpu_time = usage.cpu_time.checked_sub(last.cpu_time)?;
+                (elapsed > 0.0).then(|| cpu_time.as_secs_f64() / elapsed * 100.0)
+            });
+        self.samples.insert(key.clone(), (pid, now, usage));
+        Some((usage, cpu_percent))
+    }
+
+    /// remembers whether a instance exceeds its thresholds,
+    /// returning true if it didn't on the previous check, so every excess is only reported once
+    pub fn set_exceeding(&mut self, key: &InstanceKey, exceeding: bool) -> bool {
+        if exceeding {
+            self.exceeding.insert(key.clone())
+        } else {
+            self.exceeding.remove(key);
+            false
+        }
+    }
+}
+
+/// `rss` *RSS*`MB` `cpu` *CPUTime* (*CPUPercent*`%`|`-`) `threads` *Threads* `files` *OpenFiles* `processes` *Processes*
+pub fn describe(usage: &Usage, cpu_percent: Option<f64>) -> String {
+    format!(
+        "rss {}MB cpu {} {} threads {} files {} processes {}",
+        usage.rss_mb(),
+        crate::instance::format_uptime(usage.cpu_time),
+        cpu_percent.map_or_else(|| "-".to_string(), |percent| format!("{:.1}%", percent)),
+        usage.threads,
+        usage.open_files,
+        usage.processes
+    )
+}
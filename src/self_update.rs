@@ -0,0 +1,257 @@
+use std::os::fd::RawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::Subscription;
+use crate::protocol::Protocol;
+
+/// the file everything handed to the new executable is written to
+pub const HANDOFF_FILE: &str = "dcbothub.handoff";
+/// a copy of the executable switched away from, which is switched back to if the new one fails
+pub const PREVIOUS_EXECUTABLE: &str = "dcbothub.previous";
+/// set to the path of `HANDOFF_FILE` for the executable switched to
+const HANDOFF_ENV: &str = "DCBOTHUB_HANDOFF";
+/// set to why the new executable failed for the previous executable switched back to
+const ROLLBACK_ENV: &str = "DCBOTHUB_ROLLBACK";
+/// bumped whenever `Handoff` changes, so a executable never resumes a handoff it can't understand
+const HANDOFF_VERSION: u32 = 1;
+
+/// `control_bot` as it's handed to the new executable, which keeps talking to it through the same pipes
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControlBot {
+    pub stdout: RawFd,
+    pub stdin: RawFd,
+    pub protocol: Protocol,
+    pub subscription: Subscription,
+}
+
+/// everything handed to the new executable
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handoff {
+    version: u32,
+    /// the lock file held by bothub
+    pub lock: RawFd,
+    pub control_socket: Option<(PathBuf, RawFd)>,
+    /// the listener of the HTTP API, missing from handoffs of executables before it
+    #[serde(default)]
+    pub http: Option<RawFd>,
+    pub control_bot: Option<ControlBot>,
+    pub hub: crate::hub::Handoff,
+}
+
+impl Handoff {
+    pub fn new(
+        lock: RawFd,
+        control_socket: Option<(PathBuf, RawFd)>,
+        http: Option<RawFd>,
+        control_bot: Option<ControlBot>,
+        hub: crate::hub::Handoff,
+    ) -> Handoff {
+        Handoff {
+            version: HANDOFF_VERSION,
+            lock,
+            control_socket,
+            http,
+            control_bot,
+            hub,
+        }
+    }
+
+    /// every file descriptor handed over, which must be kept open across `exec`
+    fn fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.lock];
+        fds.extend(self.control_socket.iter().map(|(_, fd)| *fd));
+        fds.extend(self.http);
+        fds.extend(
+            self.control_bot
+                .iter()
+                .flat_map(|control_bot| [control_bot.stdout, control_bot.stdin]),
+        );
+        fds.extend(self.hub.fds());
+        fds
+    }
+}
+
+/// checks the new executable can run at all, by running it with `--version`
+pub fn check(executable: &Path) -> Result<(), String> {
+    let output = std::process::Command::new(executable)
+        .arg("--version")
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|err| format!("Failed running {}, {}", executable.display(), err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} --version {}",
+            executable.display(),
+            crate::instance::exit_description(&output.status)
+        ));
+    }
+    Ok(())
+}
+
+/// switches to `executable` with the same arguments, handing `handoff` to it
+///
+/// the running executable is copied to `PREVIOUS_EXECUTABLE` first, so it can be switched back to;
+/// returns only if switching fails, with the description of how, leaving bothub as it was
+pub fn switch(executable: &Path, handoff: &Handoff) -> String {
+    let previous = format!("{}.tmp", PREVIOUS_EXECUTABLE);
+    // copied from /proc, which works even if the file bothub runs from is replaced by the build,
+    // and renamed so the copy bothub may be running from isn't written to
+    if let Err(err) = std::fs::copy("/proc/self/exe", &previous)
+        .and_then(|_| std::fs::rename(&previous, PREVIOUS_EXECUTABLE))
+    {
+        return format!(
+            "Failed copying the executable to {}, {}",
+            PREVIOUS_EXECUTABLE, err
+        );
+    }
+    if let Err(err) = std::fs::write(HANDOFF_FILE, serde_json::to_string(handoff).unwrap()) {
+        return format!("Failed writing {}, {}", HANDOFF_FILE, err);
+    }
+    let err = exec(executable, handoff, &[(HANDOFF_ENV, HANDOFF_FILE)]);
+    let _ = std::fs::remove_file(HANDOFF_FILE);
+    err
+}
+
+/// execs `executable` with the same arguments and `envs`, keeping the file descriptors of `handoff` open,
+/// returning the description of how it failed
+fn exec(executable: &Path, handoff: &Handoff, envs: &[(&str, &str)]) -> String {
+    use std::io::Write;
+
+    // anything buffered would be lost
+    let _ = std::io::stdout().flush();
+    let fds = handoff.fds();
+    let err = match fds.iter().try_for_each(|fd| set_inheritable(*fd, true)) {
+        Ok(()) => std::process::Command::new(executable)
+            .args(std::env::args_os().skip(1))
+            .envs(envs.iter().copied())
+            .exec(),
+        Err(err) => err,
+    };
+    for fd in fds {
+        let _ = set_inheritable(fd, false);
+    }
+    format!("Failed executing {}, {}", executable.display(), err)
+}
+
+/// sets whether `fd` is kept open across `exec`
+fn set_inheritable(fd: RawFd, inheritable: bool) -> std::io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let flags = match inheritable {
+        true => flags & !libc::FD_CLOEXEC,
+        false => flags | libc::FD_CLOEXEC,
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// a handoff from the executable switched away from, or switched back from
+pub struct Resumed {
+    pub handoff: Handoff,
+    /// why the new executable failed, if this is the previous executable switched back to
+    pub rolled_back: Option<String>,
+}
+
+/// takes the handoff bothub is started with, if it's started by `self-update`,
+/// switching back to the previous executable if the handoff can't be read
+///
+/// the environment variables telling about it are removed, so nothing bothub starts inherits them
+pub fn take() -> Option<Resumed> {
+    let path = std::env::var_os(HANDOFF_ENV)?;
+    let rolled_back = std::env::var(ROLLBACK_ENV).ok();
+    std::env::remove_var(HANDOFF_ENV);
+    std::env::remove_var(ROLLBACK_ENV);
+
+    let path = Path::new(&path);
+    let handoff = std::fs::read_to_string(path)
+        .map_err(|err| format!("Failed reading {}, {}", path.display(), err))
+        .and_then(|handoff| {
+            let handoff = serde_json::from_str::<serde_json::Value>(&handoff)
+                .map_err(|err| format!("Failed parsing {}, {}", path.display(), err))?;
+            // checked first, since a handoff of another version might not even parse
+            if handoff["version"] != HANDOFF_VERSION {
+                return Err(format!(
+                    "handoff version {} isn't supported",
+                    handoff["version"]
+                ));
+            }
+            serde_json::from_value::<Handoff>(handoff)
+                .map_err(|err| format!("Failed parsing {}, {}", path.display(), err))
+        });
+    match handoff {
+        Ok(handoff) => {
+            // so nothing started from now on inherits them, until they're handed over again
+            for fd in handoff.fds() {
+                let _ = set_inheritable(fd, false);
+            }
+            Some(Resumed {
+                handoff,
+                rolled_back,
+            })
+        }
+        Err(err) => match rolled_back {
+            // the previous executable can't read it either, so there is nothing left to switch to
+            Some(_) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            None => roll_back_unread(&err),
+        },
+    }
+}
+
+/// removes the handoff file once the handoff is resumed
+pub fn finish() {
+    if let Err(err) = std::fs::remove_file(HANDOFF_FILE) {
+        eprintln!("Failed removing {}, {}", HANDOFF_FILE, err);
+    }
+}
+
+/// switches back to the previous executable, handing it the same handoff, because of `reason`
+///
+/// if this is already the previous executable, or switching back fails, there is nothing left to switch to,
+/// so bothub exits and every bot is left running without it
+pub fn roll_back(resumed: &Resumed, reason: &str) -> ! {
+    if resumed.rolled_back.is_some() {
+        eprintln!("Failed resuming after self-update, {}", reason);
+        std::process::exit(1);
+    }
+    eprintln!(
+        "Failed resuming after self-update, rolling back, {}",
+        reason
+    );
+    let err = exec(
+        &previous_executable(),
+        &resumed.handoff,
+        &[(HANDOFF_ENV, HANDOFF_FILE), (ROLLBACK_ENV, reason)],
+    );
+    eprintln!("{}", err);
+    std::process::exit(1);
+}
+
+/// same as `roll_back` for a handoff that can't be read, the file descriptors of which are still inherited
+fn roll_back_unread(reason: &str) -> ! {
+    eprintln!(
+        "Failed resuming after self-update, rolling back, {}",
+        reason
+    );
+    let err = std::process::Command::new(previous_executable())
+        .args(std::env::args_os().skip(1))
+        .env(HANDOFF_ENV, HANDOFF_FILE)
+        .env(ROLLBACK_ENV, reason)
+        .exec();
+    eprintln!("Failed executing {}, {}", PREVIOUS_EXECUTABLE, err);
+    std::process::exit(1);
+}
+
+/// `PREVIOUS_EXECUTABLE` in the working directory, which `exec` would otherwise look up in `PATH`
+fn previous_executable() -> PathBuf {
+    Path::new(".").join(PREVIOUS_EXECUTABLE)
+}
@@ -0,0 +1,51 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+/// a detached instance recorded in the state file, for the next bothub to adopt
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DetachedInstance {
+    pub bot: String,
+    pub shard: Option<u32>,
+    pub pid: u32,
+    /// when the process started in clock ticks since boot, see `resources::start_time`
+    pub start_time: u64,
+    /// when the instance started in seconds since the unix epoch
+    pub started_at: u64,
+    pub start_reason: String,
+    pub commit: Option<String>,
+}
+
+impl DetachedInstance {
+    pub fn started_at(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(self.started_at)
+    }
+}
+
+/// the content of the state file
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct State {
+    pub instances: Vec<DetachedInstance>,
+}
+
+impl State {
+    /// reads the state file, which is empty if it doesn't exist
+    pub fn load(path: &Path) -> Result<State, String> {
+        match std::fs::read_to_string(path) {
+            Ok(state) => serde_json::from_str(&state)
+                .map_err(|err| format!("Failed parsing {}, {}", path.display(), err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(err) => Err(format!("Failed reading {}, {}", path.display(), err)),
+        }
+    }
+
+    /// writes the state file through a temporary file, so it's never left half written
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        std::fs::write(&temp_path, serde_json::to_string_pretty(self).unwrap())
+            .and_then(|_| std::fs::rename(&temp_path, path))
+            .map_err(|err| format!("Failed writing {}, {}", path.display(), err))
+    }
+}
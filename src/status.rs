@@ -0,0 +1,184 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::instance::{signal_name, Exit, Instance};
+use crate::limits::Limits;
+use crate::InstanceKey;
+
+/// what state a instance or task is in
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    /// the instance or task doesn't exist
+    None,
+    Running,
+    Exited,
+    /// the instance or task failed to start
+    Failed,
+}
+
+/// the status of a instance or task, shared by the outputs of every command about them
+///
+/// fields which don't apply, such as `exit_code` of a running instance, are `null` in json
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub bot: Option<String>,
+    pub shard: Option<u32>,
+    pub task_id: Option<String>,
+    pub state: State,
+    pub pid: Option<u32>,
+    /// -1 if the process is terminated by a signal, `null` if the exit status of a adopted instance is unknown
+    pub exit_code: Option<i32>,
+    pub signal: Option<String>,
+    /// the key in `limits` the instance most likely died from hitting
+    pub limit: Option<&'static str>,
+    /// the description of how the instance or task failed to start
+    pub error: Option<String>,
+}
+
+impl Status {
+    pub fn none() -> Status {
+        Status {
+            bot: None,
+            shard: None,
+            task_id: None,
+            state: State::None,
+            pid: None,
+            exit_code: None,
+            signal: None,
+            limit: None,
+            error: None,
+        }
+    }
+
+    /// the status of a instance, along with the limit it most likely hit if `limits` is given
+    pub fn of_instance(
+        (bot_name, shard): &InstanceKey,
+        instance: &mut Result<Instance, String>,
+        limits: Option<&Limits>,
+    ) -> Status {
+        let mut status = Status {
+            bot: Some(bot_name.clone()),
+            shard: *shard,
+            ..Status::none()
+        };
+        match instance {
+            Ok(instance) => {
+                status.pid = Some(instance.pid());
+                match instance.try_wait().unwrap() {
+                    Some(exit) => {
+                        status.set_exit(&exit);
+                        status.limit =
+                            limits.zip(exit.status()).and_then(|(limits, exit_status)| {
+                                limits.hit_by(exit_status, &instance.output().1)
+                            });
+                    }
+                    None => status.state = State::Running,
+                }
+            }
+            Err(err) => status.set_failed(err),
+        }
+        status
+    }
+
+    /// the status of a task of a bot
+    pub fn of_task(
+        task_id: &str,
+        bot_name: &str,
+        task: &mut Result<crate::Task, String>,
+    ) -> Status {
+        let mut status = Status {
+            bot: Some(bot_name.to_string()),
+            task_id: Some(task_id.to_string()),
+            ..Status::none()
+        };
+        match task {
+            Ok(task) => {
+                status.pid = Some(task.child.id());
+                match task.child.try_wait().unwrap() {
+                    Some(exit_status) => status.set_exited(&exit_status),
+                    None => status.state = State::Running,
+                }
+            }
+            Err(err) => status.set_failed(err),
+        }
+        status
+    }
+
+    pub fn set_exited(&mut self, exit_status: &std::process::ExitStatus) {
+        use std::os::unix::process::ExitStatusExt;
+
+        self.state = State::Exited;
+        self.exit_code = Some(exit_status.code().unwrap_or(-1));
+        self.signal = exit_status.signal().map(signal_name);
+    }
+
+    /// same as `set_exited`, but the exit code is `null` if the exit status is unknown
+    pub fn set_exit(&mut self, exit: &Exit) {
+        match exit {
+            Exit::Status(exit_status) => self.set_exited(exit_status),
+            Exit::Unknown => self.state = State::Exited,
+        }
+    }
+
+    pub fn set_failed(&mut self, error: &str) {
+        self.state = State::Failed;
+        self.error = Some(error.to_string());
+    }
+
+    /// `none`|(`started` (`running`|(`exited` (*ExitCode*|`unknown`) [`signal` *SignalName*] [`limit` *LimitName*])))|(`failed` *FailureDescription*)
+    pub fn text(&self) -> String {
+        match self.state {
+            State::None => "none".to_string(),
+            State::Running => "started running".to_string(),
+            State::Exited => {
+                let mut output = match self.exit_code {
+                    Some(exit_code) => format!("started exited {}", exit_code),
+                    None => "started exited unknown".to_string(),
+                };
+                if let Some(signal) = &self.signal {
+                    output.push_str(&format!(" signal {}", signal));
+                }
+                if let Some(limit) = self.limit {
+                    output.push_str(&format!(" limit {}", limit));
+                }
+                output
+            }
+            State::Failed => format!("failed {}", self.error.as_deref().unwrap_or_default()),
+        }
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+
+    /// the status in json, with the fields of `extra` (which should be a object) added
+    pub fn json_with(&self, extra: Value) -> Value {
+        let mut json = self.json();
+        if let (Value::Object(json), Value::Object(extra)) = (&mut json, extra) {
+            json.extend(extra);
+        }
+        json
+    }
+}
+
+/// the state of the status of a instance or a task in json, with how it exited or failed, as the dashboards show it
+pub fn describe(status: &Value) -> String {
+    let mut state = text(&status["state"]);
+    for field in ["exit_code", "signal", "limit", "error"] {
+        if !status[field].is_null() {
+            state.push(' ');
+            state.push_str(&text(&status[field]));
+        }
+    }
+    state
+}
+
+/// a json value as text, which is empty for `null`
+pub fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+
+use serde::Serialize;
+
+use crate::events::{Event, EventKind};
+
+/// how many items are kept for clients resuming the stream, older items are dropped
+pub const MAX_STREAM_ITEMS: usize = 10000;
+
+/// the kinds of items in the stream, every kind of events along with the output of instances and tasks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Event(EventKind),
+    Stdout,
+    Stderr,
+    TaskStdout,
+    TaskStderr,
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Event(kind) => write!(f, "{}", kind),
+            Self::Stdout => write!(f, "stdout"),
+            Self::Stderr => write!(f, "stderr"),
+            Self::TaskStdout => write!(f, "task-stdout"),
+            Self::TaskStderr => write!(f, "task-stderr"),
+        }
+    }
+}
+
+impl std::str::FromStr for Kind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Kind, String> {
+        let kinds = [
+            Kind::Stdout,
+            Kind::Stderr,
+            Kind::TaskStdout,
+            Kind::TaskStderr,
+        ];
+        EventKind::ALL
+            .into_iter()
+            .map(Kind::Event)
+            .chain(kinds)
+            .find(|known| known.to_string() == kind)
+            .ok_or_else(|| format!("{} isn't a kind of stream items", kind))
+    }
+}
+
+impl Serialize for Kind {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// a event or a line of output, numbered in the order it happened
+#[derive(Serialize, Debug, Clone)]
+pub struct Item {
+    pub seq: u64,
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    pub bot: String,
+    /// the shard of the instance a line of output is from
+    pub shard: Option<u32>,
+    /// the task a line of output is from
+    pub task_id: Option<String>,
+    /// the line of output, or the details of the event
+    pub data: String,
+}
+
+/// the items a client asks for, which is everything if a set is empty
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    pub kinds: HashSet<Kind>,
+    pub bots: HashSet<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, item: &Item) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&item.kind))
+            && (self.bots.is_empty() || self.bots.contains(&item.bot))
+    }
+}
+
+/// items read after a sequence number
+#[derive(Debug)]
+pub struct Read {
+    /// how many items after the sequence number were dropped before they could be read
+    pub lost: u64,
+    pub items: Vec<Item>,
+}
+
+#[derive(Debug)]
+struct Items {
+    next_seq: u64,
+    items: VecDeque<Item>,
+}
+
+/// every event and line of output happened in bothub, which clients read as it happens
+///
+/// cloning a `Stream` is cheap, and clones share the same items
+#[derive(Debug, Clone)]
+pub struct Stream {
+    items: Arc<(Mutex<Items>, Condvar)>,
+}
+
+impl Stream {
+    /// a stream the first item of which is numbered `next_seq`, which starts at 1
+    pub fn new(next_seq: u64) -> Stream {
+        Stream {
+            items: Arc::new((
+                Mutex::new(Items {
+                    next_seq: next_seq.max(1),
+                    items: VecDeque::new(),
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    /// the sequence number the next item is numbered
+    pub fn next_seq(&self) -> u64 {
+        self.items.0.lock().unwrap().next_seq
+    }
+
+    pub fn publish(
+        &self,
+        kind: Kind,
+        bot: &str,
+        shard: Option<u32>,
+        task_id: Option<&str>,
+        data: String,
+    ) {
+        let (items, published) = &*self.items;
+        let mut items = items.lock().unwrap();
+        let item = Item {
+            seq: items.next_seq,
+            kind,
+            bot: bot.to_string(),
+            shard,
+            task_id: task_id.map(str::to_string),
+            data,
+        };
+        items.next_seq += 1;
+        if items.items.len() >= MAX_STREAM_ITEMS {
+            items.items.pop_front();
+        }
+        items.items.push_back(item);
+        published.notify_all();
+    }
+
+    pub fn publish_event(&self, event: &Event) {
+        self.publish(
+            Kind::Event(event.kind),
+            &event.bot_name,
+            None,
+            None,
+            event.details.trim_end().to_string(),
+        );
+    }
+
+    /// reads every item numbered after `after`, waiting at most `timeout` for one if there is none yet
+    pub fn read(&self, after: u64, timeout: std::time::Duration) -> Read {
+        let (items, published) = &*self.items;
+        let items = items.lock().unwrap();
+        let (items, _) = published
+            .wait_timeout_while(items, timeout, |items| items.next_seq <= after + 1)
+            .unwrap();
+        let first = items.items.front().map_or(items.next_seq, |item| item.seq);
+        Read {
+            lost: first.saturating_sub(after + 1),
+            items: items
+                .items
+                .iter()
+                .filter(|item| item.seq > after)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// publishes the lines of output of a instance or a task to a `Stream`
+#[derive(Debug, Clone)]
+pub struct Tap {
+    stream: Stream,
+    bot: String,
+    shard: Option<u32>,
+    task_id: Option<String>,
+    stderr: bool,
+}
+
+impl Tap {
+    /// publishes stdout of the instance `key`
+    pub fn instance(stream: &Stream, (bot, shard): &crate::InstanceKey) -> Tap {
+        Tap {
+            stream: stream.clone(),
+            bot: bot.clone(),
+            shard: *shard,
+            task_id: None,
+            stderr: false,
+        }
+    }
+
+    /// publishes stdout of the task `task_id`
+    pub fn task(stream: &Stream, bot: &str, task_id: &str) -> Tap {
+        Tap {
+            stream: stream.clone(),
+            bot: bot.to_string(),
+            shard: None,
+            task_id: Some(task_id.to_string()),
+            stderr: false,
+        }
+    }
+
+    /// the same tap for stderr
+    pub fn stderr(&self) -> Tap {
+        Tap {
+            stderr: true,
+            ..self.clone()
+        }
+    }
+
+    pub fn publish(&self, line: &str) {
+        let kind = match (&self.task_id, self.stderr) {
+            (None, false) => Kind::Stdout,
+            (None, true) => Kind::Stderr,
+            (Some(_), false) => Kind::TaskStdout,
+            (Some(_), true) => Kind::TaskStderr,
+        };
+        self.stream.publish(
+            kind,
+            &self.bot,
+            self.shard,
+            self.task_id.as_deref(),
+            line.to_string(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn stream_of(lines: u64) -> Stream {
+        let stream = Stream::new(1);
+        for line in 1..=lines {
+            stream.publish(Kind::Stdout, "bot_a", None, None, line.to_string());
+        }
+        stream
+    }
+
+    fn data(read: &Read) -> Vec<&str> {
+        read.items.iter().map(|item| item.data.as_str()).collect()
+    }
+
+    #[test]
+    fn resumes_after_a_sequence_number() {
+        let stream = stream_of(5);
+        let read = stream.read(2, Duration::ZERO);
+        assert_eq!(read.lost, 0);
+        assert_eq!(data(&read), ["3", "4", "5"]);
+        assert_eq!(read.items[0].seq, 3);
+        // nothing after the last item, even after waiting
+        let read = stream.read(5, Duration::from_millis(10));
+        assert_eq!(read.lost, 0);
+        assert!(read.items.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_next_item() {
+        let stream = stream_of(1);
+        let publisher = stream.clone();
+        let published = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            publisher.publish(Kind::Stderr, "bot_a", None, None, "2".to_string());
+        });
+        let read = stream.read(1, Duration::from_secs(10));
+        published.join().unwrap();
+        assert_eq!(data(&read), ["2"]);
+    }
+
+    #[test]
+    fn counts_items_dropped_before_they_could_be_read() {
+        let stream = stream_of(MAX_STREAM_ITEMS as u64 + 10);
+        assert_eq!(stream.next_seq(), MAX_STREAM_ITEMS as u64 + 11);
+        // the first 10 items are dropped, of which 3 to 10 are after the sequence number
+        let read = stream.read(2, Duration::ZERO);
+        assert_eq!(read.lost, 8);
+        assert_eq!(read.items.len(), MAX_STREAM_ITEMS);
+        assert_eq!(read.items[0].seq, 11);
+        let read = stream.read(10, Duration::ZERO);
+        assert_eq!(read.lost, 0);
+        assert_eq!(read.items.len(), MAX_STREAM_ITEMS);
+    }
+
+    #[test]
+    fn continues_numbering_of_a_previous_stream() {
+        let stream = Stream::new(100);
+        stream.publish(Kind::Stdout, "bot_a", None, None, "1".to_string());
+        assert_eq!(stream.read(99, Duration::ZERO).items[0].seq, 100);
+        // whatever the previous stream had is gone
+        assert_eq!(stream.read(50, Duration::ZERO).lost, 49);
+        assert_eq!(Stream::new(0).next_seq(), 1);
+    }
+
+    #[test]
+    fn filters_items_by_kind_and_bot() {
+        let stream = Stream::new(1);
+        let key = ("bot_a".to_string(), Some(1));
+        let tap = Tap::instance(&stream, &key);
+        tap.publish("out");
+        tap.stderr().publish("err");
+        Tap::task(&stream, "bot_b", "3").publish("built");
+        stream.publish_event(&Event {
+            kind: EventKind::BotStarted,
+            bot_name: "bot_b".to_string(),
+            details: "started\n".to_string(),
+        });
+        let items = stream.read(0, Duration::ZERO).items;
+        assert_eq!(
+            items.iter().map(|item| item.kind).collect::<Vec<_>>(),
+            [
+                Kind::Stdout,
+                Kind::Stderr,
+                Kind::TaskStdout,
+                Kind::Event(EventKind::BotStarted)
+            ]
+        );
+        assert_eq!(items[0].shard, Some(1));
+        assert_eq!(items[2].task_id.as_deref(), Some("3"));
+        assert_eq!(items[3].data, "started");
+
+        let matching = |filter: &Filter| {
+            items
+                .iter()
+                .filter(|item| filter.matches(item))
+                .map(|item| item.data.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            matching(&Filter::default()),
+            ["out", "err", "built", "started"]
+        );
+        let bots = Filter {
+            bots: HashSet::from(["bot_b".to_string()]),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&bots), ["built", "started"]);
+        let kinds = Filter {
+            kinds: HashSet::from([Kind::Stderr, Kind::TaskStdout]),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&kinds), ["err", "built"]);
+        let both = Filter {
+            kinds: HashSet::from([Kind::Stderr, Kind::TaskStdout]),
+            bots: HashSet::from(["bot_a".to_string()]),
+        };
+        assert_eq!(matching(&both), ["err"]);
+    }
+
+    #[test]
+    fn parses_kinds_by_their_names() {
+        for kind in [
+            "stdout",
+            "stderr",
+            "task-stdout",
+            "task-stderr",
+            "bot-started",
+        ] {
+            assert_eq!(kind.parse::<Kind>().unwrap().to_string(), kind);
+        }
+        assert_eq!(
+            "stdin".parse::<Kind>(),
+            Err("stdin isn't a kind of stream items".to_string())
+        );
+    }
+}
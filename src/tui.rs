@@ -0,0 +1,583 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::{cursor, execute, terminal};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{Block, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+use serde_json::{json, Value};
+
+use crate::control_socket;
+use crate::status::{describe, text};
+
+/// how often the tables and the log pane are refreshed, besides whenever a event arrives
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// how long to wait for a key before checking for replies and events again
+const KEY_INTERVAL: Duration = Duration::from_millis(100);
+/// the keys of the actions on the selected bot, along with the commands they send
+const ACTIONS: [(char, &str); 5] = [
+    ('s', "start"),
+    ('x', "kill"),
+    ('r', "restart"),
+    ('b', "build"),
+    ('p', "pull"),
+];
+
+/// the arguments of `dcbothub tui`, which runs bothub in the foreground with a terminal dashboard
+/// in place of the prompt, or attaches the dashboard to a running bothub
+#[derive(clap::Args, Debug)]
+pub struct Tui {
+    /// attach to a running bothub through its control socket, instead of running bothub
+    #[arg(long)]
+    pub attach: bool,
+    /// the socket to attach to, `control_socket` in bots.toml by default
+    #[arg(long, requires = "attach")]
+    pub socket: Option<PathBuf>,
+}
+
+/// the terminal the dashboard is drawn on, which is switched to the alternate screen in raw mode until dropped
+pub struct Screen {
+    terminal: Terminal<CrosstermBackend<File>>,
+}
+
+impl Screen {
+    /// takes over the terminal `output` writes to
+    pub fn open(mut output: File) -> Result<Screen, String> {
+        terminal::enable_raw_mode().map_err(|err| format!("Failed enabling raw mode, {}", err))?;
+        if let Err(err) = execute!(output, terminal::EnterAlternateScreen, cursor::Hide) {
+            let _ = terminal::disable_raw_mode();
+            return Err(format!("Failed switching to the alternate screen, {}", err));
+        }
+        let terminal = match Terminal::new(CrosstermBackend::new(output)) {
+            Ok(terminal) => terminal,
+            Err(err) => {
+                let _ = terminal::disable_raw_mode();
+                return Err(format!("Failed opening the terminal, {}", err));
+            }
+        };
+        // restored by dropping it from now on
+        let mut screen = Screen { terminal };
+        // anything left on the alternate screen, such as the dashboard before `self-update`, is drawn over otherwise
+        screen
+            .terminal
+            .clear()
+            .map_err(|err| format!("Failed clearing the terminal, {}", err))?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(
+            self.terminal.backend_mut(),
+            terminal::LeaveAlternateScreen,
+            cursor::Show
+        );
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// takes over the terminal for the dashboard of bothub running in-process,
+/// redirecting stdout and stderr to `log_path` so nothing bothub prints draws over it
+///
+/// the terminal is opened as `/dev/tty`, which is still the terminal after `self-update`
+/// even though stdout and stderr are redirected by then
+pub fn take_terminal(log_path: &Path) -> Result<Screen, String> {
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .map_err(|err| format!("Failed opening {}, {}", log_path.display(), err))?;
+    let screen = Screen::open(tty()?)?;
+    let _ = std::io::stdout().flush();
+    let redirected =
+        unsafe { libc::dup2(log.as_raw_fd(), 1) != -1 && libc::dup2(log.as_raw_fd(), 2) != -1 };
+    if !redirected {
+        return Err(format!(
+            "Failed redirecting output to {}, {}",
+            log_path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(screen)
+}
+
+/// runs `dcbothub tui --attach`, which leaves bothub running when it's quit
+pub fn attach(args: Tui) -> Result<(), String> {
+    let stream = control_socket::connect(args.socket)?;
+    run(Screen::open(tty()?)?, stream, false)
+}
+
+fn tty() -> Result<File, String> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|err| format!("Failed opening /dev/tty, {}", err))
+}
+
+/// a row of the bot table, a instance or a bot without any
+struct BotRow {
+    bot: String,
+    shard: Option<u64>,
+    status: Value,
+}
+
+impl BotRow {
+    fn name(&self) -> String {
+        match self.shard {
+            Some(shard) => format!("{}#{}", self.bot, shard),
+            None => self.bot.clone(),
+        }
+    }
+
+    /// `bot_name` along with `--shard` for commands on the instance
+    fn args(&self, command: &str) -> Vec<String> {
+        let mut args = vec![command.to_string(), self.bot.clone()];
+        if let (Some(shard), "start" | "kill" | "restart" | "logs") = (self.shard, command) {
+            args.extend(["--shard".to_string(), shard.to_string()]);
+        }
+        args
+    }
+}
+
+/// what the dashboard shows, updated by the replies and events from bothub
+#[derive(Default)]
+struct State {
+    names: Vec<String>,
+    instances: Vec<Value>,
+    tasks: Vec<Value>,
+    rows: Vec<BotRow>,
+    /// the selected row of the bot table, kept by name across refreshes
+    selected: usize,
+    selected_name: Option<String>,
+    /// the text output of `logs` of the selected row, and the name of the row it's of
+    log: (String, String),
+    /// how many lines the log pane is scrolled up from the bottom, which follows new lines at 0
+    scroll: usize,
+    /// the last event, or the first line of the reply to the last action
+    message: String,
+    /// the token of the action waiting to be confirmed with `y`
+    confirm: Option<String>,
+    /// refreshes not replied yet, so refreshes don't pile up while bothub is busy
+    pending: usize,
+    quitting: bool,
+}
+
+impl State {
+    /// rebuilds the rows out of `list` and `list-status`, keeping the selection on the same row
+    fn update_rows(&mut self) {
+        let mut rows: Vec<BotRow> = self
+            .instances
+            .iter()
+            .map(|status| BotRow {
+                bot: text(&status["bot"]),
+                shard: status["shard"].as_u64(),
+                status: status.clone(),
+            })
+            .collect();
+        for name in &self.names {
+            if !rows.iter().any(|row| &row.bot == name) {
+                rows.push(BotRow {
+                    bot: name.clone(),
+                    shard: None,
+                    status: json!({ "state": "none" }),
+                });
+            }
+        }
+        rows.sort_by(|a, b| (&a.bot, a.shard).cmp(&(&b.bot, b.shard)));
+        self.rows = rows;
+        if let Some(index) = self
+            .selected_name
+            .as_ref()
+            .and_then(|name| self.rows.iter().position(|row| &row.name() == name))
+        {
+            self.selected = index;
+        }
+        self.select(self.selected);
+    }
+
+    fn select(&mut self, index: usize) {
+        self.selected = index.min(self.rows.len().saturating_sub(1));
+        let name = self.rows.get(self.selected).map(BotRow::name);
+        if name != self.selected_name {
+            self.scroll = 0;
+        }
+        self.selected_name = name;
+    }
+
+    fn selected_row(&self) -> Option<&BotRow> {
+        self.rows.get(self.selected)
+    }
+}
+
+/// sends requests in the json protocol, the ids of which tell what the reply is for
+struct Requests {
+    stream: UnixStream,
+}
+
+impl Requests {
+    /// switches to the json protocol, since every client starts in the text protocol
+    fn hello(&mut self) -> Result<(), String> {
+        self.write("hello json")
+    }
+
+    fn send(&mut self, id: &str, command: &[String]) -> Result<(), String> {
+        self.write(&json!({ "id": id, "command": command }).to_string())
+    }
+
+    fn write(&mut self, line: &str) -> Result<(), String> {
+        writeln!(self.stream, "{}", line)
+            .and_then(|_| self.stream.flush())
+            .map_err(|err| format!("Failed sending a command to bothub, {}", err))
+    }
+
+    /// asks for everything the dashboard shows
+    fn refresh(&mut self, state: &mut State) -> Result<(), String> {
+        self.send("list", &["list".to_string()])?;
+        self.send("list-status", &["list-status".to_string()])?;
+        self.send("list-tasks", &["list-tasks".to_string()])?;
+        state.pending += 3;
+        if let Some(row) = state.selected_row() {
+            self.send(&format!("logs {}", row.name()), &row.args("logs"))?;
+            state.pending += 1;
+        }
+        Ok(())
+    }
+
+    /// closes the connection for writing, which bothub takes as the terminal it runs in being closed
+    fn close(&mut self) -> Result<(), String> {
+        self.stream
+            .shutdown(std::net::Shutdown::Write)
+            .map_err(|err| format!("Failed closing the connection, {}", err))
+    }
+}
+
+/// runs the dashboard on `screen` for the bothub at the other end of `stream`,
+/// until it's quit or bothub closes the connection
+///
+/// if `exit_on_quit`, bothub runs in the same terminal, and is told to exit when it's quit by closing the connection,
+/// which the dashboard waits for bothub to close as well, while it exits on its own once the dashboard is gone
+pub fn run(screen: Screen, stream: UnixStream, exit_on_quit: bool) -> Result<(), String> {
+    let input = stream
+        .try_clone()
+        .map_err(|err| format!("Failed cloning the connection, {}", err))?;
+    let mut requests = Requests { stream };
+    let result = show(screen, &mut requests, read_lines(input), exit_on_quit);
+    // closed explicitly, since the thread reading lines holds the connection open
+    let _ = requests.close();
+    result
+}
+
+fn show(
+    mut screen: Screen,
+    requests: &mut Requests,
+    lines: Receiver<Value>,
+    exit_on_quit: bool,
+) -> Result<(), String> {
+    requests.hello()?;
+    requests.send("subscribe", &["subscribe".to_string()])?;
+
+    let mut state = State::default();
+    let mut next_refresh = Instant::now();
+    loop {
+        loop {
+            match lines.try_recv() {
+                Ok(line) => {
+                    // anything could have changed with a event
+                    if handle_line(&mut state, &line) {
+                        next_refresh = Instant::now();
+                    }
+                }
+                // bothub exited, or closed the connection
+                Err(TryRecvError::Disconnected) => return Ok(()),
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+        if !state.quitting && state.pending == 0 && Instant::now() >= next_refresh {
+            requests.refresh(&mut state)?;
+            next_refresh = Instant::now() + REFRESH_INTERVAL;
+        }
+        screen
+            .terminal
+            .draw(|frame| draw(frame, &mut state))
+            .map_err(|err| format!("Failed drawing the dashboard, {}", err))?;
+
+        if !event::poll(KEY_INTERVAL).map_err(|err| format!("Failed reading keys, {}", err))? {
+            continue;
+        }
+        let key = match event::read().map_err(|err| format!("Failed reading keys, {}", err))? {
+            event::Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+        if state.quitting {
+            continue;
+        }
+        // any key but `y` cancels the action waiting to be confirmed, and is handled as usual
+        if let Some(token) = state.confirm.take() {
+            if key.code == KeyCode::Char('y') {
+                requests.send("action", &["confirm".to_string(), token])?;
+                state.message = "confirmed ...".to_string();
+                continue;
+            }
+            state.message = "cancelled".to_string();
+        }
+        let page = screen.terminal.size().map_or(10, |size| size.height / 3) as usize;
+        let quit = match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => true,
+            KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+            KeyCode::Up | KeyCode::Char('k') => {
+                state.select(state.selected.saturating_sub(1));
+                // the output of the newly selected row is asked for right away
+                next_refresh = Instant::now();
+                false
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                state.select(state.selected + 1);
+                next_refresh = Instant::now();
+                false
+            }
+            KeyCode::PageUp => {
+                state.scroll = state.scroll.saturating_add(page);
+                false
+            }
+            KeyCode::PageDown => {
+                state.scroll = state.scroll.saturating_sub(page);
+                false
+            }
+            KeyCode::Home => {
+                state.scroll = usize::MAX;
+                false
+            }
+            KeyCode::End => {
+                state.scroll = 0;
+                false
+            }
+            KeyCode::Char(c) => {
+                let action = ACTIONS.iter().find(|(key, _)| *key == c);
+                if let (Some((_, action)), Some(row)) = (action, state.selected_row()) {
+                    requests.send("action", &row.args(action))?;
+                    state.message = format!("{} {} ...", action, row.name());
+                }
+                false
+            }
+            _ => false,
+        };
+        if !quit {
+            continue;
+        }
+        if !exit_on_quit {
+            return Ok(());
+        }
+        // waits for bothub to close the connection once every bot is stopped
+        requests.close()?;
+        state.quitting = true;
+        state.message = "exiting, stopping every bot ...".to_string();
+    }
+}
+
+/// reads every line from bothub on a separate thread, until the connection is closed
+fn read_lines(input: UnixStream) -> Receiver<Value> {
+    let (send, receive) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(input).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            // the reply to `hello json` is still preceded by its line count, which is ignored
+            let line = serde_json::from_str(&line).unwrap_or(Value::Null);
+            if send.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receive
+}
+
+/// handles a line from bothub, returning whether it's a event
+fn handle_line(state: &mut State, line: &Value) -> bool {
+    match line["type"].as_str() {
+        Some("event") => {
+            state.message = format!(
+                "event {} {} {}",
+                text(&line["event"]),
+                text(&line["bot"]),
+                text(&line["details"])
+            );
+            return true;
+        }
+        Some("reply") => {
+            let id = text(&line["id"]);
+            let result = &line["result"];
+            match id.as_str() {
+                "action" if result["result"] == "confirm" => {
+                    state.confirm = result["token"].as_str().map(str::to_string);
+                    state.message = format!(
+                        "{} {}: press y to confirm, any other key to cancel",
+                        text(&result["command"]),
+                        text(&result["bot"])
+                    );
+                    return false;
+                }
+                "action" => {
+                    state.message = text(&line["output"])
+                        .lines()
+                        .next()
+                        .unwrap_or("")
+                        .to_string();
+                    return false;
+                }
+                "list" => state.names = result.as_array().into_iter().flatten().map(text).collect(),
+                "list-status" => {
+                    state.instances = result.as_array().cloned().unwrap_or_default();
+                    state.update_rows();
+                }
+                "list-tasks" => state.tasks = result.as_array().cloned().unwrap_or_default(),
+                id => match id.strip_prefix("logs ") {
+                    Some(name) => state.log = (name.to_string(), text(&line["output"])),
+                    // the reply to `subscribe`
+                    None => return false,
+                },
+            }
+            // every other reply is to a refresh
+            state.pending = state.pending.saturating_sub(1);
+        }
+        Some("error") => state.message = text(&line["error"]).trim_end().to_string(),
+        _ => {}
+    }
+    false
+}
+
+fn draw(frame: &mut Frame, state: &mut State) {
+    let area = frame.area();
+    let bots_height = (state.rows.len() as u16 + 3).min(area.height * 2 / 5);
+    let tasks_height = (state.tasks.len() as u16 + 3).min(area.height / 4);
+    let [bots, tasks, log, message, help] = Layout::vertical([
+        Constraint::Length(bots_height),
+        Constraint::Length(tasks_height),
+        Constraint::Min(3),
+        Constraint::Length(1),
+        Constraint::Length(1),
+    ])
+    .areas(area);
+
+    draw_bots(frame, bots, state);
+    draw_tasks(frame, tasks, state);
+    draw_log(frame, log, state);
+    frame.render_widget(Paragraph::new(state.message.as_str()), message);
+    let mut keys: Vec<String> = ACTIONS
+        .iter()
+        .map(|(key, action)| format!("{} {}", key, action))
+        .collect();
+    keys.extend(
+        ["↑↓ select", "PgUp PgDn Home End scroll", "q quit"]
+            .iter()
+            .map(|keys| keys.to_string()),
+    );
+    frame.render_widget(
+        Paragraph::new(keys.join("  ")).style(Style::default().add_modifier(Modifier::DIM)),
+        help,
+    );
+}
+
+fn draw_bots(frame: &mut Frame, area: Rect, state: &State) {
+    let rows = state.rows.iter().map(|row| {
+        let status = &row.status;
+        let uptime = status["uptime_seconds"]
+            .as_u64()
+            .map(|uptime| crate::instance::format_uptime(Duration::from_secs(uptime)))
+            .unwrap_or_default();
+        Row::new([
+            Cell::from(row.name()),
+            Cell::from(describe(status)).style(state_style(status)),
+            Cell::from(text(&status["pid"])),
+            Cell::from(uptime),
+            Cell::from(text(&status["restarts"])),
+            Cell::from(text(&status["commit"])),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Fill(2),
+            Constraint::Fill(2),
+            Constraint::Length(8),
+            Constraint::Length(12),
+            Constraint::Length(8),
+            Constraint::Fill(1),
+        ],
+    )
+    .header(header([
+        "bot", "state", "pid", "uptime", "restarts", "commit",
+    ]))
+    .block(Block::bordered().title(" bots "))
+    .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    let mut table_state = TableState::default().with_selected(Some(state.selected));
+    frame.render_stateful_widget(table, area, &mut table_state);
+}
+
+fn draw_tasks(frame: &mut Frame, area: Rect, state: &State) {
+    let rows = state.tasks.iter().map(|task| {
+        Row::new([
+            Cell::from(text(&task["task_id"])),
+            Cell::from(text(&task["bot"])),
+            Cell::from(text(&task["task_type"])),
+            Cell::from(describe(task)).style(state_style(task)),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+            Constraint::Length(12),
+            Constraint::Fill(2),
+        ],
+    )
+    .header(header(["task", "bot", "type", "state"]))
+    .block(Block::bordered().title(" tasks "));
+    frame.render_widget(table, area);
+}
+
+fn draw_log(frame: &mut Frame, area: Rect, state: &mut State) {
+    let (name, log) = &state.log;
+    let title = match state.selected_name.as_ref() {
+        Some(selected) if selected == name => format!(" logs {} ", name),
+        Some(selected) => format!(" logs {} (loading) ", selected),
+        None => " logs ".to_string(),
+    };
+    let log = match state.selected_name.as_ref() {
+        Some(selected) if selected == name => log.as_str(),
+        _ => "",
+    };
+    let lines = log.lines().count();
+    let height = area.height.saturating_sub(2) as usize;
+    // clamped here, since how far it can be scrolled is only known once it's drawn
+    state.scroll = state.scroll.min(lines.saturating_sub(height));
+    let top = lines.saturating_sub(height + state.scroll);
+    let paragraph = Paragraph::new(log)
+        .block(Block::bordered().title(title))
+        .scroll((top.min(u16::MAX as usize) as u16, 0));
+    frame.render_widget(paragraph, area);
+}
+
+fn header<'a>(names: impl IntoIterator<Item = &'a str>) -> Row<'a> {
+    Row::new(names).style(Style::default().add_modifier(Modifier::BOLD))
+}
+
+fn state_style(status: &Value) -> Style {
+    match status["state"].as_str() {
+        Some("running") => Style::default().fg(Color::Green),
+        Some("exited" | "failed") => Style::default().fg(Color::Red),
+        _ => Style::default(),
+    }
+}